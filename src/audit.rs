@@ -0,0 +1,148 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use serde_json::{json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The audit log file, `None` if disabled
+static AUDIT_LOG: Mutex<Option<File>> = Mutex::new(None);
+
+/// The sequence number of the next executed command
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Opens the audit log, records are always appended
+///
+/// # Parameters
+///
+/// * `path`: The audit log path
+pub fn open(path: &str) -> std::io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *AUDIT_LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+    Ok(())
+}
+
+/// Records a command execution, returns the id correlating its records or `None` if the audit
+/// log is disabled
+///
+/// # Parameters
+///
+/// * `kind`: What the command is executed for
+/// * `source`: The config file of the element
+/// * `path`: The file or directory the command is executed for
+/// * `command`: The executed command
+/// * `pid`: The command process id
+pub fn spawned(kind: &str, source: &str, path: &str, command: &str, pid: u32) -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+
+    // Ids stay unique across restarts of the daemon
+    let id = format!(
+        "{}-{}",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    );
+
+    write(json!({
+        "record": "spawn",
+        "id": &id,
+        "kind": kind,
+        "source": source,
+        "path": path,
+        "command": command,
+        "pid": pid,
+    }));
+
+    Some(id)
+}
+
+/// Records a command which couldn't be executed
+///
+/// # Parameters
+///
+/// * `kind`: What the command is executed for
+/// * `source`: The config file of the element
+/// * `path`: The file or directory the command is executed for
+/// * `command`: The command
+/// * `error`: The reason
+pub fn spawn_failed(kind: &str, source: &str, path: &str, command: &str, error: &str) {
+    if enabled() {
+        write(json!({
+            "record": "spawn_failed",
+            "kind": kind,
+            "source": source,
+            "path": path,
+            "command": command,
+            "error": error,
+        }));
+    }
+}
+
+/// Records the end of a command
+///
+/// # Parameters
+///
+/// * `id`: The id returned when the command was recorded, `None` if unknown
+/// * `pid`: The command process id
+/// * `status`: The command exit status
+pub fn exited(id: Option<&str>, pid: u32, status: ExitStatus) {
+    if enabled() {
+        write(json!({
+            "record": "exit",
+            "id": id,
+            "pid": pid,
+            "code": status.code(),
+            "signal": status.signal(),
+        }));
+    }
+}
+
+/// Checks if the audit log is enabled
+fn enabled() -> bool {
+    AUDIT_LOG
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .is_some()
+}
+
+/// Appends a timestamped record as a JSON line
+///
+/// # Parameters
+///
+/// * `record`: The record
+fn write(mut record: Value) {
+    record["time"] = Value::from(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    );
+
+    let mut guard = AUDIT_LOG.lock().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(file) = guard.as_mut() {
+        // A single write per line keeps records whole even with other writers
+        if let Err(e) = file.write_all(format!("{}\n", record).as_bytes()) {
+            log!(error, "Error: unable to write audit log: {}", e);
+        }
+    }
+}
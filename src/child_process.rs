@@ -0,0 +1,226 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::file_check::FileCheck;
+use std::io::{BufRead, BufReader, Read};
+use std::process::Child;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// A spawned command
+pub struct ChildProcess {
+    /// The child process
+    pub child: Child,
+
+    /// The file the command was executed for, `None` for hooks
+    pub file: Option<FileCheck>,
+
+    /// Whether the child was killed for exceeding its lifecycle timeout
+    pub expired: bool,
+
+    /// Whether the working directory of the child was found removed
+    pub cwd_removed: bool,
+
+    /// The id of the command in the audit log, if enabled
+    pub audit_id: Option<String>,
+
+    /// When the child was spawned
+    pub started_at: Instant,
+
+    /// When the child was terminated for exceeding its timeout, if it was
+    pub terminated_at: Option<Instant>,
+
+    /// Whether the child was killed after its grace period
+    pub killed: bool,
+
+    /// When the child was reaped with its outputs still open, by a process it started
+    exited_at: Option<Instant>,
+
+    /// The threads reading the child outputs, returning the kept lines
+    readers: Vec<JoinHandle<Vec<String>>>,
+}
+
+impl ChildProcess {
+    /// The time the outputs can stay open once the child has exited, in milliseconds
+    const DRAIN_TIMEOUT: u64 = 5000;
+
+    /// Wraps a freshly spawned child and starts reading its outputs if they are piped
+    ///
+    /// Each output gets its own thread, this way the child never blocks on a full pipe even if
+    /// the main loop is busy
+    ///
+    /// # Parameters
+    ///
+    /// * `child`: The spawned child
+    /// * `file`: The file the command was executed for
+    pub fn new(mut child: Child, file: Option<FileCheck>) -> Self {
+        let id = child.id();
+        let mut readers = Vec::new();
+
+        // Standard output lines are kept when they are chained to new executions
+        let keep_stdout = file.as_ref().is_some_and(|f| f.chain_output);
+
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(Self::spawn_reader(stdout, id, "stdout", keep_stdout));
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(Self::spawn_reader(stderr, id, "stderr", false));
+        }
+
+        Self {
+            child,
+            file,
+            expired: false,
+            cwd_removed: false,
+            audit_id: None,
+            started_at: Instant::now(),
+            terminated_at: None,
+            killed: false,
+            exited_at: None,
+            readers,
+        }
+    }
+
+    /// Starts a thread printing each line of an output
+    ///
+    /// # Parameters
+    ///
+    /// * `output`: The output to read
+    /// * `id`: The child id
+    /// * `name`: The output name
+    /// * `keep`: Whether the lines are returned once the output is closed
+    fn spawn_reader<R: Read + Send + 'static>(
+        output: R,
+        id: u32,
+        name: &'static str,
+        keep: bool,
+    ) -> JoinHandle<Vec<String>> {
+        std::thread::spawn(move || {
+            let mut kept = Vec::new();
+
+            for line in BufReader::new(output).lines() {
+                match line {
+                    Ok(v) => {
+                        log!("Child {} {}: {}", id, name, v);
+
+                        if keep {
+                            kept.push(v);
+                        }
+                    }
+                    Err(e) => {
+                        log!(error, "Error while reading child {} {}: {}", id, name, e);
+                        break;
+                    }
+                }
+            }
+
+            kept
+        })
+    }
+
+    /// Returns the number of output pipes still open on our side
+    pub fn open_pipes(&self) -> usize {
+        // A reader drops its pipe once the output is closed
+        self.readers.iter().filter(|r| !r.is_finished()).count()
+    }
+
+    /// Checks if the outputs of an exited child are closed
+    ///
+    /// A process started by the child can keep them open long after its exit, readers still
+    /// running after [`Self::DRAIN_TIMEOUT`] are detached, they stop on their own once the
+    /// pipes are closed
+    pub fn outputs_drained(&mut self) -> bool {
+        if self.readers.iter().all(|r| r.is_finished()) {
+            return true;
+        }
+
+        let id = self.child.id();
+        let exited_at = *self.exited_at.get_or_insert_with(|| {
+            log!("Child {} exited, waiting for its outputs to close", id);
+            Instant::now()
+        });
+
+        if exited_at.elapsed() < Duration::from_millis(Self::DRAIN_TIMEOUT) {
+            return false;
+        }
+
+        log!(
+            warning,
+            "Warning: outputs of child {} still open {} seconds after its exit, detached",
+            id,
+            Self::DRAIN_TIMEOUT as f64 / 1000.0
+        );
+        self.readers.retain(|r| r.is_finished());
+        true
+    }
+
+    /// Checks if the child was reaped, its pid may then be reused by another process
+    pub fn exited(&self) -> bool {
+        self.exited_at.is_some()
+    }
+
+    /// Joins the finished output readers, then returns the kept lines
+    ///
+    /// Must be called once [`Self::outputs_drained`] returns `true`, joining never blocks then
+    pub fn join_readers(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for reader in self.readers.drain(..).filter(|r| r.is_finished()) {
+            match reader.join() {
+                Ok(v) => lines.extend(v),
+                Err(_) => log!(
+                    error,
+                    "Error while joining output reader of child {}",
+                    self.child.id()
+                ),
+            }
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn grandchild_holding_outputs_does_not_block() {
+        let child = Command::new("sh")
+            .args(["-c", "echo started; sleep 2 &"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut process = ChildProcess::new(child, None);
+
+        while process.child.try_wait().unwrap().is_none() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let start = Instant::now();
+        assert!(!process.outputs_drained());
+        assert!(process.exited());
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // Once the grandchild is gone, the readers are finished and joined
+        while !process.outputs_drained() {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(process.join_readers().is_empty());
+    }
+}
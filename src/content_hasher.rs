@@ -0,0 +1,156 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use simple_error::bail;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// The algorithm hashing file contents for the hash completion strategy
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum HashAlgo {
+    /// The standard library SipHash, always available
+    Siphash,
+
+    /// XXH3, the fastest
+    #[cfg(feature = "xxhash")]
+    Xxhash,
+
+    /// CRC32
+    #[cfg(feature = "crc32")]
+    Crc32,
+
+    /// SHA-256, slower but collision resistant
+    #[cfg(feature = "sha256")]
+    Sha256,
+}
+
+impl HashAlgo {
+    /// Returns the fastest algorithm compiled in
+    pub fn fastest() -> Self {
+        #[cfg(feature = "xxhash")]
+        return Self::Xxhash;
+
+        #[cfg(all(not(feature = "xxhash"), feature = "crc32"))]
+        return Self::Crc32;
+
+        #[cfg(not(any(feature = "xxhash", feature = "crc32")))]
+        Self::Siphash
+    }
+
+    /// Returns the algorithm name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Siphash => "siphash",
+            #[cfg(feature = "xxhash")]
+            Self::Xxhash => "xxhash",
+            #[cfg(feature = "crc32")]
+            Self::Crc32 => "crc32",
+            #[cfg(feature = "sha256")]
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    /// Finds an algorithm by name
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The algorithm name
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        match name {
+            "siphash" => Ok(Self::Siphash),
+            #[cfg(feature = "xxhash")]
+            "xxhash" => Ok(Self::Xxhash),
+            #[cfg(feature = "crc32")]
+            "crc32" => Ok(Self::Crc32),
+            #[cfg(feature = "sha256")]
+            "sha256" => Ok(Self::Sha256),
+            #[cfg(not(feature = "xxhash"))]
+            "xxhash" => bail!("Hash algorithm xxhash needs the xxhash feature"),
+            #[cfg(not(feature = "crc32"))]
+            "crc32" => bail!("Hash algorithm crc32 needs the crc32 feature"),
+            #[cfg(not(feature = "sha256"))]
+            "sha256" => bail!("Hash algorithm sha256 needs the sha256 feature"),
+            _ => bail!("Unknown hash algorithm: {}", name),
+        }
+    }
+}
+
+/// A running hash of a file content
+pub enum ContentHasher {
+    /// SipHash state
+    Siphash(DefaultHasher),
+
+    /// XXH3 state
+    #[cfg(feature = "xxhash")]
+    Xxhash(Box<xxhash_rust::xxh3::Xxh3>),
+
+    /// CRC32 state
+    #[cfg(feature = "crc32")]
+    Crc32(crc32fast::Hasher),
+
+    /// SHA-256 state
+    #[cfg(feature = "sha256")]
+    Sha256(sha2::Sha256),
+}
+
+impl ContentHasher {
+    /// Starts a new hash
+    ///
+    /// # Parameters
+    ///
+    /// * `algo`: The hash algorithm
+    pub fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Siphash => Self::Siphash(DefaultHasher::new()),
+            #[cfg(feature = "xxhash")]
+            HashAlgo::Xxhash => Self::Xxhash(Box::default()),
+            #[cfg(feature = "crc32")]
+            HashAlgo::Crc32 => Self::Crc32(crc32fast::Hasher::new()),
+            #[cfg(feature = "sha256")]
+            HashAlgo::Sha256 => Self::Sha256(<sha2::Sha256 as sha2::Digest>::new()),
+        }
+    }
+
+    /// Adds data to the hash
+    ///
+    /// # Parameters
+    ///
+    /// * `data`: The data read from the file
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Siphash(h) => data.hash(h),
+            #[cfg(feature = "xxhash")]
+            Self::Xxhash(h) => h.update(data),
+            #[cfg(feature = "crc32")]
+            Self::Crc32(h) => h.update(data),
+            #[cfg(feature = "sha256")]
+            Self::Sha256(h) => sha2::Digest::update(h, data),
+        }
+    }
+
+    /// Returns the hash value
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            Self::Siphash(h) => h.finish().to_be_bytes().to_vec(),
+            #[cfg(feature = "xxhash")]
+            Self::Xxhash(h) => h.digest().to_be_bytes().to_vec(),
+            #[cfg(feature = "crc32")]
+            Self::Crc32(h) => h.finalize().to_be_bytes().to_vec(),
+            #[cfg(feature = "sha256")]
+            Self::Sha256(h) => sha2::Digest::finalize(h).to_vec(),
+        }
+    }
+}
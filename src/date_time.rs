@@ -0,0 +1,160 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A broken down date and time
+pub struct DateTime {
+    /// The year
+    pub year: i32,
+
+    /// The month, from 1 to 12
+    pub month: u32,
+
+    /// The day of the month, from 1 to 31
+    pub day: u32,
+
+    /// The hour, from 0 to 23
+    pub hour: u32,
+
+    /// The minute, from 0 to 59
+    pub minute: u32,
+
+    /// The second, from 0 to 60
+    pub second: u32,
+}
+
+impl DateTime {
+    /// Breaks down a time, `None` if the system can't convert it
+    ///
+    /// # Parameters
+    ///
+    /// * `time`: The time to break down
+    /// * `utc`: Whether the time is given in UTC instead of the local timezone
+    pub fn from_system_time(time: SystemTime, utc: bool) -> Option<Self> {
+        let seconds = match time.duration_since(UNIX_EPOCH) {
+            Ok(v) => v.as_secs() as libc::time_t,
+            Err(e) => -(e.duration().as_secs() as libc::time_t),
+        };
+
+        // SAFETY: tm is plain data, filled by the reentrant functions on success
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        let result = unsafe {
+            if utc {
+                libc::gmtime_r(&seconds, &mut tm)
+            } else {
+                libc::localtime_r(&seconds, &mut tm)
+            }
+        };
+
+        if result.is_null() {
+            return None;
+        }
+
+        Some(Self {
+            year: tm.tm_year + 1900,
+            month: tm.tm_mon as u32 + 1,
+            day: tm.tm_mday as u32,
+            hour: tm.tm_hour as u32,
+            minute: tm.tm_min as u32,
+            second: tm.tm_sec as u32,
+        })
+    }
+
+    /// Returns the zero-padded value of a date placeholder, `None` if unknown
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The placeholder name, without `${` and `}`
+    pub fn placeholder(&self, name: &str) -> Option<String> {
+        match name {
+            "year" => Some(format!("{:04}", self.year)),
+            "month" => Some(format!("{:02}", self.month)),
+            "day" => Some(format!("{:02}", self.day)),
+            "hour" => Some(format!("{:02}", self.hour)),
+            "minute" => Some(format!("{:02}", self.minute)),
+            "second" => Some(format!("{:02}", self.second)),
+            _ => None,
+        }
+    }
+
+    /// Replaces date placeholders like `${year}` in a command, unknown placeholders are kept as is
+    ///
+    /// # Parameters
+    ///
+    /// * `command`: The command line
+    pub fn expand(&self, command: &str) -> String {
+        let mut expanded = String::with_capacity(command.len());
+        let mut rest = command;
+
+        while let Some(start) = rest.find("${") {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let value = rest
+                .find('}')
+                .and_then(|end| self.placeholder(&rest[2..end]).map(|v| (end, v)));
+
+            match value {
+                Some((end, v)) => {
+                    expanded.push_str(&v);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    expanded.push_str("${");
+                    rest = &rest[2..];
+                }
+            }
+        }
+
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn placeholders_are_zero_padded() {
+        let date = DateTime {
+            year: 987,
+            month: 1,
+            day: 2,
+            hour: 3,
+            minute: 4,
+            second: 5,
+        };
+
+        assert_eq!(
+            date.expand("${year}-${month}-${day} ${hour}:${minute}:${second}"),
+            "0987-01-02 03:04:05"
+        );
+        assert_eq!(date.expand("${route} ${year"), "${route} ${year");
+    }
+
+    #[test]
+    fn utc_times_are_broken_down() {
+        // 2024-02-29 09:08:07 UTC
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_197_687);
+        let date = DateTime::from_system_time(time, true).unwrap();
+
+        assert_eq!(date.expand("${year}${month}${day}"), "20240229");
+        assert_eq!(date.expand("${hour}${minute}${second}"), "090807");
+    }
+}
@@ -0,0 +1,97 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+
+/// The attributes of a file an ATTRIB event can change
+#[derive(Clone, Copy, PartialEq)]
+pub struct FileAttributes {
+    /// The permissions, without the file type
+    pub mode: u32,
+
+    /// The owner user id
+    pub uid: u32,
+
+    /// The owner group id
+    pub gid: u32,
+
+    /// The size in bytes
+    pub size: u64,
+}
+
+impl FileAttributes {
+    /// Reads the attributes of a file, without following symlinks, `None` if it doesn't exist
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: The file path
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let metadata = std::fs::symlink_metadata(path).ok()?;
+
+        Some(Self {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size(),
+        })
+    }
+
+    /// Returns the names of the attributes which differ, comma separated
+    ///
+    /// # Parameters
+    ///
+    /// * `other`: The attributes to compare with
+    pub fn changes(&self, other: &Self) -> String {
+        let mut changes = Vec::new();
+
+        if self.mode != other.mode {
+            changes.push("mode");
+        }
+
+        if self.uid != other.uid {
+            changes.push("uid");
+        }
+
+        if self.gid != other.gid {
+            changes.push("gid");
+        }
+
+        if self.size != other.size {
+            changes.push("size");
+        }
+
+        changes.join(",")
+    }
+
+    /// Sets the attributes as environment variables of a command
+    ///
+    /// # Parameters
+    ///
+    /// * `command`: The command
+    /// * `suffix`: The suffix of variable names, like `BEFORE`
+    pub fn set_env(&self, command: &mut Command, suffix: &str) {
+        command
+            .env(
+                format!("RINCRON_MODE_{}", suffix),
+                format!("{:o}", self.mode),
+            )
+            .env(format!("RINCRON_UID_{}", suffix), self.uid.to_string())
+            .env(format!("RINCRON_GID_{}", suffix), self.gid.to_string())
+            .env(format!("RINCRON_SIZE_{}", suffix), self.size.to_string());
+    }
+}
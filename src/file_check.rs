@@ -14,7 +14,19 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::path::Path;
+use crate::content_hasher::{ContentHasher, HashAlgo};
+use crate::date_time::DateTime;
+use crate::file_attributes::FileAttributes;
+use crate::watch_element::{
+    ActionTarget, Completion, DateSource, DateTimezone, PendingEventPolicy, ShutdownPolicy,
+    VerifyFailure,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Clone)]
 /// A file checker
@@ -22,17 +34,201 @@ pub struct FileCheck {
     /// The file's path
     pub path: String,
 
-    /// The command to execute at the end
+    /// The command to execute at the end, for display only if `argv` is not empty
     pub cmd: String,
 
-    /// The previous size of the file
+    /// The program and its arguments to execute without shell, empty to run `cmd` with a shell
+    pub argv: Vec<String>,
+
+    /// The arguments as configured, wildcards are replaced after dates so that file names are
+    /// never expanded, empty if the arguments come from the journal
+    pub argv_template: Vec<String>,
+
+    /// The output of the route command once computed, given to `argv_template`
+    pub route: Option<String>,
+
+    /// The directory given as `$@`, the one of the file or the watched path for file names
+    /// relative to it
+    pub dir: String,
+
+    /// The watched path of the element the file comes from
+    pub element: String,
+
+    /// The file name
+    pub file: String,
+
+    /// The name of the event triggering the command, empty if not triggered by an event
+    pub event: String,
+
+    /// The attributes of the file before an ATTRIB event, if they were cached
+    pub attributes_before: Option<FileAttributes>,
+
+    /// The attributes of the file after an ATTRIB event, if tracked
+    pub attributes_after: Option<FileAttributes>,
+
+    /// The cookie of the event triggering the command, if any
+    pub cookie: Option<u32>,
+
+    /// The previous file name for a rename paired with its MOVED_FROM event, empty otherwise
+    pub moved_from: String,
+
+    /// The command to execute if the command succeeds
+    pub on_success: String,
+
+    /// The command to execute if the command fails
+    pub on_failure: String,
+
+    /// The command to execute if the file gets smaller during its check
+    pub on_truncate: String,
+
+    /// Whether the file got smaller at the last check, like when truncated or rewritten
+    pub shrunk: bool,
+
+    /// The strategy telling when the file is complete
+    pub completion: Completion,
+
+    /// What a new event does to the check of the file, if it's already checked
+    pub pending_event: PendingEventPolicy,
+
+    /// The previous size of the file, the total size of its files for a directory
     pub size: u64,
 
+    /// The previous number of entries of a directory
+    pub entries: u64,
+
+    /// Whether the checked path is the file or its directory
+    pub action_target: ActionTarget,
+
+    /// The previous modification time of the file
+    pub mtime: Option<SystemTime>,
+
+    /// The previous content hash of the file
+    pub hash: Option<Vec<u8>>,
+
+    /// The algorithm hashing the file content
+    pub hash_algo: HashAlgo,
+
+    /// Whether the file must not be open for writing by any process to be complete
+    pub require_closed: bool,
+
     /// The next check in milliseconds
     pub next_check: i64,
 
     /// The check interval in milliseconds
     pub check_interval: i64,
+
+    /// The verification command to execute before the command, empty if none
+    pub verify_cmd: String,
+
+    /// The behavior when the verification command fails
+    pub verify_failure: VerifyFailure,
+
+    /// The command whose output replaces `${route}` in the command, empty if none
+    pub route_cmd: String,
+
+    /// The value of `${route}` when the route command fails or prints nothing, the execution
+    /// fails if `None`
+    pub route_default: Option<String>,
+
+    /// The group sharing a limit of running commands, empty if none
+    pub concurrency_group: String,
+
+    /// The maximum number of running commands of the group
+    pub group_limit: usize,
+
+    /// Whether the command outputs are written to the log
+    pub capture_output: bool,
+
+    /// The file command outputs are appended to, dates not expanded, none if empty
+    pub log_file: String,
+
+    /// The minimum free space in bytes needed to execute the command, 0 if disabled
+    pub min_free_space: u64,
+
+    /// The path of the filesystem where free space is checked
+    pub free_space_path: String,
+
+    /// Whether the execution is waiting for free space
+    pub deferred: bool,
+
+    /// The directory where the file is moved after too many failures, if any
+    pub quarantine: Option<String>,
+
+    /// Whether the file is remembered once its command succeeded, never to be executed again
+    pub once_per_file: bool,
+
+    /// The profile of the element, empty without profiles
+    pub profile: String,
+
+    /// The config file of the element
+    pub source: String,
+
+    /// The environment variables set for commands
+    pub env: HashMap<String, String>,
+
+    /// Whether commands start with an empty environment instead of rincron's one
+    pub clean_env: bool,
+
+    /// The interpreter of commands, `none` to execute them without shell, empty for the default
+    pub shell: String,
+
+    /// The number of failures before the file is quarantined
+    pub max_failures: u32,
+
+    /// The number of times a failed command is executed again
+    pub retries: u32,
+
+    /// The time in milliseconds before a failed command is executed again
+    pub retry_delay: u64,
+
+    /// The exit codes a failed command is executed again for, any if empty
+    pub retry_on_codes: Vec<i32>,
+
+    /// The number of times the command was already executed again
+    pub attempt: u32,
+
+    /// When the command can be executed again after a failure, `None` if it's not a retry
+    pub retry_at: Option<Instant>,
+
+    /// When the first event for the file was read
+    pub created_at: Instant,
+
+    /// When the file was queued, as a Unix timestamp
+    pub enqueued_at: u64,
+
+    /// Whether the pending execution is written in the journal
+    pub journaled: bool,
+
+    /// Whether the shell reads the command on its standard input, the command line being too
+    /// long
+    pub stdin_script: bool,
+
+    /// The time in milliseconds the file must be handled in, 0 if disabled
+    pub lifecycle_timeout: u64,
+
+    /// The time in milliseconds the command can run before being terminated, 0 if unlimited
+    pub timeout: u64,
+
+    /// The time in milliseconds the terminated command has to exit before being killed
+    pub kill_grace: u64,
+
+    /// Whether the command output lines are processed as new files
+    pub chain_output: bool,
+
+    /// The number of chained executions leading to this one, 0 for an event
+    pub chain_depth: u32,
+
+    /// The maximum number of chained executions
+    pub max_chain_depth: u32,
+
+    /// What happens to the running command when rincron exits
+    pub on_shutdown: ShutdownPolicy,
+
+    /// The timezone of date placeholders
+    pub date_timezone: DateTimezone,
+
+    /// The time used by date placeholders
+    pub date_source: DateSource,
 }
 
 impl FileCheck {
@@ -45,6 +241,17 @@ impl FileCheck {
         self.next_check -= time;
     }
 
+    /// Returns the real path of the file, `path` being made of shell-escaped parts
+    ///
+    /// Checks made without directory (like from the journal) only have their real path
+    pub fn full_path(&self) -> PathBuf {
+        match self.file.is_empty() {
+            _ if self.dir.is_empty() => PathBuf::from(&self.path),
+            true => Path::new(&self.dir).to_path_buf(),
+            false => Path::new(&self.dir).join(&self.file),
+        }
+    }
+
     /// Check if file has changed
     ///
     /// If `true`, the command will not be executed
@@ -54,27 +261,75 @@ impl FileCheck {
             return true;
         }
 
+        let changed = match &self.completion {
+            Completion::Immediate | Completion::Quiet => false,
+            Completion::Size => self.size_changed(),
+            Completion::Mtime => self.mtime_changed(),
+            // Both are checked to keep both values up to date
+            Completion::SizeMtime => self.size_changed() | self.mtime_changed(),
+            Completion::Hash(sample) => {
+                let sample = *sample;
+                self.hash_changed(sample)
+            }
+            Completion::Marker(suffix) => !self.marker_exists(suffix),
+            Completion::Xattr(name, expected) => {
+                let (name, expected) = (name.clone(), expected.clone());
+                !self.xattr_matches(&name, &expected)
+            }
+        };
+
+        // A stable file may still be held open by a slow writer
+        let changed = changed || (self.require_closed && self.has_open_writer());
+
+        // If not, we reset for a new check
+        if changed {
+            self.next_check = self.check_interval;
+        }
+
+        changed
+    }
+
+    /// Check if the file size has changed since the last check
+    fn size_changed(&mut self) -> bool {
+        if self.action_target == ActionTarget::Dir {
+            return self.directory_changed();
+        }
+
         // If file does not exist, we set the size to zero
-        let file = Path::new(&self.path);
+        let file = self.full_path();
         if !file.exists() {
-            print!("Warning: file does not exist: {}", self.path);
+            log!(
+                warning,
+                path = &self.path,
+                "Warning: file does not exist: {}",
+                self.path
+            );
         }
 
         // Same with metadata reading
-        let metadata = std::fs::metadata(&self.path);
+        let metadata = std::fs::metadata(&file);
 
         // Size extraction
         let new_size = match metadata {
             Ok(v) => v.len(),
             Err(e) => {
-                print!("Warning: error while reading file metadata: {}", e);
+                log!(
+                    warning,
+                    path = &self.path,
+                    "Warning: error while reading file metadata: {}",
+                    e
+                );
                 0
             }
         };
 
-        println!(
+        log!(
+            info,
+            path = &self.path,
             "File {} checked, was {} bytes long, now {}",
-            &self.path, self.size, new_size
+            &self.path,
+            self.size,
+            new_size
         );
 
         // If size hadn't changed, we trigger the command
@@ -82,13 +337,472 @@ impl FileCheck {
             return false;
         }
 
-        // If not, we reset for a new check
-
+        // Completion only expects growth, a smaller file was truncated
+        self.shrunk = new_size < self.size;
         self.size = new_size;
-        self.next_check = self.check_interval;
         true
     }
 
+    /// Check if files were added to or removed from the directory, or changed size, since the
+    /// last check
+    fn directory_changed(&mut self) -> bool {
+        let (mut entries, mut size) = (0, 0);
+
+        match std::fs::read_dir(&self.dir) {
+            Ok(v) => {
+                for entry in v.filter_map(|e| e.ok()) {
+                    entries += 1;
+                    size += entry.metadata().map(|m| m.len()).unwrap_or_default();
+                }
+            }
+            Err(e) => log!(
+                warning,
+                path = &self.path,
+                "Warning: error while listing directory: {}",
+                e
+            ),
+        }
+
+        log!(
+            info,
+            path = &self.path,
+            "Directory {} checked, had {} entries for {} bytes, now {} for {}",
+            &self.dir,
+            self.entries,
+            self.size,
+            entries,
+            size
+        );
+
+        if entries == self.entries && size == self.size {
+            return false;
+        }
+
+        (self.entries, self.size) = (entries, size);
+        true
+    }
+
+    /// Check if the file modification time has changed since the last check
+    fn mtime_changed(&mut self) -> bool {
+        let target = match self.action_target {
+            ActionTarget::File => self.full_path(),
+            ActionTarget::Dir => PathBuf::from(&self.dir),
+        };
+
+        let new_mtime = match std::fs::metadata(target).and_then(|m| m.modified()) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log!(
+                    warning,
+                    path = &self.path,
+                    "Warning: error while reading file modification time: {}",
+                    e
+                );
+                None
+            }
+        };
+
+        log!(
+            info,
+            path = &self.path,
+            "File {} checked, modification time {:?}",
+            &self.path,
+            new_mtime
+        );
+
+        // The first check always counts as a change, we need two values to compare
+        if new_mtime.is_some() && new_mtime == self.mtime {
+            return false;
+        }
+
+        self.mtime = new_mtime;
+        true
+    }
+
+    /// Check if the file content has changed since the last check
+    ///
+    /// # Parameters
+    ///
+    /// * `sample`: The number of bytes hashed at the start and at the end of the file with its
+    ///   size, 0 to hash the whole file
+    fn hash_changed(&mut self, sample: u64) -> bool {
+        let new_hash = match std::fs::File::open(self.full_path()) {
+            Ok(mut f) => {
+                let mut hasher = ContentHasher::new(self.hash_algo);
+                let size = f.metadata().map(|m| m.len()).unwrap_or_default();
+
+                // Big files are only sampled, reading them whole on each check costs too much
+                let hashed = if sample == 0 || size <= sample * 2 {
+                    Self::hash_content(&mut f, &mut hasher, u64::MAX)
+                } else {
+                    hasher.update(&size.to_le_bytes());
+                    Self::hash_content(&mut f, &mut hasher, sample)
+                        .and_then(|_| f.seek(SeekFrom::End(-(sample as i64))))
+                        .and_then(|_| Self::hash_content(&mut f, &mut hasher, sample))
+                };
+
+                match hashed {
+                    Ok(_) => Some(hasher.finish()),
+                    Err(e) => {
+                        log!(
+                            warning,
+                            path = &self.path,
+                            "Warning: error while reading file: {}",
+                            e
+                        );
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                log!(
+                    warning,
+                    path = &self.path,
+                    "Warning: error while opening file: {}",
+                    e
+                );
+                None
+            }
+        };
+
+        let hex: Option<String> = new_hash
+            .as_ref()
+            .map(|h| h.iter().map(|b| format!("{:02x}", b)).collect());
+        log!(
+            info,
+            path = &self.path,
+            "File {} checked, content hash {:?}",
+            &self.path,
+            hex
+        );
+
+        // The first check always counts as a change, we need two values to compare
+        if new_hash.is_some() && new_hash == self.hash {
+            return false;
+        }
+
+        self.hash = new_hash;
+        true
+    }
+
+    /// Hashes the content of a file from its current position, up to a number of bytes
+    ///
+    /// # Parameters
+    ///
+    /// * `file`: The file to read
+    /// * `hasher`: The hasher to update
+    /// * `limit`: The maximum number of bytes to hash
+    fn hash_content(
+        file: &mut std::fs::File,
+        hasher: &mut ContentHasher,
+        limit: u64,
+    ) -> std::io::Result<u64> {
+        let mut buffer = [0; 65536];
+        let mut hashed = 0;
+
+        while hashed < limit {
+            let wanted = (limit - hashed).min(buffer.len() as u64) as usize;
+
+            match file.read(&mut buffer[..wanted])? {
+                0 => break,
+                n => {
+                    hasher.update(&buffer[..n]);
+                    hashed += n as u64;
+                }
+            }
+        }
+
+        Ok(hashed)
+    }
+
+    /// Check if a process holds the file open for writing
+    ///
+    /// This is best-effort: `/proc` only shows the descriptors of processes we are allowed to
+    /// inspect
+    fn has_open_writer(&self) -> bool {
+        let full_path = self.full_path();
+        let processes = match std::fs::read_dir("/proc") {
+            Ok(v) => v,
+            Err(e) => {
+                log!(
+                    warning,
+                    path = &self.path,
+                    "Warning: unable to scan /proc for writers: {}",
+                    e
+                );
+                return false;
+            }
+        };
+
+        for process in processes.filter_map(|p| p.ok()) {
+            let pid = process.file_name();
+
+            if !pid.to_string_lossy().bytes().all(|b| b.is_ascii_digit()) {
+                continue;
+            }
+
+            let fds = match std::fs::read_dir(process.path().join("fd")) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            for fd in fds.filter_map(|f| f.ok()) {
+                if std::fs::read_link(fd.path()).ok().as_deref() != Some(full_path.as_path()) {
+                    continue;
+                }
+
+                // The access mode is in the two lowest bits of the octal flags
+                let fdinfo = process.path().join("fdinfo").join(fd.file_name());
+                let writing = std::fs::read_to_string(fdinfo)
+                    .ok()
+                    .and_then(|info| {
+                        info.lines()
+                            .find_map(|l| l.strip_prefix("flags:"))
+                            .and_then(|f| u32::from_str_radix(f.trim(), 8).ok())
+                    })
+                    .is_some_and(|flags| flags & 3 != 0);
+
+                if writing {
+                    log!(
+                        info,
+                        path = &self.path,
+                        "File {} is still open for writing by process {}",
+                        &self.path,
+                        pid.to_string_lossy()
+                    );
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Check if the marker file of the file exists
+    ///
+    /// # Parameters
+    ///
+    /// * `suffix`: The suffix added to the file path to get the marker path
+    fn marker_exists(&self, suffix: &str) -> bool {
+        let mut marker = self.full_path().into_os_string();
+        marker.push(suffix);
+        let exists = Path::new(&marker).exists();
+
+        log!(
+            info,
+            path = &self.path,
+            "Marker {} checked, exists: {}",
+            Path::new(&marker).display(),
+            exists
+        );
+        exists
+    }
+
+    /// Check if an extended attribute of the file has the expected value
+    ///
+    /// Without extended attribute support, the strategy falls back to size checks
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The attribute name
+    /// * `expected`: The expected attribute value
+    fn xattr_matches(&mut self, name: &str, expected: &str) -> bool {
+        let full_path = self.full_path();
+
+        match xattr::get(&full_path, name) {
+            Ok(v) => {
+                let matches = v.is_some_and(|v| v == expected.as_bytes());
+                log!(
+                    info,
+                    path = &self.path,
+                    "Attribute {} of {} checked, matches: {}",
+                    name,
+                    &self.path,
+                    matches
+                );
+                matches
+            }
+            Err(e) if e.kind() == ErrorKind::Unsupported || !xattr::SUPPORTED_PLATFORM => {
+                log!(
+                    warning,
+                    path = &self.path,
+                    "Warning: extended attributes unsupported for {}, checking size instead: {}",
+                    &self.path,
+                    e
+                );
+                self.completion = Completion::Size;
+                !self.size_changed()
+            }
+            Err(e) => {
+                log!(
+                    warning,
+                    path = &self.path,
+                    "Warning: unable to read attribute {} of {}: {}",
+                    name,
+                    &self.path,
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns the available space in bytes of the filesystem containing a path
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: A path on the filesystem
+    fn free_space(path: &str) -> std::io::Result<u64> {
+        let c_path = CString::new(path)?;
+        // SAFETY: statvfs is a plain C struct, zeroed memory is valid
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+        // SAFETY: both pointers are valid during the call
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+
+    /// Check if there is enough free space to execute the command
+    ///
+    /// A warning is printed the first time the execution is deferred
+    pub fn has_free_space(&mut self) -> bool {
+        if self.min_free_space == 0 {
+            return true;
+        }
+
+        let available = match Self::free_space(&self.free_space_path) {
+            Ok(v) => v,
+            Err(e) => {
+                // We don't want to block executions if we're unable to check
+                log!(
+                    warning,
+                    path = &self.path,
+                    "Warning: unable to check free space on {}: {}",
+                    &self.free_space_path,
+                    e
+                );
+                return true;
+            }
+        };
+
+        if available >= self.min_free_space {
+            if self.deferred {
+                log!(
+                    info,
+                    path = &self.path,
+                    "Enough free space for {}, execution resumed",
+                    &self.path
+                );
+                self.deferred = false;
+            }
+
+            return true;
+        }
+
+        if !self.deferred {
+            log!(
+                warning,
+                path = &self.path,
+                "Warning: only {} bytes available on {} ({} needed), execution of {} deferred",
+                available,
+                &self.free_space_path,
+                self.min_free_space,
+                &self.path
+            );
+            self.deferred = true;
+        }
+
+        false
+    }
+
+    /// Replaces date placeholders in a command with the current date or the file modification
+    /// date
+    ///
+    /// # Parameters
+    ///
+    /// * `cmd`: The command line
+    pub fn expand_dates(&self, cmd: &str) -> String {
+        if !cmd.contains("${") {
+            return cmd.to_string();
+        }
+
+        let time = match self.date_source {
+            DateSource::Now => SystemTime::now(),
+            DateSource::Mtime => {
+                let full_path = self.full_path();
+
+                match std::fs::metadata(&full_path).and_then(|m| m.modified()) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log!(
+                            warning,
+                            path = &self.path,
+                            "Warning: unable to read modification time of {}, using current time: {}",
+                            full_path.display(),
+                            e
+                        );
+                        SystemTime::now()
+                    }
+                }
+            }
+        };
+
+        match DateTime::from_system_time(time, self.date_timezone == DateTimezone::Utc) {
+            Some(v) => v.expand(cmd),
+            None => {
+                log!(
+                    warning,
+                    path = &self.path,
+                    "Warning: unable to convert date for {}",
+                    &self.path
+                );
+                cmd.to_string()
+            }
+        }
+    }
+
+    /// Converts the pending execution to a journal entry
+    pub fn to_journal_value(&self) -> Value {
+        // The path is exactly the file, not its escaped form
+        let path = self.full_path();
+
+        let mut value = json!({
+            "path": path.to_string_lossy(),
+            "element": &self.element,
+            "enqueued": self.enqueued_at,
+        });
+
+        if self.argv.is_empty() {
+            value["command"] = Value::from(self.cmd.as_str());
+        } else {
+            value["argv"] = json!(&self.argv);
+        }
+
+        value
+    }
+
+    /// Whether the file took longer than its lifecycle timeout to be handled
+    pub fn lifecycle_expired(&self) -> bool {
+        self.lifecycle_timeout > 0
+            && self.created_at.elapsed() >= Duration::from_millis(self.lifecycle_timeout)
+    }
+
+    /// Returns the new file name of a rename paired with its MOVED_FROM event, empty otherwise
+    pub fn moved_to(&self) -> &str {
+        match self.moved_from.is_empty() {
+            true => "",
+            false => &self.file,
+        }
+    }
+
+    /// Restarts the check from scratch, like if the file had just changed
+    pub fn reset(&mut self) {
+        self.next_check = self.check_interval;
+    }
+
     /// Creates a new file checker
     ///
     /// # Parameters
@@ -98,10 +812,239 @@ impl FileCheck {
     pub fn new(path: &str, check_interval: i64, cmd: &str) -> Self {
         Self {
             path: path.to_string(),
+            completion: Completion::Size,
+            pending_event: PendingEventPolicy::Reset,
             size: 0,
+            entries: 0,
+            action_target: ActionTarget::File,
+            mtime: None,
+            hash: None,
+            hash_algo: HashAlgo::fastest(),
+            require_closed: false,
             next_check: check_interval,
             check_interval,
             cmd: cmd.to_string(),
+            argv: Vec::new(),
+            argv_template: Vec::new(),
+            route: None,
+            dir: String::new(),
+            element: String::new(),
+            file: String::new(),
+            event: String::new(),
+            attributes_before: None,
+            attributes_after: None,
+            cookie: None,
+            moved_from: String::new(),
+            on_success: String::new(),
+            on_failure: String::new(),
+            on_truncate: String::new(),
+            shrunk: false,
+            verify_cmd: String::new(),
+            verify_failure: VerifyFailure::Retry,
+            route_cmd: String::new(),
+            route_default: None,
+            concurrency_group: String::new(),
+            group_limit: 1,
+            capture_output: false,
+            min_free_space: 0,
+            free_space_path: String::new(),
+            deferred: false,
+            quarantine: None,
+            once_per_file: false,
+            profile: String::new(),
+            source: String::new(),
+            env: HashMap::new(),
+            clean_env: false,
+            max_failures: 0,
+            retries: 0,
+            retry_delay: 0,
+            retry_on_codes: Vec::new(),
+            attempt: 0,
+            retry_at: None,
+            created_at: Instant::now(),
+            enqueued_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            journaled: false,
+            stdin_script: false,
+            log_file: String::new(),
+            shell: String::new(),
+            lifecycle_timeout: 0,
+            timeout: 0,
+            kill_grace: 0,
+            chain_output: false,
+            chain_depth: 0,
+            max_chain_depth: 0,
+            on_shutdown: ShutdownPolicy::Terminate,
+            date_timezone: DateTimezone::Local,
+            date_source: DateSource::Now,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rincron::tests::test_dir;
+    use std::fs::File;
+
+    /// Creates a file and its check with a completion strategy
+    ///
+    /// The file name needs quoting, like for checks made from events the path is escaped
+    ///
+    /// # Parameters
+    ///
+    /// * `dir`: The directory of the file
+    /// * `completion`: The completion strategy
+    fn check_of(dir: &Path, completion: Completion) -> FileCheck {
+        let name = "a file";
+        std::fs::write(dir.join(name), "abc").unwrap();
+
+        let escaped = Path::new(&shell_escape::escape(dir.to_string_lossy()).to_string())
+            .join(shell_escape::escape(name.into()).to_string());
+        let mut fc = FileCheck::new(&escaped.to_string_lossy(), 100, "true");
+        fc.dir = dir.to_string_lossy().to_string();
+        fc.file = name.to_string();
+        fc.completion = completion;
+        fc
+    }
+
+    /// Runs a check once its interval is elapsed, returns `true` if the file changed
+    ///
+    /// # Parameters
+    ///
+    /// * `fc`: The file check
+    fn check(fc: &mut FileCheck) -> bool {
+        fc.tick(fc.check_interval);
+        fc.has_changed()
+    }
+
+    #[test]
+    fn immediate_completion_never_waits() {
+        let dir = test_dir("immediate");
+        let mut fc = check_of(&dir, Completion::Immediate);
+        assert!(!check(&mut fc));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_completion_waits_for_a_stable_size() {
+        let dir = test_dir("size");
+        let mut fc = check_of(&dir, Completion::Size);
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+
+        std::fs::write(fc.full_path(), "abcd").unwrap();
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mtime_completion_waits_for_a_stable_mtime() {
+        let dir = test_dir("mtime");
+        let mut fc = check_of(&dir, Completion::Mtime);
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+
+        // Same size, only the modification time tells the file was written again
+        let file = File::options().write(true).open(fc.full_path()).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_mtime_completion_waits_for_both() {
+        let dir = test_dir("size-mtime");
+        let mut fc = check_of(&dir, Completion::SizeMtime);
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+
+        let file = File::options().write(true).open(fc.full_path()).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(check(&mut fc));
+
+        std::fs::write(fc.full_path(), "abcd").unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_completion_waits_for_a_stable_content() {
+        let dir = test_dir("hash");
+        let mut fc = check_of(&dir, Completion::Hash(0));
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+
+        // Same size, different content
+        std::fs::write(fc.full_path(), "xyz").unwrap();
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sampled_hash_completion_sees_changes_at_both_ends() {
+        let dir = test_dir("sampled-hash");
+        let mut fc = check_of(&dir, Completion::Hash(1));
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+
+        // Only the first and last bytes are hashed with the size
+        std::fs::write(fc.full_path(), "xbc").unwrap();
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+
+        std::fs::write(fc.full_path(), "xbz").unwrap();
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checks_without_directory_use_the_path() {
+        let dir = test_dir("path-only");
+        let path = dir.join("file");
+        std::fs::write(&path, "abc").unwrap();
+
+        let mut fc = FileCheck::new(&path.to_string_lossy(), 100, "true");
+        assert_eq!(fc.full_path(), path);
+        assert!(check(&mut fc));
+        assert!(!check(&mut fc));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn marker_completion_waits_for_the_marker() {
+        let dir = test_dir("marker");
+        let mut fc = check_of(&dir, Completion::Marker(".done".to_string()));
+        assert!(check(&mut fc));
+
+        std::fs::write(dir.join("a file.done"), "").unwrap();
+        assert!(!check(&mut fc));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quiet_completion_waits_for_a_quiet_interval() {
+        let dir = test_dir("quiet");
+        let mut fc = check_of(&dir, Completion::Quiet);
+
+        // An event restarts the interval, the file is complete once it's elapsed
+        fc.tick(fc.check_interval / 2);
+        fc.reset();
+        fc.tick(fc.check_interval / 2);
+        assert!(fc.has_changed());
+        fc.tick(fc.check_interval / 2);
+        assert!(!fc.has_changed());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
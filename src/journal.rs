@@ -0,0 +1,211 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+
+/// The pending executions journal, one JSON object per line
+pub struct Journal {
+    /// The journal path
+    path: String,
+
+    /// The journal length already read or written by rincron
+    len: u64,
+
+    /// The number of lines in the journal
+    pub lines: usize,
+}
+
+impl Journal {
+    /// Opens a journal, creating it if needed
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: The journal path
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            path: path.to_string(),
+            len: 0,
+            lines: 0,
+        })
+    }
+
+    /// Reads the lines added since the last read or write, by rincron at startup or by other
+    /// tools later
+    ///
+    /// An incomplete last line is left for the next read
+    pub fn read_new(&mut self) -> std::io::Result<Vec<Value>> {
+        let mut file = OpenOptions::new().read(true).open(&self.path)?;
+        let len = file.metadata()?.len();
+
+        // Somebody else rewrote the journal, we start over
+        if len < self.len {
+            self.len = 0;
+            self.lines = 0;
+        }
+
+        if len == self.len {
+            return Ok(Vec::new());
+        }
+
+        file.seek(SeekFrom::Start(self.len))?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+
+        let complete = match content.rfind('\n') {
+            Some(v) => &content[..=v],
+            None => return Ok(Vec::new()),
+        };
+        self.len += complete.len() as u64;
+
+        let mut entries = Vec::new();
+
+        for line in complete.lines().filter(|l| !l.trim().is_empty()) {
+            self.lines += 1;
+
+            match serde_json::from_str(line) {
+                Ok(v) => entries.push(v),
+                Err(e) => log!(
+                    warning,
+                    "Warning: invalid journal line ignored ({}): {}",
+                    e,
+                    line
+                ),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Appends entries
+    ///
+    /// # Parameters
+    ///
+    /// * `entries`: The entries to append
+    pub fn append(&mut self, entries: &[Value]) -> std::io::Result<()> {
+        let content = Self::to_lines(entries);
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+
+        // A single write keeps lines whole even with other writers
+        file.write_all(content.as_bytes())?;
+        self.len += content.len() as u64;
+        self.lines += entries.len();
+        Ok(())
+    }
+
+    /// Replaces the journal content, the new file is renamed over the old one so readers never
+    /// see a partial journal
+    ///
+    /// Lines appended since the last read are carried over to the new file, to be read next
+    /// time. The lock file is held meanwhile, tools appending while holding it are never lost
+    ///
+    /// # Parameters
+    ///
+    /// * `entries`: The remaining entries
+    pub fn rewrite(&mut self, entries: &[Value]) -> std::io::Result<()> {
+        let content = Self::to_lines(entries);
+        let temp_path = format!("{}.tmp", &self.path);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+
+        let _lock = self.lock()?;
+        file.write_all(&self.read_tail()?)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &self.path)?;
+
+        self.len = content.len() as u64;
+        self.lines = entries.len();
+        Ok(())
+    }
+
+    /// Takes the lock of the journal, released when the returned file is closed
+    ///
+    /// The lock is taken on a separate file, the journal itself is replaced by rewrites
+    fn lock(&self) -> std::io::Result<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(format!("{}.lock", &self.path))?;
+
+        // SAFETY: plain call on an open descriptor
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(file)
+    }
+
+    /// Returns the content added since the last read or write
+    fn read_tail(&self) -> std::io::Result<Vec<u8>> {
+        let mut file = OpenOptions::new().read(true).open(&self.path)?;
+        let mut tail = Vec::new();
+
+        // A journal rewritten by somebody else is shorter, there's nothing to carry over
+        file.seek(SeekFrom::Start(self.len))?;
+        file.read_to_end(&mut tail)?;
+        Ok(tail)
+    }
+
+    /// Formats entries as JSON lines
+    ///
+    /// # Parameters
+    ///
+    /// * `entries`: The entries
+    fn to_lines(entries: &[Value]) -> String {
+        entries.iter().map(|e| format!("{}\n", e)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn rewrite_keeps_lines_appended_meanwhile() {
+        let path = std::env::temp_dir().join(format!("rincron-{}-journal", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal
+            .append(&[json!({"path": "/a"}), json!({"path": "/b"})])
+            .unwrap();
+
+        // Another tool appends after the last read, then rincron compacts
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"path\": \"/c\"}\n").unwrap();
+        journal.rewrite(&[json!({"path": "/b"})]).unwrap();
+
+        assert_eq!(journal.read_new().unwrap(), vec![json!({"path": "/c"})]);
+        assert_eq!(journal.lines, 2);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}.lock", path)).unwrap();
+    }
+}
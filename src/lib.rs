@@ -0,0 +1,63 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Rincron-mini as a library, for embedding the watcher in another program
+//!
+//! See the `Embedding` section of the README below
+
+#![warn(missing_docs)]
+#![warn(clippy::missing_docs_in_private_items)]
+#![doc = include_str!("../README.md")]
+
+/// The log writer
+#[macro_use]
+pub mod logger;
+
+/// The audit log of executed commands
+pub mod audit;
+/// A spawned command
+mod child_process;
+/// The file content hashing
+mod content_hasher;
+/// The date placeholders
+mod date_time;
+/// The attributes of a file
+mod file_attributes;
+/// The file checker
+pub mod file_check;
+/// The pending executions journal
+mod journal;
+/// The activity metrics
+mod metrics;
+/// The command line options
+pub mod options;
+/// A command executed periodically
+mod periodic_task;
+/// The event messages publisher
+#[cfg(feature = "nats")]
+mod publisher;
+/// The main program
+pub mod rincron;
+/// The systemd notifications
+mod systemd;
+/// The event rate limiter
+mod token_bucket;
+/// An event to watch
+mod watch_element;
+/// The manager of all events
+mod watch_manager;
+/// The threads executing callbacks
+pub mod worker_pool;
@@ -0,0 +1,243 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::date_time::DateTime;
+use crate::options::{LogFormat, LogTime};
+use std::fmt::{Arguments, Display};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Whether log lines are written to the error output instead of the standard output
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Whether log lines are written at all
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Whether log lines start with a timestamp
+static TIME_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Whether the timestamp is in UTC instead of the local timezone
+static TIME_UTC: AtomicBool = AtomicBool::new(false);
+
+/// Whether the timestamp has milliseconds
+static TIME_MILLIS: AtomicBool = AtomicBool::new(false);
+
+/// Whether log lines are JSON objects instead of text
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Writes log lines to the error output, the standard output is then free for other uses
+pub fn use_stderr() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+/// Enables or disables log lines, for measurements which must not include their cost
+///
+/// # Parameters
+///
+/// * `enabled`: Whether log lines are written
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Sets the timestamp written at the start of log lines
+///
+/// # Parameters
+///
+/// * `log_time`: The timestamp specification
+pub fn set_time(log_time: LogTime) {
+    TIME_ENABLED.store(log_time.enabled, Ordering::Relaxed);
+    TIME_UTC.store(log_time.utc, Ordering::Relaxed);
+    TIME_MILLIS.store(log_time.millis, Ordering::Relaxed);
+}
+
+/// Sets the format of log lines
+///
+/// # Parameters
+///
+/// * `log_format`: The format
+pub fn set_format(log_format: LogFormat) {
+    JSON.store(log_format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// The level of a log line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    /// Rincron can't work as expected anymore
+    Critical,
+
+    /// An operation failed
+    Error,
+
+    /// Something unexpected happened, but rincron goes on
+    Warning,
+
+    /// A normal operation
+    Info,
+}
+
+impl Level {
+    /// Returns the name of the level, as written in JSON log lines
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Critical => "critical",
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+        }
+    }
+}
+
+/// Formats a log line as a JSON object
+///
+/// # Parameters
+///
+/// * `prefix`: The timestamp prefix, empty if disabled
+/// * `level`: The level of the line
+/// * `path`: The path the line is about, if any
+/// * `message`: The log message
+fn json_line(prefix: &str, level: Level, path: Option<&dyn Display>, message: &str) -> String {
+    let mut line = serde_json::json!({
+        "level": level.name(),
+        "message": message,
+    });
+
+    if let Some(v) = path {
+        line["path"] = serde_json::Value::from(v.to_string());
+    }
+
+    if !prefix.is_empty() {
+        line["timestamp"] = serde_json::Value::from(prefix.trim_end());
+    }
+
+    line.to_string()
+}
+
+/// Returns the timestamp prefix of a log line, empty if disabled
+fn timestamp() -> String {
+    if !TIME_ENABLED.load(Ordering::Relaxed) {
+        return String::new();
+    }
+
+    let now = SystemTime::now();
+    let utc = TIME_UTC.load(Ordering::Relaxed);
+
+    let date = match DateTime::from_system_time(now, utc) {
+        Some(v) => v,
+        None => return String::new(),
+    };
+
+    let mut prefix = format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        date.year, date.month, date.day, date.hour, date.minute, date.second
+    );
+
+    if TIME_MILLIS.load(Ordering::Relaxed) {
+        let millis = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_millis())
+            .unwrap_or(0);
+        prefix.push_str(&format!(".{:03}", millis));
+    }
+
+    if utc {
+        prefix.push('Z');
+    }
+
+    prefix.push(' ');
+    prefix
+}
+
+/// Writes a log line
+///
+/// # Parameters
+///
+/// * `level`: The level of the line
+/// * `path`: The path the line is about, if any, only written in JSON log lines
+/// * `args`: The formatted message
+pub fn write(level: Level, path: Option<&dyn Display>, args: Arguments) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let prefix = timestamp();
+
+    let line = match JSON.load(Ordering::Relaxed) {
+        true => json_line(&prefix, level, path, &args.to_string()),
+        false => format!("{}{}", prefix, args),
+    };
+
+    if TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Writes a log line, works like `println!`
+///
+/// The level (`critical`, `error`, `warning` or `info`) and the path the line is about can be
+/// given first, like `log!(error, path = &file.path, "Unable to read {}", &file.path)`. Without
+/// level, the line is an information
+#[macro_export]
+macro_rules! log {
+    (critical, $($arg:tt)*) => {
+        $crate::log!(@level $crate::logger::Level::Critical, $($arg)*)
+    };
+    (error, $($arg:tt)*) => {
+        $crate::log!(@level $crate::logger::Level::Error, $($arg)*)
+    };
+    (warning, $($arg:tt)*) => {
+        $crate::log!(@level $crate::logger::Level::Warning, $($arg)*)
+    };
+    (info, $($arg:tt)*) => {
+        $crate::log!(@level $crate::logger::Level::Info, $($arg)*)
+    };
+    (@level $level:expr, path = $path:expr, $($arg:tt)*) => {
+        $crate::logger::write($level, Some(&$path), format_args!($($arg)*))
+    };
+    (@level $level:expr, $($arg:tt)*) => {
+        $crate::logger::write($level, None, format_args!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::log!(info, $($arg)*)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lines_carry_the_given_level_and_path() {
+        let line = json_line(
+            "",
+            Level::Warning,
+            Some(&"/tmp/a.log"),
+            "Error in the name only",
+        );
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "warning");
+        assert_eq!(value["path"], "/tmp/a.log");
+        assert_eq!(value["message"], "Error in the name only");
+        assert!(value.get("timestamp").is_none());
+
+        let line = json_line("2024-01-01 12:00:00 ", Level::Info, None, "Child 1 spawned");
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "info");
+        assert!(value.get("path").is_none());
+        assert_eq!(value["timestamp"], "2024-01-01 12:00:00");
+    }
+}
@@ -14,28 +14,72 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+//! The rincron-mini daemon, reading its options from the command line
+
 #![warn(missing_docs)]
 #![warn(clippy::missing_docs_in_private_items)]
-#![doc = include_str!("../README.md")]
-
-use rincron::Rincron;
 
-/// The file checker
-mod file_check;
-/// The main program
-mod rincron;
-/// An event to watch
-mod watch_element;
-/// The manager of all events
-mod watch_manager;
+use rincron_mini::options::Options;
+use rincron_mini::rincron::Rincron;
+use rincron_mini::{audit, log, logger};
 
 fn main() {
-    println!("Rincron-Mini Copyright (C) 2022-2023 Camille Nevermind");
-    println!("THIS SOFTWARE IS DISTRIBUTED UNDER GPL-3.0 LICENSE");
-    println!("THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND");
-    println!("EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES");
-    println!("OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.");
+    let options = Options::from_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        println!("{}", e);
+        println!("{}", Options::USAGE);
+        std::process::exit(1);
+    });
+
+    // The standard output is kept for events or the normalized config
+    if options.emit_events || options.normalize_config {
+        logger::use_stderr();
+    }
+
+    logger::set_time(options.log_time);
+    logger::set_format(options.log_format);
+
+    log!("Rincron-Mini Copyright (C) 2022-2023 Camille Nevermind");
+    log!("THIS SOFTWARE IS DISTRIBUTED UNDER GPL-3.0 LICENSE");
+    log!("THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND");
+    log!("EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES");
+    log!("OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.");
+
+    // Commands must not run without their audit trail
+    if let Some(path) = &options.audit_log {
+        if let Err(e) = audit::open(path) {
+            log!(
+                error,
+                path = &path,
+                "Error: unable to open audit log {}: {}",
+                path,
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+
+    let replay = options.replay.clone();
+    let stress = options.stress;
+    let normalize_config = options.normalize_config;
+    let check_config = options.check_config;
+    let mut rincron = Rincron::init(options).unwrap_or_else(|_| std::process::exit(1));
+
+    if let Some(path) = replay {
+        std::process::exit(rincron.replay(&path));
+    }
+
+    if stress > 0 {
+        std::process::exit(rincron.stress(stress));
+    }
+
+    if normalize_config {
+        rincron.normalize_config();
+        return;
+    }
+
+    if check_config {
+        std::process::exit(rincron.check_config());
+    }
 
-    let mut rincron = Rincron::init().unwrap_or_else(|_| std::process::exit(1));
     rincron.execute();
 }
@@ -0,0 +1,238 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+#[cfg(feature = "statsd")]
+use std::net::UdpSocket;
+#[cfg(feature = "statsd")]
+use std::time::{Duration, Instant};
+
+/// An activity counter
+#[derive(Clone, Copy)]
+pub enum Counter {
+    /// An event was received
+    Events,
+
+    /// A command was spawned
+    Spawns,
+
+    /// A command was unable to spawn or exited with an error
+    Failures,
+
+    /// An event was dropped by a rate limit
+    Shed,
+
+    /// A file was moved to the quarantine
+    Quarantines,
+}
+
+#[derive(Clone, Copy, Default)]
+/// Activity counters
+pub struct Counters {
+    /// The number of events received
+    pub events: u64,
+
+    /// The number of commands spawned
+    pub spawns: u64,
+
+    /// The number of commands unable to spawn or exited with an error
+    pub failures: u64,
+
+    /// The number of events dropped by rate limits
+    pub shed: u64,
+
+    /// The number of files moved to the quarantine
+    pub quarantines: u64,
+}
+
+impl Counters {
+    /// Returns a counter value
+    ///
+    /// # Parameters
+    ///
+    /// * `counter`: The counter
+    fn get_mut(&mut self, counter: Counter) -> &mut u64 {
+        match counter {
+            Counter::Events => &mut self.events,
+            Counter::Spawns => &mut self.spawns,
+            Counter::Failures => &mut self.failures,
+            Counter::Shed => &mut self.shed,
+            Counter::Quarantines => &mut self.quarantines,
+        }
+    }
+}
+
+#[derive(Default)]
+/// Activity metrics
+pub struct Metrics {
+    /// The counters since startup
+    pub counters: Counters,
+
+    /// The counters since startup of each profile
+    pub profiles: HashMap<String, Counters>,
+
+    /// The counters since startup of each config file
+    pub sources: HashMap<String, Counters>,
+
+    /// The counters of each profile already sent to statsd
+    #[cfg(feature = "statsd")]
+    sent_profiles: HashMap<String, Counters>,
+
+    /// The counters of each config file already sent to statsd
+    #[cfg(feature = "statsd")]
+    sent_sources: HashMap<String, Counters>,
+
+    /// The counters already sent to statsd
+    #[cfg(feature = "statsd")]
+    sent: Counters,
+
+    /// The statsd socket, connected to the server
+    #[cfg(feature = "statsd")]
+    statsd: Option<UdpSocket>,
+
+    /// The last time metrics were sent to statsd
+    #[cfg(feature = "statsd")]
+    sent_at: Option<Instant>,
+}
+
+impl Metrics {
+    /// Increments a counter, and the counters of the profile and the config file if any
+    ///
+    /// # Parameters
+    ///
+    /// * `counter`: The counter to increment
+    /// * `profile`: The profile of the element, empty without profiles
+    /// * `source`: The config file of the element, empty if unknown
+    pub fn increment(&mut self, counter: Counter, profile: &str, source: &str) {
+        *self.counters.get_mut(counter) += 1;
+
+        if !profile.is_empty() {
+            *self
+                .profiles
+                .entry(profile.to_string())
+                .or_default()
+                .get_mut(counter) += 1;
+        }
+
+        if !source.is_empty() {
+            *self
+                .sources
+                .entry(source.to_string())
+                .or_default()
+                .get_mut(counter) += 1;
+        }
+    }
+
+    /// Converts a config file path to a statsd name part, its file name without extension
+    ///
+    /// Characters other than letters, digits, `-` and `_` are replaced by `_`
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: The config file path
+    #[cfg(feature = "statsd")]
+    fn source_name(source: &str) -> String {
+        std::path::Path::new(source)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default()
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect()
+    }
+
+    /// Connects metrics to a statsd server
+    ///
+    /// # Parameters
+    ///
+    /// * `address`: The server address as `host:port`
+    #[cfg(feature = "statsd")]
+    pub fn connect_statsd(&mut self, address: &str) -> std::io::Result<()> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        self.statsd = Some(socket);
+        Ok(())
+    }
+
+    /// Sends counters increments and gauges to statsd if the interval has elapsed
+    ///
+    /// Statsd is fire-and-forget, send errors are ignored
+    ///
+    /// # Parameters
+    ///
+    /// * `interval`: The delay between two sends in seconds
+    /// * `children`: The number of running children
+    /// * `checks`: The number of pending file checks
+    #[cfg(feature = "statsd")]
+    pub fn send_statsd(&mut self, interval: u64, children: usize, checks: usize) {
+        let socket = match &self.statsd {
+            None => return,
+            Some(v) => v,
+        };
+
+        if let Some(sent_at) = self.sent_at {
+            if sent_at.elapsed() < Duration::from_secs(interval) {
+                return;
+            }
+        }
+
+        let mut packet = format!(
+            "rincron.events:{}|c\nrincron.spawns:{}|c\nrincron.failures:{}|c\nrincron.shed:{}|c\nrincron.children:{}|g\nrincron.checks:{}|g",
+            self.counters.events - self.sent.events,
+            self.counters.spawns - self.sent.spawns,
+            self.counters.failures - self.sent.failures,
+            self.counters.shed - self.sent.shed,
+            children,
+            checks
+        );
+
+        // Each profile gets its own counters
+        for (profile, counters) in &self.profiles {
+            let sent = self.sent_profiles.get(profile).copied().unwrap_or_default();
+            packet.push_str(&format!(
+                "\nrincron.{profile}.events:{}|c\nrincron.{profile}.spawns:{}|c\nrincron.{profile}.failures:{}|c\nrincron.{profile}.shed:{}|c",
+                counters.events - sent.events,
+                counters.spawns - sent.spawns,
+                counters.failures - sent.failures,
+                counters.shed - sent.shed,
+            ));
+        }
+
+        // And each config file, under the source prefix to not mix with profiles
+        for (source, counters) in &self.sources {
+            let sent = self.sent_sources.get(source).copied().unwrap_or_default();
+            let name = Self::source_name(source);
+            packet.push_str(&format!(
+                "\nrincron.source.{name}.events:{}|c\nrincron.source.{name}.spawns:{}|c\nrincron.source.{name}.failures:{}|c\nrincron.source.{name}.shed:{}|c",
+                counters.events - sent.events,
+                counters.spawns - sent.spawns,
+                counters.failures - sent.failures,
+                counters.shed - sent.shed,
+            ));
+        }
+
+        let _ = socket.send(packet.as_bytes());
+        self.sent = self.counters;
+        self.sent_profiles = self.profiles.clone();
+        self.sent_sources = self.sources.clone();
+        self.sent_at = Some(Instant::now());
+    }
+}
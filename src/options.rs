@@ -0,0 +1,560 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use simple_error::bail;
+
+/// The behavior when the maximum number of running children is reached
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum ChildrenCapPolicy {
+    /// Commands are held back until children exit
+    Backpressure,
+
+    /// A critical error is logged, but commands are still executed
+    Log,
+}
+
+/// The behavior when a command line is too long to be executed
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum LongCommandPolicy {
+    /// The shell reads the command on its standard input instead of its arguments
+    Stdin,
+
+    /// The execution fails
+    Reject,
+}
+
+/// The format of log lines
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Human readable text
+    Text,
+
+    /// One JSON object per line
+    Json,
+}
+
+/// The timestamp at the start of log lines
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct LogTime {
+    /// Whether log lines start with a timestamp
+    pub enabled: bool,
+
+    /// Whether the timestamp is in UTC instead of the local timezone
+    pub utc: bool,
+
+    /// Whether the timestamp has milliseconds
+    pub millis: bool,
+}
+
+impl Default for LogTime {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            utc: false,
+            millis: false,
+        }
+    }
+}
+
+/// A step of the main loop
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Phase {
+    /// Reaps exited commands
+    Children,
+
+    /// Counts down file checks and rate limits
+    Ticks,
+
+    /// Reads inotify events, waiting if there are none
+    Events,
+
+    /// Checks files waiting for completion
+    Checks,
+
+    /// Executes due periodic tasks
+    Periodic,
+
+    /// Executes commands of complete files
+    Executions,
+}
+
+impl Phase {
+    /// The default order of the main loop steps
+    pub const DEFAULT_ORDER: [Phase; 6] = [
+        Phase::Children,
+        Phase::Ticks,
+        Phase::Events,
+        Phase::Checks,
+        Phase::Periodic,
+        Phase::Executions,
+    ];
+
+    /// Returns the phase name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Children => "children",
+            Self::Ticks => "ticks",
+            Self::Events => "events",
+            Self::Checks => "checks",
+            Self::Periodic => "periodic",
+            Self::Executions => "executions",
+        }
+    }
+
+    /// Reads a phase from its name
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The phase name
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(match name {
+            "children" => Self::Children,
+            "ticks" => Self::Ticks,
+            "events" => Self::Events,
+            "checks" => Self::Checks,
+            "periodic" => Self::Periodic,
+            "executions" => Self::Executions,
+            _ => bail!("Unknown phase: {}", name),
+        })
+    }
+}
+
+#[derive(Clone)]
+/// Command line options
+pub struct Options {
+    /// The user to switch to once watches are established
+    pub run_as: Option<String>,
+
+    /// The statsd server address
+    pub statsd_addr: Option<String>,
+
+    /// The delay between statsd sends in seconds
+    pub statsd_interval: u64,
+
+    /// The idle time in seconds before exiting, 0 to never exit
+    pub idle_exit: u64,
+
+    /// Whether config elements with unknown keys are rejected
+    pub strict: bool,
+
+    /// Whether config elements watching the same directory as another one, or its parent or
+    /// a subdirectory, are rejected
+    pub reject_overlaps: bool,
+
+    /// The directory relative paths in configs are resolved against, the config file
+    /// directory if `None`
+    pub base_dir: Option<String>,
+
+    /// Whether rincron exits at startup if no watch is established
+    pub require_watches: bool,
+
+    /// The number of empty event reads using the burst interval after an event
+    pub burst_reads: u32,
+
+    /// The delay in milliseconds between event reads right after an event
+    pub burst_interval: u64,
+
+    /// The file to execute the command of, without starting the daemon
+    pub replay: Option<String>,
+
+    /// The number of files of the stress test to run instead of the daemon, 0 if disabled
+    pub stress: usize,
+
+    /// Whether matched events are written as JSON lines on the standard output
+    pub emit_events: bool,
+
+    /// Whether commands are never executed
+    pub no_exec: bool,
+
+    /// Whether the activity summary is not logged on exit
+    pub no_summary: bool,
+
+    /// The file where processed files are saved for elements executing commands once per file
+    pub processed_files: Option<String>,
+
+    /// Whether the loaded config is written in a canonical form instead of starting the daemon
+    pub normalize_config: bool,
+
+    /// Whether configs are only checked instead of starting the daemon
+    pub check_config: bool,
+
+    /// Whether SIGUSR2 re-executes the binary, keeping the inotify fd
+    pub reexec_on_usr2: bool,
+
+    /// The order of the main loop steps, each step appearing once
+    pub phases: Vec<Phase>,
+
+    /// The number of file descriptors kept free, output capturing commands are held back below
+    pub fd_headroom: u64,
+
+    /// The profiles to load instead of the default config, as name and config path
+    pub profiles: Vec<(String, String)>,
+
+    /// The file where executed commands are recorded
+    pub audit_log: Option<String>,
+
+    /// The file where pending executions are journaled
+    pub journal: Option<String>,
+
+    /// The file listing the config files to reload on SIGUSR1
+    pub reload_file: Option<String>,
+
+    /// The maximum number of running children, 0 for no limit
+    pub max_children: usize,
+
+    /// The behavior when the maximum number of running children is reached
+    pub max_children_policy: ChildrenCapPolicy,
+
+    /// The time in seconds running children get to exit when rincron exits
+    pub shutdown_grace: u64,
+
+    /// The timestamp of log lines
+    pub log_time: LogTime,
+
+    /// The format of log lines
+    pub log_format: LogFormat,
+
+    /// The maximum time in seconds a drained reload waits for pending work, 0 for no limit
+    pub drain_timeout: u64,
+
+    /// The maximum length in bytes of a command line, 0 for no limit
+    pub max_command_length: usize,
+
+    /// The behavior when a command line is longer than the maximum
+    pub long_command_policy: LongCommandPolicy,
+
+    /// The number of threads executing callbacks
+    pub workers: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            run_as: None,
+            statsd_addr: None,
+            statsd_interval: 10,
+            idle_exit: 0,
+            strict: false,
+            reject_overlaps: false,
+            base_dir: None,
+            require_watches: false,
+            burst_reads: 10,
+            burst_interval: 10,
+            replay: None,
+            stress: 0,
+            emit_events: false,
+            no_exec: false,
+            no_summary: false,
+            processed_files: None,
+            normalize_config: false,
+            check_config: false,
+            reexec_on_usr2: false,
+            phases: Phase::DEFAULT_ORDER.to_vec(),
+            fd_headroom: 64,
+            profiles: Vec::new(),
+            audit_log: None,
+            journal: None,
+            reload_file: None,
+            max_children: 1000,
+            max_children_policy: ChildrenCapPolicy::Backpressure,
+            shutdown_grace: 10,
+            log_time: LogTime::default(),
+            log_format: LogFormat::Text,
+            drain_timeout: 60,
+            max_command_length: 131072,
+            long_command_policy: LongCommandPolicy::Stdin,
+            workers: 4,
+        }
+    }
+}
+
+impl Options {
+    /// The help message
+    pub const USAGE: &'static str = "Usage: rincron-mini [OPTIONS]
+
+Options:
+    --run-as USER              Drop privileges to USER once watches are established
+    --statsd-addr HOST:PORT    Send metrics to a statsd server (needs the statsd feature)
+    --statsd-interval SECONDS  Delay between statsd sends (default: 10)
+    --idle-exit SECONDS        Exit once nothing happened for SECONDS
+    --strict                   Reject config elements with unknown keys
+    --reject-overlaps          Reject config elements whose watch overlaps another one
+    --base-dir DIR             Resolve relative paths in configs against DIR
+    --require-watches          Exit with an error if no watch is established at startup
+    --burst-reads COUNT        Empty event reads using the burst interval after an event
+                               (default: 10)
+    --burst-interval MS        Delay between event reads right after an event (default: 10)
+    --replay PATH              Execute the command for PATH like if an event happened, then
+                               exit with the command exit code
+    --stress COUNT             Measure event latency and throughput by writing COUNT files in
+                               a temporary watched directory, then exit
+    --emit-events              Write matched events as JSON lines on the standard output,
+                               logs are written on the error output
+    --no-exec                  Never execute commands, only log them
+    --no-summary               Don't log the activity summary on exit
+    --processed-files FILE     Save files processed by once_per_file elements in FILE
+    --normalize-config         Write the loaded config in a canonical form, then exit
+    --check-config             Check configs, then exit with 1 if anything was rejected
+    --reexec-on-usr2           Re-execute the binary on SIGUSR2, keeping watches and pending
+                               events
+    --phase-order LIST         The order of the main loop steps, comma separated (default:
+                               children,ticks,events,checks,periodic,executions)
+    --fd-headroom COUNT        File descriptors kept free, output capturing commands are held
+                               back when less are available (default: 64)
+    --profile NAME=PATH        Load the config file or directory PATH as profile NAME instead
+                               of the default config, can be repeated
+    --audit-log FILE           Record every executed command and its exit status in FILE
+    --journal FILE             Keep pending executions in FILE, executed again after a restart
+    --reload-file FILE         Only reload the config files listed in FILE on SIGUSR1, if it
+                               exists
+    --max-children COUNT       Maximum number of running commands, 0 for no limit
+                               (default: 1000)
+    --max-children-policy P    What to do once the maximum is reached: backpressure to hold
+                               commands back, log to log a critical error and execute them
+                               anyway (default: backpressure)
+    --shutdown-grace SECONDS   Time running commands get to exit when rincron exits
+                               (default: 10)
+    --log-time SPEC            Timestamp of log lines, comma separated: local or utc, seconds
+                               or millis, or none for no timestamp (default: local,seconds)
+    --log-format FORMAT        Format of log lines: text or json (default: text)
+    --drain-timeout SECONDS    Maximum time a reload on SIGHUP waits for pending work, 0 for
+                               no limit (default: 60)
+    --max-command-length BYTES Maximum length of a command line, 0 for no limit
+                               (default: 131072)
+    --long-command-policy P    What to do with longer command lines: stdin to give them to
+                               the shell on its standard input, reject to fail the execution
+                               (default: stdin)
+    --workers COUNT            Number of threads executing callbacks of embedders
+                               (default: 4)
+    -h, --help                 Print this help";
+
+    /// Extracts the value of an option
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The option name
+    /// * `args`: The remaining arguments
+    fn value<I: Iterator<Item = String>>(
+        name: &str,
+        args: &mut I,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        match args.next() {
+            Some(v) => Ok(v),
+            None => bail!("Missing value for {}", name),
+        }
+    }
+
+    /// Extracts the numeric value of an option
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The option name
+    /// * `args`: The remaining arguments
+    fn number<I: Iterator<Item = String>, N: std::str::FromStr>(
+        name: &str,
+        args: &mut I,
+    ) -> Result<N, Box<dyn std::error::Error>> {
+        let value = Self::value(name, args)?;
+
+        match value.parse() {
+            Ok(v) => Ok(v),
+            Err(_) => bail!("Invalid value for {}: {}", name, value),
+        }
+    }
+
+    /// Extracts the main loop order, every phase must appear exactly once
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The option name
+    /// * `args`: The remaining arguments
+    fn phases<I: Iterator<Item = String>>(
+        name: &str,
+        args: &mut I,
+    ) -> Result<Vec<Phase>, Box<dyn std::error::Error>> {
+        let value = Self::value(name, args)?;
+        let mut phases = Vec::new();
+
+        for phase in value.split(',') {
+            let phase = Phase::from_name(phase.trim())?;
+
+            if phases.contains(&phase) {
+                bail!("Invalid value for {}: {} appears twice", name, phase.name());
+            }
+
+            phases.push(phase);
+        }
+
+        if let Some(missing) = Phase::DEFAULT_ORDER.iter().find(|p| !phases.contains(p)) {
+            bail!("Invalid value for {}: {} is missing", name, missing.name());
+        }
+
+        Ok(phases)
+    }
+
+    /// Extracts a log lines timestamp specification
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The option name
+    /// * `args`: The remaining arguments
+    fn log_time<I: Iterator<Item = String>>(
+        name: &str,
+        args: &mut I,
+    ) -> Result<LogTime, Box<dyn std::error::Error>> {
+        let value = Self::value(name, args)?;
+        let mut log_time = LogTime::default();
+
+        for part in value.split(',') {
+            match part.trim() {
+                "none" => log_time.enabled = false,
+                "local" => log_time.utc = false,
+                "utc" => log_time.utc = true,
+                "seconds" => log_time.millis = false,
+                "millis" => log_time.millis = true,
+                v => bail!("Invalid value for {}: {}", name, v),
+            }
+        }
+
+        Ok(log_time)
+    }
+
+    /// Extracts a profile definition
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The option name
+    /// * `args`: The remaining arguments
+    fn profile<I: Iterator<Item = String>>(
+        name: &str,
+        args: &mut I,
+    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+        let value = Self::value(name, args)?;
+
+        let (profile, path) = match value.split_once('=') {
+            Some(v) => v,
+            None => bail!("Invalid value for {}: expected NAME=PATH", name),
+        };
+
+        // The name is used in metric names
+        if profile.is_empty()
+            || !profile
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            bail!(
+                "Invalid profile name for {}: {} (letters, digits, - and _ only)",
+                name,
+                profile
+            );
+        }
+
+        Ok((profile.to_string(), path.to_string()))
+    }
+
+    /// Reads options from command line arguments
+    ///
+    /// # Parameters
+    ///
+    /// * `args`: The arguments, without the program name
+    pub fn from_args<I: Iterator<Item = String>>(
+        mut args: I,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut options = Self::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--run-as" => options.run_as = Some(Self::value(&arg, &mut args)?),
+                "--statsd-addr" => options.statsd_addr = Some(Self::value(&arg, &mut args)?),
+                "--statsd-interval" => options.statsd_interval = Self::number(&arg, &mut args)?,
+                "--idle-exit" => options.idle_exit = Self::number(&arg, &mut args)?,
+                "--strict" => options.strict = true,
+                "--reject-overlaps" => options.reject_overlaps = true,
+                "--base-dir" => options.base_dir = Some(Self::value(&arg, &mut args)?),
+                "--require-watches" => options.require_watches = true,
+                "--burst-reads" => options.burst_reads = Self::number(&arg, &mut args)?,
+                "--burst-interval" => options.burst_interval = Self::number(&arg, &mut args)?,
+                "--replay" => options.replay = Some(Self::value(&arg, &mut args)?),
+                "--stress" => options.stress = Self::number(&arg, &mut args)?,
+                "--emit-events" => options.emit_events = true,
+                "--no-exec" => options.no_exec = true,
+                "--no-summary" => options.no_summary = true,
+                "--processed-files" => {
+                    options.processed_files = Some(Self::value(&arg, &mut args)?)
+                }
+                "--normalize-config" => options.normalize_config = true,
+                "--check-config" => options.check_config = true,
+                "--reexec-on-usr2" => options.reexec_on_usr2 = true,
+                "--phase-order" => options.phases = Self::phases(&arg, &mut args)?,
+                "--fd-headroom" => options.fd_headroom = Self::number(&arg, &mut args)?,
+                "--profile" => {
+                    let profile = Self::profile(&arg, &mut args)?;
+
+                    if options.profiles.iter().any(|(n, _)| *n == profile.0) {
+                        bail!("Profile {} defined twice", profile.0);
+                    }
+
+                    options.profiles.push(profile);
+                }
+                "--audit-log" => options.audit_log = Some(Self::value(&arg, &mut args)?),
+                "--journal" => options.journal = Some(Self::value(&arg, &mut args)?),
+                "--reload-file" => options.reload_file = Some(Self::value(&arg, &mut args)?),
+                "--max-children" => options.max_children = Self::number(&arg, &mut args)?,
+                "--max-children-policy" => {
+                    options.max_children_policy = match Self::value(&arg, &mut args)?.as_str() {
+                        "backpressure" => ChildrenCapPolicy::Backpressure,
+                        "log" => ChildrenCapPolicy::Log,
+                        v => bail!("Invalid value for {}: {}", arg, v),
+                    }
+                }
+                "--shutdown-grace" => options.shutdown_grace = Self::number(&arg, &mut args)?,
+                "--log-time" => options.log_time = Self::log_time(&arg, &mut args)?,
+                "--log-format" => {
+                    options.log_format = match Self::value(&arg, &mut args)?.as_str() {
+                        "text" => LogFormat::Text,
+                        "json" => LogFormat::Json,
+                        v => bail!("Invalid value for {}: {}", arg, v),
+                    }
+                }
+                "--drain-timeout" => options.drain_timeout = Self::number(&arg, &mut args)?,
+                "--max-command-length" => {
+                    options.max_command_length = Self::number(&arg, &mut args)?
+                }
+                "--long-command-policy" => {
+                    options.long_command_policy = match Self::value(&arg, &mut args)?.as_str() {
+                        "stdin" => LongCommandPolicy::Stdin,
+                        "reject" => LongCommandPolicy::Reject,
+                        v => bail!("Invalid value for {}: {}", arg, v),
+                    }
+                }
+                "--workers" => {
+                    options.workers = Self::number(&arg, &mut args)?;
+
+                    if options.workers == 0 {
+                        bail!("{} must be at least 1", arg);
+                    }
+                }
+                "-h" | "--help" => {
+                    println!("{}", Self::USAGE);
+                    std::process::exit(0);
+                }
+                _ => bail!("Unknown option: {}", arg),
+            }
+        }
+
+        Ok(options)
+    }
+}
@@ -0,0 +1,190 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::date_time::DateTime;
+use crate::watch_element::WatchElement;
+use serde_json::{json, Value};
+use simple_error::bail;
+use std::time::{Duration, Instant, SystemTime};
+
+/// When a periodic task is executed
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Schedule {
+    /// Every given number of seconds
+    Interval(u64),
+
+    /// Every day at the given number of seconds after local midnight
+    Daily(u64),
+}
+
+/// A command executed periodically, independently of events
+#[derive(Clone)]
+pub struct PeriodicTask {
+    /// The command to execute
+    pub command: String,
+
+    /// When the command is executed
+    pub schedule: Schedule,
+
+    /// Whether the command outputs are written to the log
+    pub capture_output: bool,
+
+    /// The next execution time
+    pub next_run: Instant,
+
+    /// The config file the task was loaded from
+    pub source: String,
+}
+
+impl PeriodicTask {
+    /// The keys allowed in a periodic block, keys starting with `_` are always allowed
+    const KNOWN_KEYS: &'static [&'static str] = &["command", "interval", "at", "capture_output"];
+
+    /// Computes the delay before the next execution
+    fn next_delay(&self) -> Duration {
+        match self.schedule {
+            Schedule::Interval(v) => Duration::from_secs(v),
+            Schedule::Daily(at) => {
+                // Without the local time, we wait a whole day
+                let now = match DateTime::from_system_time(SystemTime::now(), false) {
+                    Some(v) => (v.hour * 3600 + v.minute * 60 + v.second) as u64,
+                    None => at,
+                };
+
+                match (at + 86400 - now) % 86400 {
+                    0 => Duration::from_secs(86400),
+                    v => Duration::from_secs(v),
+                }
+            }
+        }
+    }
+
+    /// Plans the next execution
+    pub fn schedule_next(&mut self) {
+        self.next_run = Instant::now() + self.next_delay();
+    }
+
+    /// Whether two tasks are the same, regardless of their next execution
+    ///
+    /// # Parameters
+    ///
+    /// * `other`: The other task
+    pub fn same_as(&self, other: &Self) -> bool {
+        self.command == other.command
+            && self.schedule == other.schedule
+            && self.capture_output == other.capture_output
+    }
+
+    /// Converts the task back to a config `periodic` block
+    pub fn to_json_value(&self) -> Value {
+        let mut value = json!({
+            "command": &self.command,
+            "capture_output": self.capture_output,
+        });
+
+        match self.schedule {
+            Schedule::Interval(v) => value["interval"] = Value::from(v),
+            Schedule::Daily(v) => {
+                value["at"] = Value::from(format!("{:02}:{:02}", v / 3600, v % 3600 / 60))
+            }
+        }
+
+        json!({ "periodic": value })
+    }
+
+    /// Parses a daily time like `03:30`
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The time string
+    fn parse_time(value: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        let parsed = value
+            .split_once(':')
+            .and_then(|(h, m)| Some((h.parse::<u64>().ok()?, m.parse::<u64>().ok()?)));
+
+        match parsed {
+            Some((h, m)) if h < 24 && m < 60 => Ok(h * 3600 + m * 60),
+            _ => bail!("Invalid time {}, expected HH:MM", value),
+        }
+    }
+
+    /// Creates a periodic task from a `periodic` JSON block
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The json object
+    /// * `strict`: Reject the block if a key is unknown instead of printing a warning
+    pub fn from_json_value(
+        value: &Value,
+        strict: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if !value.is_object() {
+            bail!("Periodic block is not an object: {}", value);
+        }
+
+        WatchElement::check_keys(value, Self::KNOWN_KEYS, strict)?;
+
+        let command = match value.get("command").and_then(|v| v.as_str()) {
+            Some(v) if !v.is_empty() => v.to_string(),
+            _ => bail!("No command found for periodic block"),
+        };
+
+        let schedule = match (value.get("interval"), value.get("at")) {
+            (Some(_), Some(_)) => bail!("Periodic block can't have both interval and at"),
+            (Some(v), None) => match v.as_u64() {
+                Some(s) if s > 0 => Schedule::Interval(s),
+                _ => bail!("Invalid periodic interval: {}", v),
+            },
+            (None, Some(v)) => match v.as_str() {
+                Some(s) => Schedule::Daily(Self::parse_time(s)?),
+                None => bail!("Invalid periodic time: {}", v),
+            },
+            (None, None) => bail!("Periodic block needs an interval or an at time"),
+        };
+
+        let capture_output = value
+            .get("capture_output")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let mut task = Self {
+            command,
+            schedule,
+            capture_output,
+            next_run: Instant::now(),
+            source: String::new(),
+        };
+
+        task.schedule_next();
+        Ok(task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_keys_are_checked_like_elements() {
+        let block = json!({"command": "true", "interval": 60, "_comment": "hourly", "every": 1});
+        assert!(PeriodicTask::from_json_value(&block, true).is_err());
+        assert!(PeriodicTask::from_json_value(&block, false).is_ok());
+
+        let block = json!({"command": "true", "at": "03:30", "_comment": "nightly"});
+        assert!(PeriodicTask::from_json_value(&block, true).is_ok());
+    }
+}
@@ -0,0 +1,306 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// A message to publish
+struct Message {
+    /// The NATS subject
+    subject: String,
+
+    /// The message content
+    payload: String,
+}
+
+/// A NATS publisher
+///
+/// Messages are sent by a dedicated thread, publishing never blocks the main loop. If the server
+/// is unreachable, messages are buffered and sent again once the connection is back
+pub struct Publisher {
+    /// The channel to the sending thread
+    sender: Sender<Message>,
+}
+
+impl Publisher {
+    /// The maximum number of messages kept while the server is unreachable
+    const MAX_BUFFERED: usize = 1000;
+
+    /// The delay between two connection attempts
+    const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+    /// The maximum length of a line sent by the server
+    const MAX_LINE: usize = 1 << 16;
+
+    /// Creates a publisher and starts its sending thread
+    ///
+    /// # Parameters
+    ///
+    /// * `server`: The NATS server address as `host:port`
+    pub fn new(server: &str) -> Self {
+        let (sender, receiver) = channel();
+        let server = server.to_string();
+
+        std::thread::spawn(move || Self::run(&server, receiver));
+
+        Self { sender }
+    }
+
+    /// Queues a message
+    ///
+    /// # Parameters
+    ///
+    /// * `subject`: The NATS subject
+    /// * `payload`: The message content
+    pub fn publish(&self, subject: &str, payload: &str) {
+        let message = Message {
+            subject: subject.to_string(),
+            payload: payload.to_string(),
+        };
+
+        if self.sender.send(message).is_err() {
+            log!(error, "Error while publishing: sending thread stopped");
+        }
+    }
+
+    /// Opens a connection to the server
+    ///
+    /// # Parameters
+    ///
+    /// * `server`: The NATS server address
+    fn connect(server: &str) -> std::io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(server)?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+        stream.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")?;
+        Ok(stream)
+    }
+
+    /// Splits the complete lines out of what the server sent
+    ///
+    /// The last line is kept in `received` if it isn't complete yet, a read may stop anywhere
+    ///
+    /// # Parameters
+    ///
+    /// * `received`: The bytes received and not handled yet
+    fn take_lines(received: &mut Vec<u8>) -> Vec<String> {
+        let end = match received.iter().rposition(|&b| b == b'\n') {
+            Some(v) => v + 1,
+            None => return Vec::new(),
+        };
+
+        let lines = String::from_utf8_lossy(&received[..end])
+            .lines()
+            .map(|l| l.trim_end_matches('\r').to_string())
+            .collect();
+        received.drain(..end);
+        lines
+    }
+
+    /// Reads what the server sent, answers its pings and logs its errors
+    ///
+    /// # Parameters
+    ///
+    /// * `server`: The NATS server address
+    /// * `stream`: The connection to the server
+    /// * `received`: The bytes received and not handled yet, kept between calls
+    fn answer_pings(
+        server: &str,
+        stream: &mut TcpStream,
+        received: &mut Vec<u8>,
+    ) -> std::io::Result<()> {
+        let mut buffer = [0; 4096];
+
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionAborted,
+                        "connection closed",
+                    ))
+                }
+                Ok(n) => received.extend_from_slice(&buffer[..n]),
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        for line in Self::take_lines(received) {
+            if line == "PING" {
+                stream.write_all(b"PONG\r\n")?;
+            } else if line.starts_with("-ERR") {
+                log!(error, "Error from NATS server {}: {}", server, line);
+            }
+        }
+
+        // A server never sends lines this long, something went wrong
+        if received.len() > Self::MAX_LINE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "line too long",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Sends queued messages until the publisher is dropped
+    ///
+    /// # Parameters
+    ///
+    /// * `server`: The NATS server address
+    /// * `receiver`: The channel receiving messages
+    fn run(server: &str, receiver: Receiver<Message>) {
+        let mut buffer: VecDeque<Message> = VecDeque::new();
+        let mut stream: Option<TcpStream> = None;
+        let mut received: Vec<u8> = Vec::new();
+
+        loop {
+            // We wait for new messages, but not forever if some are waiting for a retry or if the
+            // server is waiting for an answer
+            let timeout = if buffer.is_empty() {
+                Duration::from_secs(30)
+            } else {
+                Self::RETRY_DELAY
+            };
+
+            match receiver.recv_timeout(timeout) {
+                Ok(v) => buffer.push_back(v),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            while let Ok(v) = receiver.try_recv() {
+                buffer.push_back(v);
+            }
+
+            // Oldest messages are dropped if the server is down for too long
+            while buffer.len() > Self::MAX_BUFFERED {
+                buffer.pop_front();
+                log!(
+                    warning,
+                    "Warning: NATS buffer full for {}, message dropped",
+                    server
+                );
+            }
+
+            if stream.is_none() {
+                match Self::connect(server) {
+                    Ok(v) => {
+                        stream = Some(v);
+                        received.clear();
+                    }
+                    Err(e) => {
+                        log!(
+                            error,
+                            "Error while connecting to NATS server {}: {}",
+                            server,
+                            e
+                        );
+                        continue;
+                    }
+                }
+            }
+
+            // The server closes connections not answering its pings
+            if let Err(e) = Self::answer_pings(server, stream.as_mut().unwrap(), &mut received) {
+                log!(
+                    error,
+                    "Error while reading from NATS server {}: {}",
+                    server,
+                    e
+                );
+                stream = None;
+                continue;
+            }
+
+            while let Some(message) = buffer.front() {
+                let packet = format!(
+                    "PUB {} {}\r\n{}\r\n",
+                    &message.subject,
+                    message.payload.len(),
+                    &message.payload
+                );
+
+                if let Err(e) = stream.as_mut().unwrap().write_all(packet.as_bytes()) {
+                    log!(
+                        error,
+                        "Error while publishing to NATS server {}: {}",
+                        server,
+                        e
+                    );
+                    stream = None;
+                    break;
+                }
+
+                buffer.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_split_across_reads() {
+        let mut received = b"INFO {}\r\nPI".to_vec();
+        assert_eq!(Publisher::take_lines(&mut received), vec!["INFO {}"]);
+        assert_eq!(received, b"PI");
+
+        received.extend_from_slice(b"NG\r\n-ERR 'Invalid Subject'\r\n+OK");
+        assert_eq!(
+            Publisher::take_lines(&mut received),
+            vec!["PING", "-ERR 'Invalid Subject'"]
+        );
+        assert_eq!(received, b"+OK");
+        assert!(Publisher::take_lines(&mut received).is_empty());
+    }
+
+    #[test]
+    fn pings_are_answered_once_complete() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut received = Vec::new();
+        let mut answer = [0; 16];
+
+        // A message payload mentioning PING isn't a ping
+        server.write_all(b"MSG a 1 4\r\nPINGS\r\nPI").unwrap();
+        Publisher::answer_pings("test", &mut stream, &mut received).unwrap();
+        assert!(server.read(&mut answer).is_err());
+
+        server.write_all(b"NG\r\n").unwrap();
+        Publisher::answer_pings("test", &mut stream, &mut received).unwrap();
+        let n = server.read(&mut answer).unwrap();
+        assert_eq!(&answer[..n], b"PONG\r\n");
+    }
+}
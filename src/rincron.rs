@@ -14,23 +14,43 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::audit;
+use crate::child_process::ChildProcess;
+use crate::file_attributes::FileAttributes;
 use crate::file_check::FileCheck;
-use crate::watch_element::WatchElement;
+use crate::journal::Journal;
+use crate::logger;
+use crate::metrics::{Counter, Metrics};
+use crate::options::{ChildrenCapPolicy, LongCommandPolicy, Options, Phase};
+use crate::periodic_task::PeriodicTask;
+#[cfg(feature = "nats")]
+use crate::publisher::Publisher;
+use crate::systemd;
+use crate::token_bucket::TokenBucket;
+use crate::watch_element::{
+    ActionTarget, Completion, FilenameMode, ParseContext, PendingEventPolicy, ScanOrder,
+    ShutdownPolicy, SubstitutionMode, SymlinkPolicy, VerifyFailure, WatchElement,
+};
 use crate::watch_manager::WatchManager;
+use crate::worker_pool::{Callback, WorkerPool};
 use glob::glob;
-use inotify::Inotify;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
 use serde_json::Value;
 use simple_error::bail;
-use std::ffi::OsStr;
-use std::io::ErrorKind;
-use std::path::Path;
-use std::process::Child;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::process::ExitStatus;
 use std::process::Stdio;
 use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
-use std::time::Duration;
-use wildmatch::WildMatch;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// The main program
 pub struct Rincron {
@@ -46,361 +66,5675 @@ pub struct Rincron {
     /// The files to execute
     file_executions: Vec<FileCheck>,
 
+    /// The journal of pending executions, if enabled
+    journal: Option<Journal>,
+
+    /// The files created and waiting for a CLOSE_WRITE event, with the remaining time to wait in
+    /// milliseconds
+    pending_creates: HashMap<String, (i64, FileCheck)>,
+
+    /// The files moved and waiting for the MOVED_TO event of their rename, by event cookie, with
+    /// the remaining time to wait in milliseconds
+    pending_moves: HashMap<u32, (i64, FileCheck)>,
+
+    /// The subdirectories of elements triggering once per directory, with the remaining time to
+    /// wait in milliseconds without event before their command is executed
+    settling_dirs: HashMap<PathBuf, (i64, FileCheck)>,
+
+    /// The commands executed periodically
+    periodic_tasks: Vec<PeriodicTask>,
+
+    /// The watched path of the last executed file, where fair ordering starts from
+    last_served: Option<String>,
+
+    /// Whether the maximum number of running children was reached at the last execution
+    children_cap_reached: bool,
+
+    /// The maximum number of running children, 0 for no limit, lowered or raised at runtime
+    /// by signals
+    max_children: usize,
+
+    /// The limit the maximum number of running children goes back to when raised, the
+    /// running children when it was first lowered without limit
+    max_children_ceiling: usize,
+
+    /// The maximum number of open file descriptors
+    fd_limit: u64,
+
+    /// Whether too few file descriptors were left at the last output capturing execution
+    fd_limit_reached: bool,
+
+    /// The last time working directories of children were checked
+    last_cwd_check: Instant,
+
+    /// The elements whose watch was removed by the kernel, waiting for their path to exist
+    /// again
+    lost_elements: Vec<WatchElement>,
+
+    /// The number of config files, elements and periodic tasks rejected by the last config read
+    config_errors: usize,
+
+    /// The delay between two systemd watchdog notifications, `None` without watchdog
+    watchdog_interval: Option<Duration>,
+
+    /// The last time the systemd watchdog was notified
+    last_watchdog: Instant,
+
+    /// The last time paths of lost elements were checked
+    last_lost_check: Instant,
+
+    /// The rate limits of elements, by watched path
+    rate_buckets: HashMap<String, TokenBucket>,
+
+    /// The number of failures of each file of elements with a quarantine
+    failure_counts: HashMap<String, u32>,
+
+    /// The files already processed by elements executing commands once per file
+    processed_files: HashSet<String>,
+
+    /// The recently processed inodes, as (device, inode), with the path they were seen through
+    /// and their dedup expiration
+    recent_inodes: HashMap<(u64, u64), (PathBuf, Instant)>,
+
+    /// The last known attributes of files of elements tracking them, by path
+    attribute_cache: HashMap<PathBuf, FileAttributes>,
+
+    /// The callbacks executed in place of commands, by watched path
+    callbacks: HashMap<String, Callback>,
+
+    /// The threads executing callbacks, started with the first callback execution
+    workers: Option<WorkerPool>,
+
     /// The sigterm signal
     sigterm: Arc<AtomicBool>,
 
     /// The sigusr1 signal
     reload: Arc<AtomicBool>,
 
+    /// The sigusr2 signal
+    upgrade: Arc<AtomicBool>,
+
+    /// The sighup signal
+    drain: Arc<AtomicBool>,
+
+    /// The sigttou signal
+    throttle_down: Arc<AtomicBool>,
+
+    /// The sigttin signal
+    throttle_up: Arc<AtomicBool>,
+
+    /// When the current drained reload started, `None` if not draining
+    draining_since: Option<Instant>,
+
+    /// The events deferred until the end of the drained reload, as directory, mask, cookie and
+    /// file name
+    deferred_events: Vec<(PathBuf, EventMask, u32, OsString)>,
+
+    /// The commands started by the process before a re-exec, only reaped
+    orphans: Vec<libc::pid_t>,
+
+    /// Whether initial scans are skipped at the next config read, the inotify descriptor being
+    /// adopted from the process before a re-exec
+    skip_initial_scans: bool,
+
     /// The delay between event watches in milliseconds
     watch_interval: u64,
 
+    /// The number of consecutive event reads without events
+    empty_reads: u32,
+
+    /// The last time file checks were ticked
+    last_tick: Instant,
+
     /// The spawned children
-    child_processes: Vec<Child>,
+    child_processes: Vec<ChildProcess>,
 
     /// The config root
     config_root: String,
+
+    /// The command line options
+    options: Options,
+
+    /// The activity metrics
+    metrics: Metrics,
+
+    /// The startup time
+    started_at: Instant,
+
+    /// The last time something happened
+    last_activity: Instant,
+
+    /// The event message publishers, by server
+    #[cfg(feature = "nats")]
+    publishers: HashMap<String, Publisher>,
 }
 
 impl Rincron {
+    /// The environment variable passing the inotify descriptor through a re-exec
+    const INOTIFY_FD_VAR: &'static str = "RINCRON_INOTIFY_FD";
+
+    /// The environment variable passing the running commands through a re-exec
+    const ORPHANS_VAR: &'static str = "RINCRON_ORPHANS";
+
+    /// The environment variable passing the file of pending work through a re-exec
+    const PENDING_VAR: &'static str = "RINCRON_PENDING";
+
+    /// The extensions of config files, JSON first
+    const CONFIG_EXTENSIONS: [&'static str; 4] = ["json", "yaml", "yml", "toml"];
+
     /// Initiolizes ricron with inotify
-    pub fn init() -> Result<Self, Box<dyn std::error::Error>> {
+    ///
+    /// # Parameters
+    ///
+    /// * `options`: The command line options
+    pub fn init(options: Options) -> Result<Self, Box<dyn std::error::Error>> {
+        let processed_files = Self::load_processed_files(&options);
+        let (inotify, adopted) = Self::adopt_inotify()?;
+
         Ok(Self {
-            inotify: Inotify::init()?,
+            inotify,
             manager: WatchManager::default(),
             file_checks: Vec::new(),
             file_executions: Vec::new(),
+            journal: None,
+            pending_creates: HashMap::new(),
+            pending_moves: HashMap::new(),
+            settling_dirs: HashMap::new(),
+            recent_inodes: HashMap::new(),
+            attribute_cache: HashMap::new(),
+            callbacks: HashMap::new(),
+            workers: None,
+            processed_files,
+            rate_buckets: HashMap::new(),
+            failure_counts: HashMap::new(),
+            periodic_tasks: Vec::new(),
+            last_served: None,
+            children_cap_reached: false,
+            max_children: options.max_children,
+            max_children_ceiling: options.max_children,
+            fd_limit: Self::read_fd_limit(),
+            fd_limit_reached: false,
+            last_cwd_check: Instant::now(),
+            lost_elements: Vec::new(),
+            config_errors: 0,
+            watchdog_interval: systemd::watchdog_interval(),
+            last_watchdog: Instant::now(),
+            last_lost_check: Instant::now(),
             sigterm: Arc::new(AtomicBool::new(false)),
             reload: Arc::new(AtomicBool::new(false)),
+            upgrade: Arc::new(AtomicBool::new(false)),
+            drain: Arc::new(AtomicBool::new(false)),
+            throttle_down: Arc::new(AtomicBool::new(false)),
+            throttle_up: Arc::new(AtomicBool::new(false)),
+            draining_since: None,
+            deferred_events: Vec::new(),
+            orphans: Self::adopt_orphans(),
+            skip_initial_scans: adopted,
             watch_interval: 100,
+            empty_reads: 0,
+            last_tick: Instant::now(),
             child_processes: Vec::new(),
             config_root: Self::get_config_root(),
+            options,
+            metrics: Metrics::default(),
+            started_at: Instant::now(),
+            last_activity: Instant::now(),
+            #[cfg(feature = "nats")]
+            publishers: HashMap::new(),
         })
     }
 
-    /// Returns the config directory for the current user
-    fn get_config_root() -> String {
-        let home_path = dirs::home_dir();
+    /// Creates the inotify object, or adopts the one passed by the process before a re-exec
+    ///
+    /// Returns the object and whether it was adopted
+    fn adopt_inotify() -> Result<(Inotify, bool), Box<dyn std::error::Error>> {
+        if let Some(value) = std::env::var_os(Self::INOTIFY_FD_VAR) {
+            std::env::remove_var(Self::INOTIFY_FD_VAR);
 
-        if home_path.is_none() {
-            return "/etc".to_string();
-        }
-        let home_pathbuf = home_path.unwrap();
-        let home_path = home_pathbuf.to_string_lossy();
+            match value.to_string_lossy().parse::<RawFd>() {
+                Ok(fd) if Self::is_inotify_fd(fd) => {
+                    // Commands must not inherit it
+                    // SAFETY: the descriptor is open, checked just above
+                    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                    log!("Inotify descriptor {} adopted, watches are kept", fd);
 
-        if home_path == "/root" {
-            return "/etc".to_string();
+                    // SAFETY: the descriptor was passed by the previous process, nothing else owns it
+                    return Ok((unsafe { Inotify::from_raw_fd(fd) }, true));
+                }
+                _ => log!(
+                    warning,
+                    "Warning: invalid inherited inotify descriptor {}, starting from scratch",
+                    value.to_string_lossy()
+                ),
+            }
         }
 
-        format!("{}/.config", home_path)
+        Ok((Inotify::init()?, false))
     }
 
-    /// Reads all config files
+    /// Checks if a descriptor is an open inotify descriptor
     ///
-    /// Config files are found in /etc/rincron-mini directory
-    /// If you don't want a folder, you can use /etc/rincron-mini.json
-    pub fn read_configs(&mut self) {
-        let config_file = format!("{}/rincron-mini.json", &self.config_root);
-        let config_dir_pattern = format!("{}/rincron-mini/*.json", &self.config_root);
-
-        self.manager.begin_transaction();
-
-        println!("Checking config file {}", &config_file);
-
-        // First we check the main config file
-        if Path::new(&config_file).exists() {
-            if let Err(e) = self.read_config(&config_file) {
-                println!("Error while reading config file {}: {}", &config_file, e);
-            }
+    /// # Parameters
+    ///
+    /// * `fd`: The descriptor
+    fn is_inotify_fd(fd: RawFd) -> bool {
+        match std::fs::read_link(format!("/proc/self/fd/{}", fd)) {
+            Ok(v) => v.as_os_str() == "anon_inode:inotify",
+            Err(_) => false,
         }
+    }
 
-        println!("Scanning config files {}", &config_dir_pattern);
+    /// Reads the commands started by the process before a re-exec
+    fn adopt_orphans() -> Vec<libc::pid_t> {
+        let value = match std::env::var(Self::ORPHANS_VAR) {
+            Ok(v) => v,
+            Err(_) => return Vec::new(),
+        };
+        std::env::remove_var(Self::ORPHANS_VAR);
 
-        // After that, we check the folder for more config files
-        let files = glob(&config_dir_pattern);
+        let orphans: Vec<libc::pid_t> = value.split(',').filter_map(|p| p.parse().ok()).collect();
 
-        // It's horrible but I don't know how to properly write this (yet)
-        if let Ok(v) = files {
-            // We process each entry found in glob scanning
-            for entry in v {
-                // I don't know why but you can have sub errors
-                match entry {
-                    // Finally, a found config file
-                    Ok(p) => {
-                        println!("Config file found: {}", p.display());
-                        if let Err(e) = self.read_config(&p.to_string_lossy()) {
-                            println!("Error while reading config file {}: {}", p.display(), e);
-                        }
-                    }
-                    // I don't know how this error is triggered
-                    Err(e) => {
-                        println!("Error while scanning config files: {}", e);
-                    }
-                }
-            }
+        if !orphans.is_empty() {
+            log!(
+                "{} commands still running from before the re-exec",
+                orphans.len()
+            );
         }
 
-        self.manager.end_transaction(&mut self.inotify);
+        orphans
     }
 
-    /// Reads a config file
-    ///
-    /// # Parameters
+    /// Re-executes the binary with the same arguments, passing the inotify descriptor
     ///
-    /// * `path`: The config file path
-    pub fn read_config(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        // Check if config file exists
-        let cfg_file = Path::new(path);
+    /// Only returns if the binary couldn't be executed
+    fn reexec(&mut self) {
+        let fd = self.inotify.as_raw_fd();
+        let mut command = Command::new(Self::current_binary());
+        command.args(std::env::args_os().skip(1));
 
-        if !cfg_file.exists() {
-            bail!("The config file {} doesn't exist", path);
+        // The descriptor is closed on exec by default
+        // SAFETY: plain calls on our own descriptor
+        let inheritable = unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            flags >= 0 && libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == 0
+        };
+
+        if inheritable {
+            command.env(Self::INOTIFY_FD_VAR, fd.to_string());
+        } else {
+            log!(
+                warning,
+                "Warning: unable to pass the inotify descriptor ({}), watches will be established again",
+                std::io::Error::last_os_error()
+            );
+            command.env_remove(Self::INOTIFY_FD_VAR);
         }
 
-        // Read config file
-        let cfg_string = std::fs::read_to_string(cfg_file);
+        let pids: Vec<String> = self
+            .child_processes
+            .iter()
+            .map(|p| p.child.id().to_string())
+            .chain(self.orphans.iter().map(|p| p.to_string()))
+            .collect();
+        command.env(Self::ORPHANS_VAR, pids.join(","));
 
-        if let Err(e) = cfg_string {
-            bail!("Error while reading config file: {}", e.to_string());
-        }
+        let pending = self.save_pending();
 
-        // Deserialize JSON
-        let cfg_string = cfg_string.unwrap();
-        let cfg_json = serde_json::from_str(&cfg_string);
+        match &pending {
+            Some(path) => command.env(Self::PENDING_VAR, path),
+            None => command.env_remove(Self::PENDING_VAR),
+        };
 
-        if let Err(e) = cfg_json {
-            bail!("Error while deserializing JSON: {}", e.to_string());
+        log!("Re-executing rincron");
+        let error = command.exec();
+        log!(error, "Error while re-executing rincron: {}", error);
+
+        if let Some(path) = pending {
+            let _ = std::fs::remove_file(path);
         }
 
-        let cfg_json: Value = cfg_json.unwrap();
+        // SAFETY: plain call on our own descriptor
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
 
-        // Read all dirs
-        if !cfg_json.is_array() {
-            bail!("Config JSON must be an array");
-        }
+    /// Writes the pending work to a file read by the process after a re-exec, returns its path
+    ///
+    /// Journaled executions are not written, the journal already passes them
+    fn save_pending(&self) -> Option<String> {
+        let executions = self
+            .file_executions
+            .iter()
+            .filter(|f| !f.journaled && f.source != "periodic")
+            .map(|f| Self::pending_value("executions", f, 0));
+        let checks = self
+            .file_checks
+            .iter()
+            .map(|f| Self::pending_value("checks", f, 0));
+        let creates = self
+            .pending_creates
+            .values()
+            .map(|(r, f)| Self::pending_value("creates", f, *r));
+        let moves = self
+            .pending_moves
+            .values()
+            .map(|(r, f)| Self::pending_value("moves", f, *r));
+        let settling = self
+            .settling_dirs
+            .values()
+            .map(|(r, f)| Self::pending_value("settling", f, *r));
 
-        let cfg_array = cfg_json.as_array().unwrap();
+        let entries: Vec<Value> = executions
+            .chain(checks)
+            .chain(creates)
+            .chain(moves)
+            .chain(settling)
+            .collect();
 
-        for value in cfg_array {
-            let we = WatchElement::from_json_value(value, &mut self.inotify);
+        if entries.is_empty() {
+            return None;
+        }
+
+        let path = std::env::temp_dir()
+            .join(format!("rincron-mini-{}.pending", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = std::fs::remove_file(&path);
 
-            match we {
-                Err(e) => println!("Error during parsing: {}", e),
-                Ok(v) => self.manager.add_element(v),
+        match Journal::open(&path).and_then(|mut j| j.append(&entries)) {
+            Ok(()) => {
+                log!(
+                    info,
+                    path = &path,
+                    "{} pending files passed in {}",
+                    entries.len(),
+                    &path
+                );
+                Some(path)
+            }
+            Err(e) => {
+                log!(
+                    warning,
+                    path = &path,
+                    "Warning: unable to save pending work to {}: {}",
+                    &path,
+                    e
+                );
+                None
             }
         }
-        Ok(())
     }
 
-    /// Hook vars to system signals
-    pub fn hook_signals(&mut self) {
-        // SIGINT managment
-        let hook =
-            signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&self.sigterm));
-        if hook.is_err() {
-            println!("WARNING! Unable to catch SIGINT signal. Program will continue running but might not exit properly");
-        }
+    /// Converts a pending file to an entry of the pending work file
+    ///
+    /// # Parameters
+    ///
+    /// * `queue`: The queue the file is in
+    /// * `file`: The file
+    /// * `remaining`: The remaining time to wait in milliseconds, for queues with one
+    fn pending_value(queue: &str, file: &FileCheck, remaining: i64) -> Value {
+        let mut value = file.to_journal_value();
+        value["queue"] = Value::from(queue);
+        value["event"] = Value::from(file.event.as_str());
+        value["moved_from"] = Value::from(file.moved_from.as_str());
+        value["cookie"] = Value::from(file.cookie);
+        value["remaining"] = Value::from(remaining);
+        value
+    }
 
-        // SIGTERM managment
-        let hook =
-            signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&self.sigterm));
-        if hook.is_err() {
-            println!("WARNING! Unable to catch SIGTERM signal. Program will continue running but might not exit properly");
-        }
+    /// Queues again the pending work of the process before a re-exec, once configs are read
+    fn adopt_pending(&mut self) {
+        let path = match std::env::var(Self::PENDING_VAR) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        std::env::remove_var(Self::PENDING_VAR);
 
-        // SIGTERM managment
-        let hook =
-            signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&self.reload));
-        if hook.is_err() {
-            println!("WARNING! Unable to catch SIGUSR1 signal. Program will continue running but you may not be able to reload configs");
+        let entries = match Journal::open(&path).and_then(|mut j| j.read_new()) {
+            Ok(v) => v,
+            Err(e) => {
+                log!(
+                    warning,
+                    path = &path,
+                    "Warning: unable to read pending work from {}: {}",
+                    &path,
+                    e
+                );
+                return;
+            }
+        };
+
+        if let Err(e) = std::fs::remove_file(&path) {
+            log!(
+                warning,
+                path = &path,
+                "Warning: unable to remove {}: {}",
+                &path,
+                e
+            );
         }
-    }
 
-    /// Check if children have exited
-    pub fn watch_children(&mut self) {
-        // We watch spawned childs to report exit status
-        let mut finished_children = Vec::new();
-        for (index, child) in self.child_processes.iter_mut().enumerate() {
-            match child.try_wait() {
-                Err(e) => {
-                    println!("Error while checking child {}: {}", child.id(), e);
-                    finished_children.push(index);
+        for entry in &entries {
+            let queue = entry
+                .get("queue")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default();
+            let remaining = entry
+                .get("remaining")
+                .and_then(|v| v.as_i64())
+                .unwrap_or_default();
+
+            // Executions are queued as they were, other files go through their element again
+            let fc = match queue {
+                "executions" => self.journal_entry(entry),
+                _ => self.pending_entry(entry),
+            };
+
+            let mut fc = match fc {
+                Some(v) => v,
+                None => {
+                    log!(warning, "Warning: pending work entry ignored: {}", entry);
+                    continue;
                 }
-                Ok(Some(v)) => {
-                    println!("Child {} exited with {}", child.id(), v);
-                    finished_children.push(index);
+            };
+
+            match queue {
+                "executions" => {
+                    fc.journaled = false;
+                    self.file_executions.push(fc);
                 }
-                _ => { /* Not exited*/ }
+                "creates" => {
+                    self.pending_creates
+                        .insert(fc.path.clone(), (remaining, fc));
+                }
+                "moves" if fc.cookie.is_some() => {
+                    self.pending_moves
+                        .insert(fc.cookie.unwrap(), (remaining, fc));
+                }
+                "settling" => {
+                    let path = PathBuf::from(entry["path"].as_str().unwrap_or_default());
+                    fc.completion = Completion::Immediate;
+                    self.settling_dirs.insert(path, (remaining, fc));
+                }
+                _ => Self::enqueue(&mut self.file_checks, &mut self.file_executions, fc),
             }
         }
 
-        // We need indexes in reverse order to not remove wrong children
-        finished_children.sort();
-        finished_children.reverse();
-
-        // Time to remove finished children, now that the var is free from borrows
-        for i in finished_children {
-            self.child_processes.remove(i);
-        }
+        log!("{} pending files adopted", entries.len());
     }
 
-    /// Read all events from inotify
+    /// Creates the file check of a pending work entry, `None` if its element is gone
+    ///
+    /// The completion check starts over, changes made meanwhile are seen by the new check
     ///
     /// # Parameters
     ///
-    /// * `buffer`: A buffer to write events
-    pub fn watch_events(&mut self, buffer: &mut [u8]) {
-        // Read inotify events buffer
-        let events = self.inotify.read_events(buffer);
+    /// * `entry`: The pending work entry
+    fn pending_entry(&self, entry: &Value) -> Option<FileCheck> {
+        let path = Path::new(entry.get("path")?.as_str()?);
+        let dir = entry.get("element")?.as_str()?;
+        let element = self.manager.elements().find(|e| e.path == dir)?;
+        path.strip_prefix(&element.path).ok()?;
 
-        if let Err(e) = events {
-            // We need to notify for any error not related to an empty buffer
-            if e.kind() != ErrorKind::WouldBlock {
-                println!("Error while reading events: {}", e);
-            }
+        let event = entry
+            .get("event")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let moved_from = entry
+            .get("moved_from")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let mut fc = Self::create_file_check(
+            element,
+            path.parent()?,
+            path.file_name()?,
+            WatchElement::event_name_to_mask(event),
+            moved_from,
+        )?;
 
-            std::thread::sleep(Duration::from_millis(self.watch_interval));
-            return;
+        fc.event = event.to_string();
+        fc.cookie = entry
+            .get("cookie")
+            .and_then(|v| v.as_u64())
+            .and_then(|v| u32::try_from(v).ok());
+        Some(fc)
+    }
+
+    /// Returns the path of the running binary
+    fn current_binary() -> PathBuf {
+        match std::env::current_exe() {
+            // Once the binary is replaced, the running one is marked as deleted
+            Ok(v) => match v.to_string_lossy().strip_suffix(" (deleted)") {
+                Some(path) => PathBuf::from(path),
+                None => v,
+            },
+            Err(_) => PathBuf::from(std::env::args_os().next().unwrap_or_default()),
         }
-        let events = events.unwrap();
+    }
 
-        // Events management
-        for event in events {
-            // We need more info for this descriptor
-            let event_config = self.manager.search_element(&event.wd);
+    /// Reads the files already processed from the processed files list, if any
+    ///
+    /// # Parameters
+    ///
+    /// * `options`: The command line options
+    fn load_processed_files(options: &Options) -> HashSet<String> {
+        let path = match &options.processed_files {
+            None => return HashSet::new(),
+            Some(v) => v,
+        };
 
-            // We do nothing if element not found
-            if event_config.is_none() {
-                continue;
+        match std::fs::read_to_string(path) {
+            Ok(v) => {
+                let files: HashSet<String> = v.lines().map(|l| l.to_string()).collect();
+                log!(
+                    info,
+                    path = &path,
+                    "{} processed files read from {}",
+                    files.len(),
+                    path
+                );
+                files
             }
-
-            let element = event_config.unwrap();
-            let file = event.name.unwrap_or_else(|| OsStr::new(""));
-            let escaped_path = shell_escape::escape((&element.path).into());
-            let escaped_file = shell_escape::escape(file.to_string_lossy());
-            let full_path = Path::new(&escaped_path.to_string()).join(&escaped_file.to_string());
-
-            println!("Event found for {} ({})", &escaped_path, &escaped_file);
-
-            // If the file does not match the desired string, we don't do anything
-            if !element.file_match.is_empty()
-                && !WildMatch::new(&element.file_match).matches(&escaped_file)
-            {
-                println!(
-                    "File {} does not match {}, event discarded",
-                    &escaped_file, &element.file_match
+            Err(e) if e.kind() == ErrorKind::NotFound => HashSet::new(),
+            Err(e) => {
+                log!(
+                    warning,
+                    path = &path,
+                    "Warning: unable to read processed files list {}: {}",
+                    path,
+                    e
                 );
-                continue;
+                HashSet::new()
             }
+        }
+    }
 
-            // Command line creation
-            let converted_cmd = element
-                .command
-                .replace("$@", &escaped_path)
-                .replace("$#", &escaped_file)
-                .replace("$$", "$");
+    /// Remembers a successfully processed file, returns `false` if it was already processed
+    ///
+    /// # Parameters
+    ///
+    /// * `processed_files`: The files already processed
+    /// * `options`: The command line options
+    /// * `path`: The file path
+    fn mark_processed(
+        processed_files: &mut HashSet<String>,
+        options: &Options,
+        path: &str,
+    ) -> bool {
+        if !processed_files.insert(path.to_string()) {
+            return false;
+        }
 
-            // File information creation
-            let fc = FileCheck::new(
-                &full_path.to_string_lossy(),
-                element.check_interval * 1000,
-                &converted_cmd,
-            );
+        if let Some(list) = &options.processed_files {
+            let write = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(list)
+                .and_then(|mut f| writeln!(f, "{}", path));
 
-            // If a size check is needed, we put it in file checks instead of file executions
-            if element.check_interval == 0 {
-                self.file_executions.push(fc);
-            } else {
-                self.file_checks.push(fc);
+            if let Err(e) = write {
+                log!(
+                    warning,
+                    "Warning: unable to write processed files list {}: {}",
+                    list,
+                    e
+                );
             }
         }
+
+        true
     }
 
-    /// Substract elapsed time for all files checkers
-    pub fn file_watch_tick(&mut self) {
-        for file in &mut self.file_checks {
-            file.tick(self.watch_interval as i64);
+    /// Remembers a file of a `once_per_file` element once its command succeeded
+    ///
+    /// # Parameters
+    ///
+    /// * `processed_files`: The files already processed
+    /// * `options`: The command line options
+    /// * `file`: The successful file
+    fn mark_success(processed_files: &mut HashSet<String>, options: &Options, file: &FileCheck) {
+        if file.once_per_file {
+            let path = file.full_path().to_string_lossy().to_string();
+            Self::mark_processed(processed_files, options, &path);
         }
     }
 
-    /// Watch all file sizes
-    pub fn file_watch(&mut self) {
-        let mut finished_files = Vec::new();
+    /// Forgets all processed files
+    fn clear_processed_files(&mut self) {
+        log!("{} processed files forgotten", self.processed_files.len());
+        self.processed_files.clear();
 
-        for (index, file) in &mut self.file_checks.iter_mut().enumerate() {
-            // If file did not change, the upload/copy is considered finished
-            if !file.has_changed() {
-                println!("File {} is now ready for execution", &file.path);
-                self.file_executions.push(file.clone());
-                finished_files.push(index);
+        if let Some(list) = &self.options.processed_files {
+            if let Err(e) = std::fs::write(list, "") {
+                log!(
+                    warning,
+                    "Warning: unable to clear processed files list {}: {}",
+                    list,
+                    e
+                );
             }
         }
+    }
 
-        // We delete finished file checks
-        finished_files.sort();
-        finished_files.reverse();
+    /// Returns the config directory for the current user
+    fn get_config_root() -> String {
+        let home_path = dirs::home_dir();
 
-        for i in finished_files {
-            self.file_checks.remove(i);
+        if home_path.is_none() {
+            return "/etc".to_string();
+        }
+        let home_pathbuf = home_path.unwrap();
+        let home_path = home_pathbuf.to_string_lossy();
+
+        if home_path == "/root" {
+            return "/etc".to_string();
         }
+
+        format!("{}/.config", home_path)
     }
 
-    /// Executes files
-    pub fn file_execute(&mut self) {
-        for file in &self.file_executions {
-            println!("CMD({}) => {}", &file.path, &file.cmd);
-
-            let cmd = Command::new("bash")
-                .arg("-c")
-                .arg(&file.cmd)
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .stdin(Stdio::null())
-                .spawn();
-
-            match cmd {
-                Err(e) => {
-                    println!("Unable to launch command: {}", e);
-                }
-                Ok(v) => {
-                    println!("Child {} spawned", v.id());
-                    self.child_processes.push(v);
-                }
-            };
+    /// Reads all config files, of the config root or of each profile
+    pub fn read_configs(&mut self) {
+        // Configs give all elements again, lost ones included
+        self.lost_elements.clear();
+        self.config_errors = 0;
+        self.manager.begin_transaction();
+        let previous_tasks = std::mem::take(&mut self.periodic_tasks);
+
+        if self.options.profiles.is_empty() {
+            self.read_default_configs();
+        } else {
+            for (name, path) in self.options.profiles.clone() {
+                self.read_profile(&name, &path);
+            }
         }
 
-        self.file_executions = Vec::new();
+        self.end_reload(previous_tasks);
     }
 
-    /// Executes the main loop
-    pub fn execute(&mut self) {
-        let mut buffer = [0; 1024];
+    /// Reads a single config file again, elements and periodic tasks of other config files are
+    /// left untouched
+    ///
+    /// The file must be one a full reload reads, a removed file removes its elements
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: The config file path
+    pub fn reload_config(&mut self, path: &str) {
+        let canonical = Path::new(path).canonicalize().ok();
 
-        self.read_configs();
-        self.hook_signals();
+        // Elements know their config file by the path it was found with
+        let known = self
+            .config_sources()
+            .into_iter()
+            .find(|(p, _)| canonical.is_some() && p.canonicalize().ok() == canonical)
+            .map(|(p, profile)| (p.to_string_lossy().to_string(), profile))
+            .or_else(|| {
+                self.manager
+                    .elements()
+                    .find(|e| e.source == path)
+                    .map(|e| (e.source.clone(), e.profile.clone()))
+            });
 
-        loop {
-            // Exit requested
-            if self.sigterm.load(std::sync::atomic::Ordering::Relaxed) {
-                println!("Exiting rincron, thanks for using it");
-                break;
+        let (source, profile) = match known {
+            Some(v) => v,
+            None => {
+                log!(
+                    warning,
+                    path = &path,
+                    "{} is not a config file of rincron-mini, not reloaded",
+                    path
+                );
+                return;
             }
+        };
 
-            // Reload requested
-            if self.reload.load(std::sync::atomic::Ordering::Relaxed) {
-                println!("Reloading rincron");
-                self.reload
-                    .store(false, std::sync::atomic::Ordering::Relaxed);
+        log!("Reloading config file {}", &source);
+        self.lost_elements.retain(|e| e.source != source);
+        self.manager.begin_source_transaction(&source);
 
+        let (previous_tasks, tasks) = std::mem::take(&mut self.periodic_tasks)
+            .into_iter()
+            .partition(|t| t.source == source);
+        self.periodic_tasks = tasks;
+
+        if Path::new(&source).exists() {
+            if let Err(e) = self.read_config(&source, &profile) {
+                log!(error, "Error while reading config file {}: {}", &source, e);
+            }
+        }
+
+        self.end_reload(previous_tasks);
+    }
+
+    /// Returns the config files a full reload reads, with their profile
+    fn config_sources(&self) -> Vec<(PathBuf, String)> {
+        let mut sources = Vec::new();
+
+        if self.options.profiles.is_empty() {
+            for extension in Self::CONFIG_EXTENSIONS {
+                let config_file = format!("{}/rincron-mini.{}", &self.config_root, extension);
+                sources.push((PathBuf::from(config_file), String::new()));
+            }
+
+            let config_dir = format!("{}/rincron-mini", &self.config_root);
+            for file in Self::config_files(&config_dir).unwrap_or_default() {
+                sources.push((file, String::new()));
+            }
+        }
+
+        for (name, path) in &self.options.profiles {
+            if !Path::new(path).is_dir() {
+                sources.push((PathBuf::from(path), name.clone()));
+                continue;
+            }
+
+            for file in Self::config_files(path).unwrap_or_default() {
+                sources.push((file, name.clone()));
+            }
+        }
+
+        sources
+    }
+
+    /// Returns the config files listed in the reload file, which is removed, `None` to reload
+    /// all config files
+    fn reload_requests(&self) -> Option<Vec<String>> {
+        let reload_file = self.options.reload_file.as_ref()?;
+        let content = std::fs::read_to_string(reload_file).ok()?;
+
+        // The same list must not be applied on the next signal
+        if let Err(e) = std::fs::remove_file(reload_file) {
+            log!(
+                warning,
+                "Warning: unable to remove reload file {}: {}",
+                reload_file,
+                e
+            );
+        }
+
+        let files: Vec<String> = content
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect();
+
+        (!files.is_empty()).then_some(files)
+    }
+
+    /// Applies the config read since the start of a reload transaction
+    ///
+    /// # Parameters
+    ///
+    /// * `previous_tasks`: The periodic tasks from before the reload, to keep their schedule
+    fn end_reload(&mut self, previous_tasks: Vec<PeriodicTask>) {
+        // Setup commands already ran in the process before a re-exec
+        let register = !self.options.no_exec
+            && !self.options.normalize_config
+            && !self.options.check_config
+            && !self.skip_initial_scans;
+        let added_elements = self.manager.end_transaction(&mut self.inotify, register);
+
+        // Unchanged periodic tasks keep their schedule across reloads
+        for task in &mut self.periodic_tasks {
+            if let Some(previous) = previous_tasks.iter().find(|t| t.same_as(task)) {
+                task.next_run = previous.next_run;
+            }
+        }
+
+        // The pending work of the process before a re-exec is ours now
+        self.adopt_pending();
+
+        // Before a re-exec, the previous process already scanned watched directories
+        if std::mem::take(&mut self.skip_initial_scans) {
+            return;
+        }
+
+        for element in added_elements.iter().filter(|e| e.initial_scan) {
+            self.initial_scan(element);
+        }
+    }
+
+    /// Reads the config files of the config root
+    ///
+    /// Config files are found in /etc/rincron-mini directory
+    /// If you don't want a folder, you can use /etc/rincron-mini.json (or .yaml, .yml, .toml)
+    fn read_default_configs(&mut self) {
+        // First we check the main config files
+        for extension in Self::CONFIG_EXTENSIONS {
+            let config_file = format!("{}/rincron-mini.{}", &self.config_root, extension);
+
+            // Other formats are optional, only the usual file is logged when missing
+            if extension == "json" {
+                log!("Checking config file {}", &config_file);
+            }
+
+            if Path::new(&config_file).exists() {
+                if let Err(e) = self.read_config(&config_file, "") {
+                    log!(
+                        error,
+                        "Error while reading config file {}: {}",
+                        &config_file,
+                        e
+                    );
+                    self.config_errors += 1;
+                }
+            }
+        }
+
+        let config_dir = format!("{}/rincron-mini", &self.config_root);
+        log!(
+            "Scanning config files {}/*.{{json,yaml,yml,toml}}",
+            &config_dir
+        );
+
+        // After that, we check the folder for more config files
+        match Self::config_files(&config_dir) {
+            Ok(files) => {
+                for p in files {
+                    log!("Config file found: {}", p.display());
+                    if let Err(e) = self.read_config(&p.to_string_lossy(), "") {
+                        log!(
+                            error,
+                            "Error while reading config file {}: {}",
+                            p.display(),
+                            e
+                        );
+                        self.config_errors += 1;
+                    }
+                }
+            }
+            Err(e) => {
+                log!(error, "Error while scanning config files: {}", e);
+                self.config_errors += 1;
+            }
+        }
+    }
+
+    /// Returns the config files of a directory, in any format, sorted by path
+    ///
+    /// # Parameters
+    ///
+    /// * `dir`: The config directory
+    fn config_files(dir: &str) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let mut files = Vec::new();
+
+        for extension in Self::CONFIG_EXTENSIONS {
+            for entry in glob(&format!("{}/*.{}", dir, extension))? {
+                match entry {
+                    Ok(p) => files.push(p),
+                    Err(e) => log!(error, "Error while scanning config files: {}", e),
+                }
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+
+    /// Reads the config file, or every config file of the directory, of a profile
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The profile name
+    /// * `path`: The config file or directory
+    fn read_profile(&mut self, name: &str, path: &str) {
+        log!(info, path = &path, "Loading profile {} from {}", name, path);
+
+        if !Path::new(path).is_dir() {
+            if let Err(e) = self.read_config(path, name) {
+                log!(
+                    error,
+                    path = &path,
+                    "Error while reading config file {}: {}",
+                    path,
+                    e
+                );
+                self.config_errors += 1;
+            }
+
+            return;
+        }
+
+        let files = match Self::config_files(path) {
+            Ok(v) => v,
+            Err(e) => {
+                log!(
+                    error,
+                    "Error while scanning config files of profile {}: {}",
+                    name,
+                    e
+                );
+                self.config_errors += 1;
+                return;
+            }
+        };
+
+        for file in files {
+            log!("Config file found: {}", file.display());
+            if let Err(e) = self.read_config(&file.to_string_lossy(), name) {
+                log!(
+                    error,
+                    "Error while reading config file {}: {}",
+                    file.display(),
+                    e
+                );
+                self.config_errors += 1;
+            }
+        }
+    }
+
+    /// Processes files already present in a newly watched directory
+    ///
+    /// # Parameters
+    ///
+    /// * `element`: The new element
+    fn initial_scan(&mut self, element: &WatchElement) {
+        log!(
+            info,
+            path = &element.path,
+            "Scanning existing files in {}",
+            &element.path
+        );
+
+        // Subdirectories triggering once only do so on events
+        let recursive = element.recursive && !element.trigger_once_per_dir;
+
+        let mut files = Vec::new();
+        if let Err(e) = self.scan_directory(Path::new(&element.path), recursive, &mut files) {
+            log!(
+                error,
+                path = &element.path,
+                "Error while scanning {}: {}",
+                &element.path,
+                e
+            );
+            return;
+        }
+
+        match element.scan_order {
+            ScanOrder::Filesystem => {}
+            ScanOrder::OldestFirst => files.sort_by_key(|(_, _, mtime)| *mtime),
+            ScanOrder::NewestFirst => files.sort_by_key(|(_, _, mtime)| std::cmp::Reverse(*mtime)),
+        }
+
+        for (directory, file, _) in files {
+            if !Self::file_matches(element, &directory, &file) {
+                continue;
+            }
+
+            if let Some(fc) =
+                Self::create_file_check(element, &directory, &file, EventMask::empty(), "")
+            {
+                // A directory target gives the same check for all its files
+                if element.action_target == ActionTarget::Dir
+                    && self
+                        .file_checks
+                        .iter()
+                        .chain(self.file_executions.iter())
+                        .any(|c| c.path == fc.path)
+                {
+                    continue;
+                }
+
+                log!(info, path = &fc.path, "Existing file {} queued", &fc.path);
+                Self::enqueue(&mut self.file_checks, &mut self.file_executions, fc);
+            }
+        }
+    }
+
+    /// Lists the files of a directory with their directory and their modification time for
+    /// sorting
+    ///
+    /// Subdirectories watched by another element are left to it
+    ///
+    /// # Parameters
+    ///
+    /// * `directory`: The directory to list
+    /// * `recursive`: Whether files of subdirectories are listed too
+    /// * `files`: The list where files are added
+    fn scan_directory(
+        &self,
+        directory: &Path,
+        recursive: bool,
+        files: &mut Vec<(PathBuf, OsString, SystemTime)>,
+    ) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(directory)?.filter_map(|e| e.ok()) {
+            // Only files are processed, symlinks are not followed
+            let metadata = match entry.metadata() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+
+            if recursive && metadata.is_dir() {
+                let path = entry.path();
+
+                if self.manager.elements().any(|e| Path::new(&e.path) == path) {
+                    continue;
+                }
+
+                if let Err(e) = self.scan_directory(&path, true, files) {
+                    log!(
+                        error,
+                        path = path.display(),
+                        "Error while scanning {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            } else if metadata.is_file() {
+                files.push((
+                    directory.to_path_buf(),
+                    entry.file_name(),
+                    metadata.modified().unwrap_or(UNIX_EPOCH),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Converts a TOML config to the array of a JSON config
+    ///
+    /// TOML has no top-level array, elements are `[[watch]]` tables
+    ///
+    /// # Parameters
+    ///
+    /// * `content`: The config file content
+    fn toml_elements(content: &str) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut table: serde_json::Map<String, Value> = match toml::from_str(content) {
+            Ok(v) => v,
+            Err(e) => bail!("Error while deserializing TOML: {}", e.to_string()),
+        };
+
+        let elements = table
+            .remove("watch")
+            .unwrap_or_else(|| Value::Array(Vec::new()));
+
+        if let Some(key) = table.keys().next() {
+            bail!(
+                "Unknown TOML key {}, elements must be [[watch]] tables",
+                key
+            );
+        }
+
+        if !elements.is_array() {
+            bail!("\"watch\" must be an array of tables, written [[watch]]");
+        }
+
+        Ok(elements)
+    }
+
+    /// Reads a config file
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: The config file path
+    /// * `profile`: The profile of the config file, empty without profiles
+    pub fn read_config(
+        &mut self,
+        path: &str,
+        profile: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Check if config file exists
+        let cfg_file = Path::new(path);
+
+        if !cfg_file.exists() {
+            bail!("The config file {} doesn't exist", path);
+        }
+
+        // Read config file
+        let cfg_string = std::fs::read_to_string(cfg_file);
+
+        if let Err(e) = cfg_string {
+            bail!("Error while reading config file: {}", e.to_string());
+        }
+
+        // Deserialize JSON, or another format into the same structure
+        let cfg_string = cfg_string.unwrap();
+        let extension = cfg_file.extension().and_then(|e| e.to_str()).unwrap_or("");
+
+        let cfg_json: Value = match extension {
+            "yaml" | "yml" => match serde_yaml::from_str(&cfg_string) {
+                Ok(v) => v,
+                Err(e) => bail!("Error while deserializing YAML: {}", e.to_string()),
+            },
+            "toml" => Self::toml_elements(&cfg_string)?,
+            _ => match serde_json::from_str(&cfg_string) {
+                Ok(v) => v,
+                Err(e) => bail!("Error while deserializing JSON: {}", e.to_string()),
+            },
+        };
+
+        // Read all dirs
+        if !cfg_json.is_array() {
+            bail!("Config must be an array");
+        }
+
+        let cfg_array = cfg_json.as_array().unwrap();
+
+        // Relative paths are resolved against the config file directory by default
+        let base_dir = match &self.options.base_dir {
+            Some(v) => PathBuf::from(v),
+            None => cfg_file
+                .canonicalize()
+                .ok()
+                .and_then(|p| p.parent().map(|p| p.to_path_buf()))
+                .unwrap_or_else(|| PathBuf::from("/")),
+        };
+
+        let context = ParseContext {
+            source: path.to_string(),
+            base_dir,
+            strict: self.options.strict,
+            profile: profile.to_string(),
+        };
+
+        for value in cfg_array {
+            // Periodic tasks are not watches
+            if let Some(periodic) = value.get("periodic") {
+                match PeriodicTask::from_json_value(periodic, context.strict) {
+                    Err(e) => {
+                        log!(error, "Error during parsing: {}", e);
+                        self.config_errors += 1;
+                    }
+                    Ok(mut v) => {
+                        log!("Periodic task added: {}", &v.command);
+                        v.source = path.to_string();
+                        self.periodic_tasks.push(v);
+                    }
+                }
+
+                continue;
+            }
+
+            // A manifest gives one element for each directory of its files
+            let values = match WatchElement::expand_manifest(value, &context) {
+                Ok(v) => v,
+                Err(e) => {
+                    log!(error, "Error during parsing: {}", e);
+                    self.config_errors += 1;
+                    continue;
+                }
+            };
+
+            for value in &values {
+                let we = WatchElement::from_json_value(value, &mut self.inotify, &context);
+
+                match we {
+                    Err(e) => {
+                        log!(error, "Error during parsing: {}", e);
+                        self.config_errors += 1;
+                    }
+                    Ok(v) => {
+                        if self.overlaps(&v) {
+                            self.config_errors += 1;
+                            continue;
+                        }
+
+                        self.manager.add_element(v)
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Warns about the elements already loaded whose watch overlaps the one of a new element
+    ///
+    /// Returns `true` if the new element must be rejected
+    ///
+    /// # Parameters
+    ///
+    /// * `element`: The new element
+    fn overlaps(&mut self, element: &WatchElement) -> bool {
+        let overlapping = self.manager.overlapping_elements(element);
+
+        for other in &overlapping {
+            log!(
+                warning,
+                path = &element.path,
+                "Warning: watch of {} ({}) overlaps watch of {} ({})",
+                &element.path,
+                &element.source,
+                &other.path,
+                &other.source
+            );
+        }
+
+        if overlapping.is_empty() || !self.options.reject_overlaps {
+            return false;
+        }
+
+        log!(
+            error,
+            path = &element.path,
+            "Element {} ({}) rejected",
+            &element.path,
+            &element.source
+        );
+
+        // Parsing already added the watch, unless inotify gave the one of the same directory
+        if !overlapping
+            .iter()
+            .any(|e| e.watch_descriptor == element.watch_descriptor)
+        {
+            let _ = self
+                .inotify
+                .watches()
+                .remove(element.watch_descriptor.clone());
+        }
+
+        true
+    }
+
+    /// Hook vars to system signals
+    pub fn hook_signals(&mut self) {
+        // SIGINT managment
+        let hook =
+            signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&self.sigterm));
+        if hook.is_err() {
+            log!(warning, "WARNING! Unable to catch SIGINT signal. Program will continue running but might not exit properly");
+        }
+
+        // SIGTERM managment
+        let hook =
+            signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&self.sigterm));
+        if hook.is_err() {
+            log!(warning, "WARNING! Unable to catch SIGTERM signal. Program will continue running but might not exit properly");
+        }
+
+        // SIGTERM managment
+        let hook =
+            signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&self.reload));
+        if hook.is_err() {
+            log!(warning, "WARNING! Unable to catch SIGUSR1 signal. Program will continue running but you may not be able to reload configs");
+        }
+
+        // SIGHUP managment
+        let hook =
+            signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&self.drain));
+        if hook.is_err() {
+            log!(warning, "WARNING! Unable to catch SIGHUP signal. Program will continue running but you may not be able to drain before reloading configs");
+        }
+
+        // SIGTTOU and SIGTTIN managment
+        let hook = signal_hook::flag::register(
+            signal_hook::consts::SIGTTOU,
+            Arc::clone(&self.throttle_down),
+        )
+        .and_then(|_| {
+            signal_hook::flag::register(signal_hook::consts::SIGTTIN, Arc::clone(&self.throttle_up))
+        });
+        if hook.is_err() {
+            log!(warning, "WARNING! Unable to catch SIGTTOU or SIGTTIN signal. Program will continue running but you may not be able to change the maximum number of children");
+        }
+
+        // SIGUSR2 managment
+        if self.options.reexec_on_usr2 {
+            let hook = signal_hook::flag::register(
+                signal_hook::consts::SIGUSR2,
+                Arc::clone(&self.upgrade),
+            );
+            if hook.is_err() {
+                log!(warning, "WARNING! Unable to catch SIGUSR2 signal. Program will continue running but you may not be able to re-execute it");
+            }
+        }
+    }
+
+    /// Switches to another user
+    ///
+    /// Watches must be established before since the new user may not be able to read watched
+    /// directories anymore
+    ///
+    /// # Parameters
+    ///
+    /// * `user`: The user name
+    pub fn drop_privileges(&self, user: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let c_user = CString::new(user)?;
+
+        // SAFETY: getpwnam returns null or a pointer to a static struct, only read right after
+        let passwd = unsafe { libc::getpwnam(c_user.as_ptr()) };
+
+        if passwd.is_null() {
+            bail!("User {} not found", user);
+        }
+
+        // SAFETY: checked as non null just above
+        let (uid, gid, name) = unsafe {
+            (
+                (*passwd).pw_uid,
+                (*passwd).pw_gid,
+                CStr::from_ptr((*passwd).pw_name).to_owned(),
+            )
+        };
+
+        // After a re-exec, privileges are already dropped
+        // SAFETY: plain syscall
+        if unsafe { libc::getuid() } == uid {
+            log!("Already running as {}", user);
+            return Ok(());
+        }
+
+        // Groups must be changed before the user, we won't be allowed after
+        // SAFETY: all arguments are valid values
+        if unsafe { libc::initgroups(name.as_ptr(), gid) } != 0 {
+            bail!("Unable to set groups: {}", std::io::Error::last_os_error());
+        }
+
+        // SAFETY: plain syscall
+        if unsafe { libc::setgid(gid) } != 0 {
+            bail!("Unable to set group: {}", std::io::Error::last_os_error());
+        }
+
+        // SAFETY: plain syscall
+        if unsafe { libc::setuid(uid) } != 0 {
+            bail!("Unable to set user: {}", std::io::Error::last_os_error());
+        }
+
+        log!(
+            "Privileges dropped, now running as {} ({}:{})",
+            user,
+            uid,
+            gid
+        );
+        Ok(())
+    }
+
+    /// Check if children have exited
+    pub fn watch_children(&mut self) {
+        // We watch spawned childs to report exit status
+        let mut finished_children = Vec::new();
+        let mut hooks = Vec::new();
+        let mut chained = Vec::new();
+        let mut retried = Vec::new();
+
+        self.check_removed_cwds();
+
+        // Commands started before a re-exec are only reaped
+        self.orphans.retain(|&pid| {
+            let mut status = 0;
+
+            // SAFETY: plain call on one of our children
+            match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+                0 => true,
+                r if r == pid => {
+                    let status = ExitStatus::from_raw(status);
+                    log!("Child {} exited with {}", pid, status);
+                    audit::exited(None, pid as u32, status);
+                    false
+                }
+                _ => false,
+            }
+        });
+
+        for (index, process) in self.child_processes.iter_mut().enumerate() {
+            match process.child.try_wait() {
+                Err(e) => {
+                    log!(
+                        error,
+                        "Error while checking child {}: {}",
+                        process.child.id(),
+                        e
+                    );
+                    self.metrics.increment(
+                        Counter::Failures,
+                        Self::profile_of(&process.file),
+                        Self::source_of(&process.file),
+                    );
+                    finished_children.push(index);
+                }
+                Ok(Some(v)) => {
+                    // The child is finished once nothing can write to its outputs anymore
+                    if !process.outputs_drained() {
+                        continue;
+                    }
+
+                    let lines = process.join_readers();
+                    log!("Child {} exited with {}", process.child.id(), v);
+                    audit::exited(process.audit_id.as_deref(), process.child.id(), v);
+
+                    if !v.success() {
+                        self.metrics.increment(
+                            Counter::Failures,
+                            Self::profile_of(&process.file),
+                            Self::source_of(&process.file),
+                        );
+                    }
+
+                    // Transient failures are executed again instead of going to the failure path
+                    if let Some(file) = &process.file {
+                        if !process.expired && Self::should_retry(file, v) {
+                            retried.push(file.clone());
+                            finished_children.push(index);
+                            continue;
+                        }
+                    }
+
+                    // A file failing again and again is moved away to stop the loop
+                    if let Some(file) = &process.file {
+                        if v.success() {
+                            Self::clear_failures(&mut self.failure_counts, file);
+                            Self::mark_success(&mut self.processed_files, &self.options, file);
+                        } else {
+                            let reason = format!("command exited with {}", v);
+                            Self::record_failure(
+                                &mut self.failure_counts,
+                                &mut self.metrics,
+                                file,
+                                &reason,
+                            );
+                        }
+                    }
+
+                    // Files produced by a successful command can be processed in turn
+                    if let Some(file) = process.file.as_ref().filter(|f| f.chain_output) {
+                        if v.success() {
+                            chained.push((file.clone(), lines));
+                        }
+                    }
+
+                    if let Some(file) = &process.file {
+                        if let Some((hook, capture_output)) = Self::exit_hook(file, v) {
+                            hooks.push((hook, capture_output, file.clone()));
+                        }
+                    }
+
+                    finished_children.push(index);
+                }
+                Ok(None) => {
+                    // The whole handling of a file is bounded, command included
+                    let expired = process.file.as_ref().is_some_and(|f| f.lifecycle_expired());
+
+                    if expired && !process.expired {
+                        Self::lifecycle_timeout_reached(process.file.as_ref().unwrap(), "command");
+                        process.expired = true;
+
+                        if let Err(e) = process.child.kill() {
+                            log!(error, "Unable to kill child {}: {}", process.child.id(), e);
+                        }
+                    }
+
+                    Self::enforce_timeout(process);
+                }
+            }
+        }
+
+        // We need indexes in reverse order to not remove wrong children
+        finished_children.sort();
+        finished_children.reverse();
+
+        // Time to remove finished children, now that the var is free from borrows
+        for i in finished_children {
+            self.child_processes.remove(i);
+        }
+
+        // Hooks are not attached to a file, they can't trigger hooks themselves
+        for (hook, capture_output, file) in hooks {
+            log!("HOOK => {}", &hook);
+            self.spawn(&hook, capture_output, None, Some(&file));
+        }
+
+        for (file, lines) in chained {
+            self.chain_output(&file, &lines);
+        }
+
+        self.watch_callbacks(&mut retried);
+
+        for mut file in retried {
+            file.attempt += 1;
+            file.retry_at = Instant::now().checked_add(Duration::from_millis(file.retry_delay));
+            file.journaled = false;
+            self.file_executions.push(file);
+        }
+    }
+
+    /// Handles finished callbacks like exited children, a failed callback exiting with 1
+    ///
+    /// Output chaining needs an output, it's not done for callbacks
+    ///
+    /// # Parameters
+    ///
+    /// * `retried`: The files to execute again
+    fn watch_callbacks(&mut self, retried: &mut Vec<FileCheck>) {
+        let finished = match &mut self.workers {
+            Some(v) => v.finished(),
+            None => return,
+        };
+
+        for (file, success) in finished {
+            let status = ExitStatus::from_raw(if success { 0 } else { 1 << 8 });
+            log!(
+                info,
+                path = &file.path,
+                "Callback of {} exited with {}",
+                &file.path,
+                status
+            );
+
+            if success {
+                Self::clear_failures(&mut self.failure_counts, &file);
+                Self::mark_success(&mut self.processed_files, &self.options, &file);
+            } else {
+                self.metrics
+                    .increment(Counter::Failures, &file.profile, &file.source);
+
+                if Self::should_retry(&file, status) {
+                    retried.push(file);
+                    continue;
+                }
+
+                Self::record_failure(
+                    &mut self.failure_counts,
+                    &mut self.metrics,
+                    &file,
+                    "callback failed",
+                );
+            }
+
+            if let Some((hook, capture_output)) = Self::exit_hook(&file, status) {
+                log!("HOOK => {}", &hook);
+                self.spawn(&hook, capture_output, None, Some(&file));
+            }
+        }
+    }
+
+    /// Returns the exit code of a command, a child killed by a signal having no exit code, we
+    /// use the shell convention
+    ///
+    /// # Parameters
+    ///
+    /// * `status`: The command exit status
+    fn exit_code(status: ExitStatus) -> i32 {
+        status
+            .code()
+            .or_else(|| status.signal().map(|s| 128 + s))
+            .unwrap_or(-1)
+    }
+
+    /// Checks if a failed command must be executed again
+    ///
+    /// # Parameters
+    ///
+    /// * `file`: The file the command was executed for
+    /// * `status`: The command exit status
+    fn should_retry(file: &FileCheck, status: ExitStatus) -> bool {
+        if status.success() || file.attempt >= file.retries {
+            return false;
+        }
+
+        let code = Self::exit_code(status);
+
+        if !file.retry_on_codes.is_empty() && !file.retry_on_codes.contains(&code) {
+            log!(
+                warning,
+                path = &file.path,
+                "Command of {} exited with code {}, not retried",
+                &file.path,
+                code
+            );
+            return false;
+        }
+
+        log!(
+            info,
+            path = &file.path,
+            "Command of {} exited with code {}, retry {} of {} in {} seconds",
+            &file.path,
+            code,
+            file.attempt + 1,
+            file.retries,
+            file.retry_delay as f64 / 1000.0
+        );
+        true
+    }
+
+    /// Counts a failure of a file, then moves it to the quarantine if it failed too many times
+    ///
+    /// Returns `true` if the file was quarantined
+    ///
+    /// # Parameters
+    ///
+    /// * `failure_counts`: The number of failures of each file
+    /// * `metrics`: The activity metrics, counting quarantined files
+    /// * `file`: The failing file
+    /// * `reason`: Why the file failed
+    fn record_failure(
+        failure_counts: &mut HashMap<String, u32>,
+        metrics: &mut Metrics,
+        file: &FileCheck,
+        reason: &str,
+    ) -> bool {
+        let quarantine = match &file.quarantine {
+            None => return false,
+            Some(v) => v,
+        };
+
+        let full_path = file.full_path();
+        let key = full_path.to_string_lossy().to_string();
+        let count = failure_counts.entry(key.clone()).or_default();
+        *count += 1;
+
+        if *count < file.max_failures {
+            log!(
+                warning,
+                path = &key,
+                "File {} failed {} times out of {} before quarantine",
+                &key,
+                count,
+                file.max_failures
+            );
+            return false;
+        }
+
+        let count = failure_counts.remove(&key).unwrap_or_default();
+
+        // Relative names keep their subdirectories, a directory target has no name but its own
+        let name = match file.file.is_empty() {
+            false => Some(Path::new(&file.file)),
+            true => full_path.file_name().map(Path::new),
+        };
+
+        let destination = match name {
+            Some(v) if !v.as_os_str().is_empty() => Path::new(quarantine).join(v),
+            _ => {
+                log!(
+                    error,
+                    path = &key,
+                    "Error: unable to quarantine {}: no name to give it",
+                    &key
+                );
+                return false;
+            }
+        };
+
+        let parent = destination.parent().unwrap_or(Path::new(quarantine));
+        let moved = std::fs::create_dir_all(parent).and_then(|_| {
+            // A rename can't cross filesystems, the file is copied then
+            std::fs::rename(&full_path, &destination).or_else(|_| {
+                std::fs::copy(&full_path, &destination)?;
+                std::fs::remove_file(&full_path)
+            })
+        });
+
+        if let Err(e) = moved {
+            log!(
+                error,
+                path = &key,
+                "Error: unable to quarantine {}: {}",
+                &key,
+                e
+            );
+            return false;
+        }
+
+        // The reason is kept next to the file for whoever looks at the quarantine
+        let sidecar = format!("{}.reason", destination.display());
+        let content = format!(
+            "file: {}\nreason: {}\nfailures: {}\ntime: {}\n",
+            &key,
+            reason,
+            count,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        );
+
+        if let Err(e) = std::fs::write(&sidecar, content) {
+            log!(warning, "Warning: unable to write {}: {}", &sidecar, e);
+        }
+
+        log!(
+            warning,
+            path = &key,
+            "File {} quarantined in {} after {} failures",
+            &key,
+            quarantine,
+            count
+        );
+        metrics.increment(Counter::Quarantines, &file.profile, &file.source);
+        true
+    }
+
+    /// Forgets the failures of a file once it succeeded
+    ///
+    /// # Parameters
+    ///
+    /// * `failure_counts`: The number of failures of each file
+    /// * `file`: The successful file
+    fn clear_failures(failure_counts: &mut HashMap<String, u32>, file: &FileCheck) {
+        if file.quarantine.is_some() {
+            failure_counts.remove(file.full_path().to_string_lossy().as_ref());
+        }
+    }
+
+    /// Terminates a command running for longer than its timeout, then kills it if it's still
+    /// running after the grace period
+    ///
+    /// # Parameters
+    ///
+    /// * `process`: The running child
+    fn enforce_timeout(process: &mut ChildProcess) {
+        let (timeout, kill_grace) = match &process.file {
+            Some(f) if f.timeout > 0 => (f.timeout, f.kill_grace),
+            _ => return,
+        };
+        let id = process.child.id();
+
+        match process.terminated_at {
+            None if process.started_at.elapsed() >= Duration::from_millis(timeout) => {
+                log!(
+                    warning,
+                    "Child {} still running after {} seconds, terminated",
+                    id,
+                    timeout as f64 / 1000.0
+                );
+                // SAFETY: the child is not reaped yet, its pid can't be reused
+                unsafe { libc::kill(id as libc::pid_t, libc::SIGTERM) };
+                process.terminated_at = Some(Instant::now());
+            }
+            Some(t) if !process.killed && t.elapsed() >= Duration::from_millis(kill_grace) => {
+                log!(
+                    warning,
+                    "Child {} still running {} seconds after being terminated, killed",
+                    id,
+                    kill_grace as f64 / 1000.0
+                );
+                if let Err(e) = process.child.kill() {
+                    log!(error, "Unable to kill child {}: {}", id, e);
+                }
+                process.killed = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Logs a file aborted for exceeding its lifecycle timeout
+    ///
+    /// # Parameters
+    ///
+    /// * `file`: The aborted file
+    /// * `stage`: Where the file was when aborted
+    fn lifecycle_timeout_reached(file: &FileCheck, stage: &str) {
+        log!(
+            error,
+            path = &file.path,
+            "ALERT: file {} not handled within {} seconds, aborted during {}",
+            &file.path,
+            file.lifecycle_timeout as f64 / 1000.0,
+            stage
+        );
+    }
+
+    /// Queues files written by a command on its standard output, like if an event happened
+    ///
+    /// Each line must be the absolute path of an existing file, other lines are ignored
+    ///
+    /// # Parameters
+    ///
+    /// * `parent`: The file the command was executed for
+    /// * `lines`: The command output lines
+    fn chain_output(&mut self, parent: &FileCheck, lines: &[String]) {
+        let parent_path = Path::new(&parent.dir).join(&parent.file);
+
+        for line in lines {
+            let path = Path::new(line.trim());
+
+            if !path.is_absolute() || !path.is_file() || path == parent_path {
+                continue;
+            }
+
+            // Chains are bounded, a command writing its own input would loop forever
+            if parent.chain_depth >= parent.max_chain_depth {
+                log!(
+                    warning,
+                    path = path.display(),
+                    "Warning: chain depth {} reached, {} not processed",
+                    parent.max_chain_depth,
+                    path.display()
+                );
+                continue;
+            }
+
+            let mut fc = match self.element_file_check(path) {
+                Some(v) => v,
+                None => {
+                    log!(
+                        warning,
+                        path = path.display(),
+                        "No element with a command matches chained file {}",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+
+            fc.event = "CHAIN".to_string();
+            fc.chain_depth = parent.chain_depth + 1;
+            fc.max_chain_depth = parent.max_chain_depth;
+
+            log!(info, path = &fc.path, "Chained file {} queued", &fc.path);
+            self.file_executions.push(fc);
+        }
+    }
+
+    /// Creates the file check of a file using the first element watching its directory, directly
+    /// or recursively, and matching its name
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: The file path
+    fn element_file_check(&self, path: &Path) -> Option<FileCheck> {
+        let (directory, file) = (path.parent()?, path.file_name()?);
+
+        self.manager
+            .elements()
+            .find(|e| {
+                (Path::new(&e.path) == directory || (e.recursive && directory.starts_with(&e.path)))
+                    && Self::file_matches(e, directory, file)
+            })
+            .and_then(|e| Self::create_file_check(e, directory, file, EventMask::empty(), ""))
+    }
+
+    /// Returns the hook command to execute once a command exited, if any
+    ///
+    /// # Parameters
+    ///
+    /// * `file`: The file the command was executed for
+    /// * `status`: The command exit status
+    fn exit_hook(file: &FileCheck, status: ExitStatus) -> Option<(String, bool)> {
+        let template = if status.success() {
+            &file.on_success
+        } else {
+            &file.on_failure
+        };
+
+        if template.is_empty() {
+            return None;
+        }
+
+        let code = Self::exit_code(status).to_string();
+        let hook = Self::convert_command(
+            template,
+            &[
+                ('@', &file.dir),
+                ('#', &file.file),
+                ('&', &file.event),
+                ('<', &file.moved_from),
+                ('>', file.moved_to()),
+                ('?', &code),
+                ('*', &file.cmd),
+            ],
+            SubstitutionMode::Shell,
+        );
+
+        Some((file.expand_dates(&hook), file.capture_output))
+    }
+
+    /// Runs one iteration of the main loop, without signal handling
+    pub fn tick(&mut self) {
+        for index in 0..self.options.phases.len() {
+            self.run_phase(self.options.phases[index]);
+        }
+
+        self.journal_sync();
+
+        #[cfg(feature = "statsd")]
+        self.metrics.send_statsd(
+            self.options.statsd_interval,
+            self.child_processes.len(),
+            self.file_checks.len(),
+        );
+    }
+
+    /// Opens the journal, pending executions it contains are queued
+    fn open_journal(&mut self) {
+        let path = match &self.options.journal {
+            None => return,
+            Some(v) => v,
+        };
+
+        match Journal::open(path) {
+            Ok(v) => self.journal = Some(v),
+            Err(e) => {
+                log!(
+                    error,
+                    path = &path,
+                    "Error: unable to open journal {}: {}",
+                    path,
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+
+        self.journal_sync();
+        log!(
+            "{} pending executions read from the journal",
+            self.file_executions.len()
+        );
+    }
+
+    /// Queues executions added to the journal by other tools, appends new pending executions
+    /// and compacts the journal once some are done
+    fn journal_sync(&mut self) {
+        let entries = match self.journal.as_mut().map(|j| j.read_new()) {
+            None => return,
+            Some(Ok(v)) => v,
+            Some(Err(e)) => {
+                log!(warning, "Warning: unable to read journal: {}", e);
+                Vec::new()
+            }
+        };
+
+        for entry in entries {
+            match self.journal_entry(&entry) {
+                Some(fc) => {
+                    log!(
+                        info,
+                        path = &fc.path,
+                        "Journaled execution of {} queued",
+                        &fc.path
+                    );
+                    self.file_executions.push(fc);
+                }
+                None => log!(warning, "Warning: journal entry ignored: {}", entry),
+            }
+        }
+
+        // Periodic tasks are not pending work, they'll run again anyway
+        let new_entries: Vec<Value> = self
+            .file_executions
+            .iter_mut()
+            .filter(|f| !f.journaled && f.source != "periodic")
+            .map(|f| {
+                f.journaled = true;
+                f.to_journal_value()
+            })
+            .collect();
+
+        let journal = self.journal.as_mut().unwrap();
+
+        if !new_entries.is_empty() {
+            if let Err(e) = journal.append(&new_entries) {
+                log!(warning, "Warning: unable to write journal: {}", e);
+            }
+        }
+
+        // Done executions are only removed by rewriting the journal
+        let pending: Vec<Value> = self
+            .file_executions
+            .iter()
+            .filter(|f| f.journaled)
+            .map(|f| f.to_journal_value())
+            .collect();
+
+        if journal.lines > pending.len() {
+            if let Err(e) = journal.rewrite(&pending) {
+                log!(warning, "Warning: unable to compact journal: {}", e);
+            }
+        }
+    }
+
+    /// Creates the execution of a journal entry, `None` if the entry is invalid
+    ///
+    /// # Parameters
+    ///
+    /// * `entry`: The journal entry
+    fn journal_entry(&self, entry: &Value) -> Option<FileCheck> {
+        let path = Path::new(entry.get("path")?.as_str()?);
+
+        // The element gives its options, but the entry may give the command
+        let element = entry
+            .get("element")
+            .and_then(|v| v.as_str())
+            .and_then(|dir| {
+                self.manager
+                    .elements()
+                    .find(|e| !dir.is_empty() && e.path == dir)
+            });
+
+        let mut fc = match element {
+            Some(e) => {
+                path.strip_prefix(&e.path).ok()?;
+                Self::create_file_check(
+                    e,
+                    path.parent()?,
+                    path.file_name()?,
+                    EventMask::empty(),
+                    "",
+                )
+                .unwrap_or_else(|| FileCheck::new(&path.to_string_lossy(), 0, ""))
+            }
+            None => FileCheck::new(&path.to_string_lossy(), 0, ""),
+        };
+
+        if let Some(argv) = entry.get("argv").and_then(|v| v.as_array()) {
+            fc.argv = argv
+                .iter()
+                .map(|a| a.as_str().map(|s| s.to_string()))
+                .collect::<Option<Vec<String>>>()
+                .filter(|a| !a.is_empty())?;
+            fc.cmd = fc.argv.join(" ");
+        } else if let Some(command) = entry.get("command").and_then(|v| v.as_str()) {
+            fc.argv.clear();
+            fc.cmd = command.to_string();
+        }
+
+        if fc.cmd.is_empty() {
+            return None;
+        }
+
+        fc.completion = Completion::Immediate;
+        fc.journaled = true;
+
+        if let Some(enqueued) = entry.get("enqueued").and_then(|v| v.as_u64()) {
+            fc.enqueued_at = enqueued;
+        }
+
+        Some(fc)
+    }
+
+    /// Runs a step of the main loop
+    ///
+    /// # Parameters
+    ///
+    /// * `phase`: The step to run
+    pub fn run_phase(&mut self, phase: Phase) {
+        match phase {
+            Phase::Children => self.watch_children(),
+            Phase::Ticks => self.file_watch_tick(),
+            Phase::Events => self.watch_events(&mut [0; 1024]),
+            Phase::Checks => self.file_watch(),
+            Phase::Periodic => self.periodic_tick(),
+            Phase::Executions => self.file_execute(),
+        }
+    }
+
+    /// Read all events from inotify
+    ///
+    /// # Parameters
+    ///
+    /// * `buffer`: A buffer to write events
+    pub fn watch_events(&mut self, buffer: &mut [u8]) {
+        // Read inotify events buffer, a signal interrupting the read is not an error
+        let events = loop {
+            match self.inotify.read_events(buffer) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                v => break v,
+            }
+        };
+
+        if let Err(e) = events {
+            // We need to notify for any error not related to an empty buffer
+            if e.kind() != ErrorKind::WouldBlock {
+                log!(error, "Error while reading events: {}", e);
+            }
+
+            // Right after events, more are likely to come, so we don't wait too long
+            self.empty_reads += 1;
+            let interval = if self.empty_reads <= self.options.burst_reads {
+                self.options.burst_interval
+            } else {
+                self.watch_interval
+            };
+
+            std::thread::sleep(Duration::from_millis(interval));
+            return;
+        }
+        let events = events.unwrap();
+        self.empty_reads = 0;
+
+        // Events management
+        for event in events {
+            // Events on the watched directory itself have no file name
+            match event.name {
+                Some(file) => self.process_event(&event.wd, event.mask, event.cookie, file),
+                None => self.process_self_event(&event.wd, event.mask, event.cookie),
+            }
+            self.oneshot_fired(&event.wd);
+        }
+    }
+
+    /// Processes an inotify event on a watched directory itself, like its deletion or move
+    ///
+    /// Only `$@` is meaningful, so the element `self_command` is executed instead of its command.
+    /// A watched file has no `self_command` by default, its command is executed with an empty `$#`
+    ///
+    /// # Parameters
+    ///
+    /// * `wd`: The watch descriptor of the event
+    /// * `mask`: The event mask
+    /// * `cookie`: The event cookie, 0 if none
+    fn process_self_event(&mut self, wd: &WatchDescriptor, mask: EventMask, cookie: u32) {
+        // Subdirectories of recursive elements only matter to their parent directory events
+        if self.manager.is_subdirectory(wd) {
+            if mask.contains(EventMask::IGNORED) {
+                if let Some(path) = self.manager.forget_subdirectory(wd) {
+                    log!(
+                        info,
+                        path = path.display(),
+                        "Subdirectory {} not watched anymore",
+                        path.display()
+                    );
+                }
+            }
+            return;
+        }
+
+        let element = match self.manager.search_element(wd) {
+            None => return,
+            Some(v) => v,
+        };
+
+        // The kernel removing a watch after a deletion is not an event by itself
+        if mask.contains(EventMask::IGNORED) {
+            log!(
+                warning,
+                path = &element.path,
+                "Watch of {} removed by the kernel, waiting for the path to exist again",
+                &element.path
+            );
+            let element = self.manager.remove_element(wd).unwrap();
+            self.lost_elements.push(element);
+            return;
+        }
+
+        // Events of a watched file are its only events, they go through the usual pipeline
+        if element.self_command.is_none() && !Path::new(&element.path).is_dir() {
+            self.process_event(wd, mask, cookie, OsStr::new(""));
+            return;
+        }
+
+        self.last_activity = Instant::now();
+        self.metrics
+            .increment(Counter::Events, &element.profile, &element.source);
+
+        log!(
+            info,
+            path = &element.path,
+            "Event {} found for {} itself",
+            WatchElement::event_value_to_name(mask),
+            &element.path
+        );
+
+        let command = match &element.self_command {
+            None => {
+                log!(
+                    info,
+                    path = &element.path,
+                    "No self_command for {}, event discarded",
+                    &element.path
+                );
+                return;
+            }
+            Some(v) => v,
+        };
+
+        let values = [('@', element.path.as_str())];
+        let mut fc = FileCheck::new(
+            &element.path,
+            0,
+            &Self::convert_command(command, &values, SubstitutionMode::Shell),
+        );
+        fc.completion = Completion::Immediate;
+        fc.capture_output = element.capture_output;
+        fc.profile = element.profile.clone();
+        fc.source = element.source.clone();
+        fc.env = element.env.clone();
+        fc.clean_env = element.clean_env;
+        fc.shell = element.shell.clone();
+        self.file_executions.push(fc);
+    }
+
+    /// Forgets or adds again a oneshot watch, the kernel removes it after its first event
+    ///
+    /// # Parameters
+    ///
+    /// * `wd`: The watch descriptor of the event
+    fn oneshot_fired(&mut self, wd: &WatchDescriptor) {
+        let oneshot = self
+            .manager
+            .search_element(wd)
+            .is_some_and(|e| e.mask.contains(WatchMask::ONESHOT));
+
+        if !oneshot {
+            return;
+        }
+
+        let mut element = self.manager.remove_element(wd).unwrap();
+
+        if !element.rearm {
+            log!(
+                info,
+                path = &element.path,
+                "Oneshot watch of {} fired, removed",
+                &element.path
+            );
+            return;
+        }
+
+        match self
+            .inotify
+            .watches()
+            .add(&element.path, element.watch_mask())
+        {
+            Err(e) => log!(
+                error,
+                path = &element.path,
+                "Error: unable to rearm watch of {}: {}",
+                &element.path,
+                e
+            ),
+            Ok(v) => {
+                log!(
+                    info,
+                    path = &element.path,
+                    "Oneshot watch of {} fired, rearmed",
+                    &element.path
+                );
+                element.watch_descriptor = v;
+                self.manager.insert_element(element);
+            }
+        }
+    }
+
+    /// Processes an inotify event
+    ///
+    /// # Parameters
+    ///
+    /// * `wd`: The watch descriptor of the event
+    /// * `mask`: The event mask
+    /// * `cookie`: The event cookie, 0 if none
+    /// * `file`: The file name, empty for events on the watched path itself
+    fn process_event(&mut self, wd: &WatchDescriptor, mask: EventMask, cookie: u32, file: &OsStr) {
+        self.last_activity = Instant::now();
+
+        // The event may come from a subdirectory of a recursive element
+        let (root, directory) = match self.manager.locate(wd) {
+            None => return,
+            Some(v) => v,
+        };
+
+        if mask.contains(EventMask::ISDIR) {
+            self.follow_subdirectory(&root, &directory.join(file), mask);
+        }
+
+        // We need more info for this descriptor
+        let event_config = self.manager.search_element(wd);
+
+        // We do nothing if element not found
+        if event_config.is_none() {
+            return;
+        }
+
+        let element = event_config.unwrap();
+
+        // Recursive watches get directory events the element may not want, unless a directory
+        // may settle
+        let settling = element.trigger_once_per_dir && mask.contains(EventMask::ISDIR);
+        if !element.wants(mask) && !settling {
+            return;
+        }
+
+        let escaped_path = shell_escape::escape(directory.to_string_lossy());
+        let escaped_file = shell_escape::escape(file.to_string_lossy());
+        let event_name = WatchElement::event_value_to_name(mask);
+
+        // While draining, only events on files already being checked are processed, others wait
+        // for the new config
+        if self.draining_since.is_some() {
+            let full_path = Path::new(&escaped_path.to_string())
+                .join(escaped_file.to_string())
+                .to_string_lossy()
+                .to_string();
+
+            if !self.file_checks.iter().any(|c| c.path == full_path)
+                && !self.pending_creates.contains_key(&full_path)
+            {
+                log!(
+                    "Event {} on {} ({}) deferred until the reload",
+                    &event_name,
+                    &escaped_path,
+                    &escaped_file
+                );
+                self.deferred_events
+                    .push((directory.clone(), mask, cookie, file.to_os_string()));
+                return;
+            }
+        }
+
+        self.metrics
+            .increment(Counter::Events, &element.profile, &element.source);
+
+        log!(
+            "Event {} found for {} ({})",
+            &event_name,
+            &escaped_path,
+            &escaped_file
+        );
+
+        // Events in a subdirectory only delay the single execution for it
+        if element.trigger_once_per_dir {
+            let root = Path::new(&element.path);
+            let name = match directory
+                .strip_prefix(root)
+                .ok()
+                .and_then(|p| p.iter().next())
+            {
+                Some(v) => Some(v.to_os_string()),
+                None if mask.contains(EventMask::ISDIR) => Some(file.to_os_string()),
+                None => None,
+            };
+
+            if let Some(name) = name {
+                let path = root.join(&name);
+
+                // A subdirectory moved out or deleted has nothing left to process
+                if directory == root && mask.intersects(EventMask::MOVED_FROM | EventMask::DELETE) {
+                    if self.settling_dirs.remove(&path).is_some() {
+                        log!(
+                            info,
+                            path = path.display(),
+                            "Directory {} gone, execution canceled",
+                            path.display()
+                        );
+                    }
+                    return;
+                }
+
+                if let Some((remaining, _)) = self.settling_dirs.get_mut(&path) {
+                    *remaining = element.settle_window as i64;
+                    return;
+                }
+
+                if !Self::file_matches(element, root, &name) {
+                    return;
+                }
+
+                if let Some(mut fc) = Self::create_file_check(element, root, &name, mask, "") {
+                    log!(
+                        info,
+                        path = path.display(),
+                        "Directory {} settling, waiting {}s without event",
+                        path.display(),
+                        element.settle_window as f64 / 1000.0
+                    );
+                    fc.event = event_name;
+                    fc.completion = Completion::Immediate;
+                    self.settling_dirs
+                        .insert(path, (element.settle_window as i64, fc));
+                }
+                return;
+            }
+        }
+
+        // Some elements only care about directories
+        if element.dir_events_only && !mask.contains(EventMask::ISDIR) {
+            log!("{} is not a directory, event discarded", &escaped_file);
+            return;
+        }
+
+        // If the file does not match the desired string, we don't do anything
+        if !Self::file_matches(element, &directory, file) {
+            return;
+        }
+
+        // Telling symlinks apart needs a lstat, the link may already be gone
+        if element.symlinks != SymlinkPolicy::Process {
+            let is_symlink = match std::fs::symlink_metadata(directory.join(file)) {
+                Ok(v) => v.file_type().is_symlink(),
+                Err(e) => {
+                    log!(
+                        error,
+                        "Unable to check if {} is a symlink, event discarded: {}",
+                        &escaped_file,
+                        e
+                    );
+                    return;
+                }
+            };
+
+            if is_symlink != (element.symlinks == SymlinkPolicy::Only) {
+                log!("{} symlink policy not met, event discarded", &escaped_file);
+                return;
+            }
+        }
+
+        // Previous attributes must be known before the ATTRIB event to tell what changed
+        let attributes = match element.track_attributes {
+            true => Self::track_attributes(&mut self.attribute_cache, &directory.join(file), mask),
+            false => None,
+        };
+
+        // Beyond the element rate, events are dropped to protect what's downstream
+        if element.max_rate > 0.0 {
+            let bucket = self
+                .rate_buckets
+                .entry(element.path.clone())
+                .or_insert_with(|| TokenBucket::new(element.max_rate, element.max_burst));
+
+            // A reload may change the limits
+            if bucket.rate != element.max_rate || bucket.burst != element.max_burst {
+                *bucket = TokenBucket::new(element.max_rate, element.max_burst);
+            }
+
+            if !bucket.take() {
+                log!(
+                    warning,
+                    path = &element.path,
+                    "Rate of {} exceeded, event on {} shed",
+                    &element.path,
+                    &escaped_file
+                );
+                self.metrics
+                    .increment(Counter::Shed, &element.profile, &element.source);
+                return;
+            }
+        }
+
+        if self.options.emit_events || element.publish.is_some() {
+            let message = serde_json::json!({
+                "path": &element.path,
+                "file": file.to_string_lossy(),
+                "event": &event_name,
+                "time": SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+            })
+            .to_string();
+
+            // Events are the only thing written on the standard output in this mode
+            if self.options.emit_events {
+                println!("{}", &message);
+            }
+
+            #[cfg(feature = "nats")]
+            if let Some(publish) = &element.publish {
+                self.publishers
+                    .entry(publish.server.clone())
+                    .or_insert_with(|| Publisher::new(&publish.server))
+                    .publish(&publish.subject, &message);
+            }
+        }
+
+        // The same inode can be seen through several hardlinks, only the events of the first
+        // path are kept, the next events on this path are legit (CLOSE_WRITE after CREATE...)
+        if element.dedup_inode_window > 0 {
+            let full_path = directory.join(file);
+
+            if let Ok(metadata) = std::fs::metadata(&full_path) {
+                let inode = (metadata.dev(), metadata.ino());
+
+                if self
+                    .recent_inodes
+                    .get(&inode)
+                    .is_some_and(|(seen, expiration)| {
+                        *seen != full_path && *expiration > Instant::now()
+                    })
+                {
+                    return;
+                }
+
+                self.recent_inodes.insert(
+                    inode,
+                    (
+                        full_path,
+                        Instant::now() + Duration::from_millis(element.dedup_inode_window),
+                    ),
+                );
+            }
+        }
+
+        // The MOVED_TO of a paired rename gives the previous name to the command
+        let moved_from = match element.pair_moves && mask.contains(EventMask::MOVED_TO) {
+            true => self
+                .pending_moves
+                .remove(&cookie)
+                .map(|(_, from)| from.file),
+            false => None,
+        }
+        .unwrap_or_default();
+
+        // Elements without command only publish messages
+        let mut fc = match Self::create_file_check(element, &directory, file, mask, &moved_from) {
+            None => return,
+            Some(v) => v,
+        };
+
+        if !moved_from.is_empty() {
+            log!(
+                info,
+                path = &fc.path,
+                "Rename of {} to {} paired",
+                &moved_from,
+                &fc.path
+            );
+        }
+
+        fc.event = event_name;
+        fc.cookie = (cookie != 0).then_some(cookie);
+
+        if let Some((before, after)) = attributes {
+            fc.attributes_before = before;
+            fc.attributes_after = Some(after);
+        }
+
+        // Some commands must never be executed twice for the same file, it's remembered once its
+        // command succeeded
+        if element.once_per_file {
+            let path = fc.full_path().to_string_lossy().to_string();
+
+            if self.processed_files.contains(&path) {
+                log!(
+                    info,
+                    path = &path,
+                    "File {} already processed, event discarded",
+                    &path
+                );
+                return;
+            }
+        }
+
+        // A MOVED_FROM is kept aside, the MOVED_TO of the same rename may follow and replace it
+        if element.pair_moves && cookie != 0 && mask.contains(EventMask::MOVED_FROM) {
+            log!(
+                info,
+                path = &fc.path,
+                "File {} moved, waiting {}ms for its new name",
+                &fc.path,
+                element.pair_window
+            );
+            self.pending_moves.insert(cookie, (element.pair_window, fc));
+            return;
+        }
+
+        if element.coalesce_create_close {
+            // A CREATE is kept aside, a CLOSE_WRITE may follow and replace it
+            if mask.contains(EventMask::CREATE) {
+                log!(
+                    info,
+                    path = &fc.path,
+                    "File {} created, waiting {}ms for a CLOSE_WRITE",
+                    &fc.path,
+                    element.coalesce_window
+                );
+                self.pending_creates
+                    .insert(fc.path.clone(), (element.coalesce_window, fc));
+                return;
+            }
+
+            if mask.contains(EventMask::CLOSE_WRITE)
+                && self.pending_creates.remove(&fc.path).is_some()
+            {
+                log!(
+                    info,
+                    path = &fc.path,
+                    "CREATE event of {} coalesced with CLOSE_WRITE",
+                    &fc.path
+                );
+            }
+        }
+
+        // Attribute changes come with ATTRIB events, no need to wait for the next check
+        if let Completion::Xattr(..) = fc.completion {
+            if let Some(check) = self.file_checks.iter_mut().find(|c| c.path == fc.path) {
+                log!(
+                    info,
+                    path = &fc.path,
+                    "New event for {}, attribute checked right away",
+                    &fc.path
+                );
+                check.next_check = 0;
+                return;
+            }
+        }
+
+        Self::enqueue(&mut self.file_checks, &mut self.file_executions, fc);
+    }
+
+    /// Watches a subdirectory created or moved in a recursive element, forgets one moved out
+    ///
+    /// # Parameters
+    ///
+    /// * `root`: The watch descriptor of the element
+    /// * `path`: The subdirectory path
+    /// * `mask`: The event mask
+    fn follow_subdirectory(&mut self, root: &WatchDescriptor, path: &Path, mask: EventMask) {
+        let element = match self.manager.search_element(root) {
+            Some(e) if e.recursive => e.clone(),
+            _ => return,
+        };
+
+        if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+            self.manager
+                .watch_subdirectory(&mut self.inotify, root, path, &element);
+        } else if mask.contains(EventMask::MOVED_FROM) {
+            self.manager
+                .unwatch_subdirectories(&mut self.inotify, |_, p| p.starts_with(path));
+        }
+    }
+
+    /// Updates the attributes cache with an event, returns the previous and current attributes
+    /// of the file for an ATTRIB event
+    ///
+    /// # Parameters
+    ///
+    /// * `cache`: The attributes cache
+    /// * `path`: The file path
+    /// * `mask`: The event mask
+    fn track_attributes(
+        cache: &mut HashMap<PathBuf, FileAttributes>,
+        path: &Path,
+        mask: EventMask,
+    ) -> Option<(Option<FileAttributes>, FileAttributes)> {
+        // A gone file has no attributes to remember
+        let current = match FileAttributes::from_path(path) {
+            Some(v) if !mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) => v,
+            _ => {
+                cache.remove(path);
+                return None;
+            }
+        };
+
+        let previous = cache.insert(path.to_path_buf(), current);
+
+        if !mask.contains(EventMask::ATTRIB) {
+            return None;
+        }
+
+        match &previous {
+            Some(v) => log!(
+                info,
+                path = path.display(),
+                "Attributes of {} changed: {}",
+                path.display(),
+                match v.changes(&current).as_str() {
+                    "" => "none",
+                    changes => changes,
+                }
+            ),
+            None => log!(
+                info,
+                path = path.display(),
+                "Previous attributes of {} unknown",
+                path.display()
+            ),
+        }
+
+        Some((previous, current))
+    }
+
+    /// Check if a file matches the element patterns
+    ///
+    /// # Parameters
+    ///
+    /// * `element`: The watch element
+    /// * `directory`: The directory containing the file
+    /// * `file`: The file name
+    fn file_matches(element: &WatchElement, directory: &Path, file: &OsStr) -> bool {
+        let escaped_file = shell_escape::escape(file.to_string_lossy());
+
+        if !element.files.is_empty() && !element.files.iter().any(|f| OsStr::new(f) == file) {
+            log!("File {} is not listed, event discarded", escaped_file);
+            return false;
+        }
+
+        if let Some(matcher) = &element.file_matcher {
+            let matched = match element.match_relative_path {
+                true => element
+                    .relative_path(directory, file)
+                    .to_string_lossy()
+                    .into_owned(),
+                false => file.to_string_lossy().into_owned(),
+            };
+
+            if !matcher.matches(&matched) {
+                let pattern = match element.file_match_regex.is_empty() {
+                    true => element.file_match.join(", "),
+                    false => element.file_match_regex.clone(),
+                };
+
+                log!(
+                    "File {} does not match {}, event discarded",
+                    shell_escape::escape(matched.into()),
+                    pattern
+                );
+                return false;
+            }
+        }
+
+        // Patterns are written for names as they are, escaping would quote `~`, `#` or spaces
+        let raw_file = file.to_string_lossy();
+
+        if let Some(pattern) = element.exclude.iter().find(|p| p.matches(&raw_file)) {
+            log!(
+                "File {} matches excluded {}, event discarded",
+                escaped_file,
+                pattern
+            );
+            return false;
+        }
+
+        true
+    }
+
+    /// Creates the file check of a file, `None` if the element has no command to execute
+    ///
+    /// # Parameters
+    ///
+    /// * `element`: The watch element
+    /// * `directory`: The directory the event happened in
+    /// * `file`: The file name
+    /// * `mask`: The event mask, empty for files processed without event
+    /// * `moved_from`: The previous name for a rename paired with its MOVED_FROM event, empty
+    ///   otherwise
+    fn create_file_check(
+        element: &WatchElement,
+        directory: &Path,
+        file: &OsStr,
+        mask: EventMask,
+        moved_from: &str,
+    ) -> Option<FileCheck> {
+        // `$#` is either the name or the path below the watched path
+        let file_name = match element.filename_mode {
+            FilenameMode::Basename => file.to_string_lossy().to_string(),
+            FilenameMode::Relative => element
+                .relative_path(directory, file)
+                .to_string_lossy()
+                .to_string(),
+        };
+
+        // `$@` is the directory `$#` is relative to, or the directory of the file for a directory
+        // target, `$#` being empty
+        let dir = match (element.action_target, element.filename_mode) {
+            (ActionTarget::File, FilenameMode::Relative) => element.path.clone(),
+            _ => directory.to_string_lossy().to_string(),
+        };
+
+        let file_name = match element.action_target {
+            ActionTarget::File => file_name,
+            ActionTarget::Dir => String::new(),
+        };
+
+        let escaped_path = shell_escape::escape(dir.as_str().into());
+        let escaped_file = shell_escape::escape(file.to_string_lossy());
+        let full_path = match file_name.is_empty() {
+            true => PathBuf::from(escaped_path.to_string()),
+            false => Path::new(&escaped_path.to_string())
+                .join(shell_escape::escape(file_name.as_str().into()).to_string()),
+        };
+
+        let event = WatchElement::event_value_to_name(mask);
+        let moved_to = match moved_from.is_empty() {
+            true => "",
+            false => file_name.as_str(),
+        };
+        let values = [
+            ('@', dir.as_str()),
+            ('#', file_name.as_str()),
+            ('&', event.as_str()),
+            ('<', moved_from),
+            ('>', moved_to),
+        ];
+
+        // The lossy name points to another file, commands can't be given the real one
+        if file.to_str().is_none() {
+            log!(
+                warning,
+                "Warning: file name {} is not valid UTF-8, commands receive it with replacement characters",
+                escaped_file
+            );
+        }
+
+        // The file extension can select a specific command
+        let by_ext = Path::new(file)
+            .extension()
+            .and_then(|ext| element.command_by_ext.get(ext.to_string_lossy().as_ref()));
+
+        // Command line creation, arguments are shown escaped to be readable in logs
+        let mut argv = Vec::new();
+        let converted_cmd = match by_ext.or_else(|| element.event_command(mask)) {
+            Some(command) => Self::convert_command(command, &values, SubstitutionMode::Shell),
+            None if !element.argv.is_empty() => {
+                argv = element
+                    .argv
+                    .iter()
+                    .map(|t| Self::convert_command(t, &values, SubstitutionMode::Argv))
+                    .collect();
+
+                argv.iter()
+                    .map(|t| shell_escape::escape(t.into()).to_string())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }
+            None if !element.command.is_empty() => {
+                Self::convert_command(&element.command, &values, SubstitutionMode::Shell)
+            }
+            None => return None,
+        };
+
+        // File information creation
+        let mut fc = FileCheck::new(
+            &full_path.to_string_lossy(),
+            element.check_interval,
+            &converted_cmd,
+        );
+        if !argv.is_empty() {
+            fc.argv = argv;
+            fc.argv_template = element.argv.clone();
+        }
+
+        if !element.verify.is_empty() {
+            fc.verify_cmd =
+                Self::convert_command(&element.verify, &values, SubstitutionMode::Shell);
+            fc.verify_failure = element.verify_failure;
+        }
+
+        if !element.route_command.is_empty() {
+            fc.route_cmd =
+                Self::convert_command(&element.route_command, &values, SubstitutionMode::Shell);
+            fc.route_default = element.route_default.clone();
+        }
+
+        // The log file is a path, not a command line
+        fc.log_file = Self::convert_command(&element.log_file, &values, SubstitutionMode::Argv);
+
+        fc.dir = dir;
+        fc.element = element.path.clone();
+        fc.action_target = element.action_target;
+        fc.file = file_name;
+        fc.moved_from = moved_from.to_string();
+        fc.on_success = element.on_success.clone();
+        fc.on_failure = element.on_failure.clone();
+        fc.on_truncate = element.on_truncate.clone();
+        fc.completion = element.completion.clone();
+        fc.pending_event = element.pending_event;
+        fc.hash_algo = element.hash_algo;
+        fc.require_closed = element.require_closed;
+        fc.capture_output = element.capture_output;
+        fc.min_free_space = element.min_free_space;
+        fc.free_space_path = element.free_space_path.clone();
+        fc.quarantine = element.quarantine.clone();
+        fc.profile = element.profile.clone();
+        fc.source = element.source.clone();
+        fc.env = element.env.clone();
+        fc.clean_env = element.clean_env;
+        fc.shell = element.shell.clone();
+        fc.max_failures = element.max_failures;
+        fc.once_per_file = element.once_per_file;
+        fc.retries = element.retries;
+        fc.retry_delay = element.retry_delay;
+        fc.retry_on_codes = element.retry_on_codes.clone();
+        fc.concurrency_group = element.concurrency_group.clone();
+        fc.group_limit = element.group_limit;
+        fc.lifecycle_timeout = element.lifecycle_timeout;
+        fc.timeout = element.timeout;
+        fc.kill_grace = element.kill_grace;
+        fc.chain_output = element.chain_output;
+        fc.max_chain_depth = element.max_chain_depth;
+        fc.on_shutdown = element.on_shutdown;
+        fc.date_timezone = element.date_timezone;
+        fc.date_source = element.date_source;
+
+        Some(fc)
+    }
+
+    /// Executes the command of a file like if an event had been triggered, then returns the
+    /// command exit code
+    ///
+    /// The first element watching the file directory and matching the file name is used
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: The file path
+    pub fn replay(&mut self, path: &str) -> i32 {
+        self.read_configs();
+
+        let fc = match self.element_file_check(Path::new(path)) {
+            Some(v) => v,
+            None => {
+                log!(
+                    error,
+                    path = &path,
+                    "Error: no element with a command matches {}",
+                    path
+                );
+                return 1;
+            }
+        };
+
+        let cmd = fc.expand_dates(&fc.cmd);
+        log!(info, path = &fc.path, "CMD({}) => {}", &fc.path, &cmd);
+
+        let mut command = Self::build_command(&cmd, Some(&fc), &fc.shell);
+        Self::set_env(&mut command, fc.clean_env, &fc.env);
+        Self::set_event_env(&mut command, &fc);
+
+        match Self::run_audited(&mut command, "replay", &fc.source, &fc.path, &cmd) {
+            Err(e) => {
+                log!(error, "Unable to launch command: {}", e);
+                1
+            }
+            Ok(v) => {
+                log!("Command exited with {}", v);
+                v.code()
+                    .or_else(|| v.signal().map(|s| 128 + s))
+                    .unwrap_or(1)
+            }
+        }
+    }
+
+    /// Writes files in a temporary watched directory as fast as possible, measures the time
+    /// each event takes to go through the main loop up to the execution queue, then returns 0
+    /// if every event was seen
+    ///
+    /// Commands are not executed, the measure is about rincron itself
+    ///
+    /// # Parameters
+    ///
+    /// * `count`: The number of files to write
+    pub fn stress(&mut self, count: usize) -> i32 {
+        let directory = std::env::temp_dir().join(format!("rincron-stress-{}", std::process::id()));
+
+        if let Err(e) = std::fs::create_dir_all(&directory) {
+            log!(
+                error,
+                "Error: unable to create {}: {}",
+                directory.display(),
+                e
+            );
+            return 1;
+        }
+
+        let value = serde_json::json!({
+            "path": directory.to_string_lossy(),
+            "events": ["CLOSE_WRITE"],
+            "command": "true",
+        });
+        let context = ParseContext {
+            source: "stress".to_string(),
+            base_dir: PathBuf::from("/"),
+            strict: true,
+            profile: String::new(),
+        };
+
+        match WatchElement::from_json_value(&value, &mut self.inotify, &context) {
+            Err(e) => {
+                log!(
+                    error,
+                    "Error: unable to watch {}: {}",
+                    directory.display(),
+                    e
+                );
+                let _ = std::fs::remove_dir_all(&directory);
+                return 1;
+            }
+            Ok(v) => {
+                self.manager.begin_transaction();
+                self.manager.add_element(v);
+                self.manager.end_transaction(&mut self.inotify, false);
+            }
+        }
+
+        log!("Writing {} files in {}", count, directory.display());
+
+        // Logs would be most of the measured time
+        logger::set_enabled(false);
+
+        let writer_directory = directory.clone();
+        let writer = std::thread::spawn(move || {
+            let mut sent = Vec::with_capacity(count);
+
+            for index in 0..count {
+                sent.push(Instant::now());
+                let _ = std::fs::write(writer_directory.join(format!("f{}", index)), b"stress");
+            }
+
+            sent
+        });
+
+        let mut received: Vec<Option<Instant>> = vec![None; count];
+        let mut remaining = count;
+        let mut last_progress = Instant::now();
+
+        // Executions are taken out of the queue instead of being executed
+        while remaining > 0 && last_progress.elapsed() < Duration::from_secs(5) {
+            for index in 0..self.options.phases.len() {
+                if self.options.phases[index] != Phase::Executions {
+                    self.run_phase(self.options.phases[index]);
+                }
+            }
+
+            let now = Instant::now();
+
+            for file in self.file_executions.drain(..) {
+                let index = file.file.strip_prefix('f').and_then(|v| v.parse().ok());
+
+                if let Some(slot) = index.and_then(|i: usize| received.get_mut(i)) {
+                    if slot.is_none() {
+                        *slot = Some(now);
+                        remaining -= 1;
+                        last_progress = now;
+                    }
+                }
+            }
+        }
+
+        let sent = writer.join().unwrap_or_default();
+        logger::set_enabled(true);
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let mut latencies: Vec<Duration> = sent
+            .iter()
+            .zip(&received)
+            .filter_map(|(s, r)| r.map(|r| r.duration_since(*s)))
+            .collect();
+        latencies.sort();
+
+        let seen = latencies.len();
+        let span = match (sent.first(), received.iter().flatten().max()) {
+            (Some(first), Some(last)) => last.duration_since(*first),
+            _ => Duration::ZERO,
+        };
+
+        log!(
+            "{} events seen out of {} in {:.3}s, {:.0} events per second",
+            seen,
+            count,
+            span.as_secs_f64(),
+            seen as f64 / span.as_secs_f64().max(f64::EPSILON)
+        );
+
+        if seen > 0 {
+            let percentile = |p: usize| latencies[(seen - 1) * p / 100].as_secs_f64() * 1000.0;
+            log!(
+                "Latency: p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+                percentile(50),
+                percentile(90),
+                percentile(99),
+                percentile(100)
+            );
+        }
+
+        if seen < count {
+            log!(
+                warning,
+                "Warning: {} events lost, the inotify queue may have overflowed",
+                count - seen
+            );
+            return 1;
+        }
+
+        0
+    }
+
+    /// Reads configs without starting the daemon, then returns 1 if a config file, an element or
+    /// a periodic task was rejected, 0 otherwise
+    pub fn check_config(&mut self) -> i32 {
+        self.read_configs();
+
+        log!(
+            "Config checked: {} elements and {} periodic tasks accepted, {} rejected",
+            self.manager.elements().count(),
+            self.periodic_tasks.len(),
+            self.config_errors
+        );
+
+        match self.config_errors {
+            0 => 0,
+            _ => 1,
+        }
+    }
+
+    /// Writes the loaded config in a canonical form on the standard output
+    ///
+    /// Keys are sorted, every option is explicit and paths are resolved, so two configs behaving
+    /// the same give the same output. Invalid elements are left out.
+    pub fn normalize_config(&mut self) {
+        self.read_configs();
+
+        let mut elements: Vec<&WatchElement> = self.manager.elements().collect();
+        elements.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let config: Vec<Value> = elements
+            .iter()
+            .map(|e| {
+                let mut value = e.to_json_value();
+
+                // Existing paths are written without symlinks nor `..`
+                for key in ["path", "free_space_path", "quarantine"] {
+                    let canonical = value[key]
+                        .as_str()
+                        .and_then(|p| std::fs::canonicalize(p).ok());
+
+                    if let Some(path) = canonical {
+                        value[key] = Value::from(path.to_string_lossy().as_ref());
+                    }
+                }
+
+                value
+            })
+            .chain(self.periodic_tasks.iter().map(|t| t.to_json_value()))
+            .collect();
+
+        match serde_json::to_string_pretty(&config) {
+            Ok(v) => println!("{}", v),
+            Err(e) => log!(error, "Error while writing config: {}", e),
+        }
+    }
+
+    /// Puts a file in the right queue
+    ///
+    /// A file already checked is still being written, its pending event policy tells what
+    /// happens to the current check, a quiet period being restarted like any other check
+    ///
+    /// # Parameters
+    ///
+    /// * `file_checks`: The files to check
+    /// * `file_executions`: The files to execute
+    /// * `fc`: The file to enqueue
+    fn enqueue(
+        file_checks: &mut Vec<FileCheck>,
+        file_executions: &mut Vec<FileCheck>,
+        fc: FileCheck,
+    ) {
+        if let Some(check) = file_checks.iter_mut().find(|c| c.path == fc.path) {
+            match fc.pending_event {
+                PendingEventPolicy::Reset => {
+                    log!(
+                        info,
+                        path = &fc.path,
+                        "New event for {}, check restarted",
+                        &fc.path
+                    );
+                    check.reset();
+                    return;
+                }
+                PendingEventPolicy::Keep => {
+                    log!(
+                        info,
+                        path = &fc.path,
+                        "New event for {}, current check kept",
+                        &fc.path
+                    );
+                    return;
+                }
+                PendingEventPolicy::Queue => {}
+            }
+        }
+
+        // If a check is needed, we put it in file checks instead of file executions
+        if fc.completion == Completion::Immediate {
+            file_executions.push(fc);
+        } else {
+            file_checks.push(fc);
+        }
+    }
+
+    /// Replaces wildcards in a command template
+    ///
+    /// Wildcards are replaced in a single pass, `$$` always gives a `$` and unknown wildcards are
+    /// kept as is. Values are escaped for a shell command, but inserted as is in an argument
+    /// since no shell will read it
+    ///
+    /// # Parameters
+    ///
+    /// * `template`: The command given by the user
+    /// * `values`: The character following `$` for each wildcard and its raw value
+    /// * `mode`: How the command is executed
+    fn convert_command(template: &str, values: &[(char, &str)], mode: SubstitutionMode) -> String {
+        let mut converted = String::with_capacity(template.len());
+        let mut chars = template.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                converted.push(c);
+                continue;
+            }
+
+            match chars.next() {
+                None => converted.push('$'),
+                Some('$') => converted.push('$'),
+                Some(n) => match values.iter().find(|(k, _)| *k == n) {
+                    Some((_, v)) if mode == SubstitutionMode::Shell => {
+                        converted.push_str(&Self::escape_value(v))
+                    }
+                    Some((_, v)) => converted.push_str(v),
+                    None => {
+                        converted.push('$');
+                        converted.push(n);
+                    }
+                },
+            }
+        }
+
+        converted
+    }
+
+    /// Escapes a value for a shell command
+    ///
+    /// The value is single quoted as soon as it contains a `$`, so an empty quote can be put in
+    /// `${` without changing what the shell reads. Placeholders expanded later, like dates and
+    /// routes, don't find anything in the value this way
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The raw value
+    fn escape_value(value: &str) -> String {
+        shell_escape::escape(value.into()).replace("${", "$''{")
+    }
+
+    /// Runs a command and waits for its exit status, recording it in the audit log
+    ///
+    /// # Parameters
+    ///
+    /// * `command`: The command to run
+    /// * `kind`: What the command is executed for
+    /// * `source`: The config file of the element
+    /// * `path`: The file or directory the command is executed for
+    /// * `cmd`: The command line
+    pub fn run_audited(
+        command: &mut Command,
+        kind: &str,
+        source: &str,
+        path: &str,
+        cmd: &str,
+    ) -> std::io::Result<ExitStatus> {
+        let mut child = match command.spawn() {
+            Ok(v) => v,
+            Err(e) => {
+                audit::spawn_failed(kind, source, path, cmd, &e.to_string());
+                return Err(e);
+            }
+        };
+
+        let id = audit::spawned(kind, source, path, cmd, child.id());
+        let status = child.wait()?;
+        audit::exited(id.as_deref(), child.id(), status);
+        Ok(status)
+    }
+
+    /// Runs a command like `run_audited`, reading its standard output, which must be piped
+    ///
+    /// Returns the exit status and the output
+    ///
+    /// # Parameters
+    ///
+    /// * `command`: The command to run
+    /// * `kind`: What the command is executed for
+    /// * `source`: The config file of the element
+    /// * `path`: The file or directory the command is executed for
+    /// * `cmd`: The command line
+    fn output_audited(
+        command: &mut Command,
+        kind: &str,
+        source: &str,
+        path: &str,
+        cmd: &str,
+    ) -> std::io::Result<(ExitStatus, String)> {
+        let mut child = match command.spawn() {
+            Ok(v) => v,
+            Err(e) => {
+                audit::spawn_failed(kind, source, path, cmd, &e.to_string());
+                return Err(e);
+            }
+        };
+
+        let id = audit::spawned(kind, source, path, cmd, child.id());
+
+        // The output is read before waiting, a full pipe would block the command forever
+        let mut output = String::new();
+        if let Some(mut stdout) = child.stdout.take() {
+            let _ = stdout.read_to_string(&mut output);
+        }
+
+        let status = child.wait()?;
+        audit::exited(id.as_deref(), child.id(), status);
+        Ok((status, output))
+    }
+
+    /// Runs the route command of a file and returns its output, the route default if it fails
+    /// or prints nothing, `None` if there's no default
+    ///
+    /// # Parameters
+    ///
+    /// * `file`: The file to route
+    fn route_file(file: &FileCheck) -> Option<String> {
+        let route_cmd = file.expand_dates(&file.route_cmd);
+        log!(
+            info,
+            path = &file.path,
+            "ROUTE({}) => {}",
+            &file.path,
+            &route_cmd
+        );
+
+        let mut command = Self::shell_command(&file.shell, &route_cmd);
+        command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .stdin(Stdio::null());
+        Self::set_env(&mut command, file.clean_env, &file.env);
+        Self::set_event_env(&mut command, file);
+
+        let output =
+            Self::output_audited(&mut command, "route", &file.source, &file.path, &route_cmd);
+
+        let route = match output {
+            Err(e) => {
+                log!(error, "Unable to launch route command: {}", e);
+                None
+            }
+            Ok((status, _)) if !status.success() => {
+                log!(
+                    info,
+                    path = &file.path,
+                    "Route command of {} exited with {}",
+                    &file.path,
+                    status
+                );
+                None
+            }
+            Ok((_, output)) => Some(output.trim().to_string()).filter(|o| !o.is_empty()),
+        };
+
+        match (route, &file.route_default) {
+            (Some(v), _) => {
+                log!(info, path = &file.path, "Route of {} is {}", &file.path, &v);
+                Some(v)
+            }
+            (None, Some(default)) => {
+                log!(
+                    info,
+                    path = &file.path,
+                    "No route for {}, default {} used",
+                    &file.path,
+                    default
+                );
+                Some(default.clone())
+            }
+            (None, None) => {
+                log!(
+                    error,
+                    path = &file.path,
+                    "No route for {}, execution failed",
+                    &file.path
+                );
+                None
+            }
+        }
+    }
+
+    /// Runs the verification command of a file and waits for its result
+    ///
+    /// # Parameters
+    ///
+    /// * `file`: The file to verify
+    fn verify_file(file: &FileCheck) -> bool {
+        let verify_cmd = file.expand_dates(&file.verify_cmd);
+        log!(
+            info,
+            path = &file.path,
+            "VERIFY({}) => {}",
+            &file.path,
+            &verify_cmd
+        );
+
+        let mut command = Self::shell_command(&file.shell, &verify_cmd);
+        command
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .stdin(Stdio::null());
+        Self::set_env(&mut command, file.clean_env, &file.env);
+        Self::set_event_env(&mut command, file);
+
+        let status = Self::run_audited(
+            &mut command,
+            "verify",
+            &file.source,
+            &file.path,
+            &verify_cmd,
+        );
+
+        match status {
+            Err(e) => {
+                log!(error, "Unable to launch verification command: {}", e);
+                false
+            }
+            Ok(v) => {
+                log!(
+                    info,
+                    path = &file.path,
+                    "Verification of {} exited with {}",
+                    &file.path,
+                    v
+                );
+                v.success()
+            }
+        }
+    }
+
+    /// Substract elapsed time for all files checkers
+    pub fn file_watch_tick(&mut self) {
+        // Loop iterations don't always last the same time, we need the real elapsed time
+        let elapsed = self.last_tick.elapsed().as_millis() as i64;
+        self.last_tick = Instant::now();
+
+        for file in &mut self.file_checks {
+            file.tick(elapsed);
+        }
+
+        for bucket in self.rate_buckets.values_mut() {
+            bucket.refill(elapsed);
+        }
+
+        for (remaining, _) in self.pending_creates.values_mut() {
+            *remaining -= elapsed;
+        }
+
+        for (remaining, _) in self.pending_moves.values_mut() {
+            *remaining -= elapsed;
+        }
+
+        for (remaining, _) in self.settling_dirs.values_mut() {
+            *remaining -= elapsed;
+        }
+
+        let now = Instant::now();
+        self.recent_inodes
+            .retain(|_, (_, expiration)| *expiration > now);
+
+        // Created files without CLOSE_WRITE in time are processed normally
+        let expired: Vec<String> = self
+            .pending_creates
+            .iter()
+            .filter(|(_, (remaining, _))| *remaining <= 0)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in expired {
+            let (_, fc) = self.pending_creates.remove(&path).unwrap();
+            log!(
+                info,
+                path = &path,
+                "No CLOSE_WRITE for {}, processing CREATE event",
+                &path
+            );
+            Self::enqueue(&mut self.file_checks, &mut self.file_executions, fc);
+        }
+
+        // Files moved without MOVED_TO in time were moved out, they're processed normally
+        let expired: Vec<u32> = self
+            .pending_moves
+            .iter()
+            .filter(|(_, (remaining, _))| *remaining <= 0)
+            .map(|(cookie, _)| *cookie)
+            .collect();
+
+        for cookie in expired {
+            let (_, fc) = self.pending_moves.remove(&cookie).unwrap();
+            log!(
+                info,
+                path = &fc.path,
+                "No MOVED_TO for {}, processing MOVED_FROM event",
+                &fc.path
+            );
+            Self::enqueue(&mut self.file_checks, &mut self.file_executions, fc);
+        }
+
+        // Directories without event for their settle window are processed once
+        let settled: Vec<PathBuf> = self
+            .settling_dirs
+            .iter()
+            .filter(|(_, (remaining, _))| *remaining <= 0)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        for path in settled {
+            let (_, fc) = self.settling_dirs.remove(&path).unwrap();
+            log!(
+                info,
+                path = path.display(),
+                "Directory {} settled",
+                path.display()
+            );
+            self.file_executions.push(fc);
+        }
+
+        self.rewatch_lost_elements();
+    }
+
+    /// Watches again the elements whose watch was removed by the kernel once their path exists
+    /// again, like a directory deleted then recreated
+    fn rewatch_lost_elements(&mut self) {
+        if self.lost_elements.is_empty() || self.last_lost_check.elapsed() < Duration::from_secs(1)
+        {
+            return;
+        }
+        self.last_lost_check = Instant::now();
+
+        for mut element in std::mem::take(&mut self.lost_elements) {
+            if !Path::new(&element.path).exists() {
+                self.lost_elements.push(element);
+                continue;
+            }
+
+            match self
+                .inotify
+                .watches()
+                .add(&element.path, element.watch_mask())
+            {
+                Err(e) => {
+                    log!(
+                        warning,
+                        path = &element.path,
+                        "Warning: unable to watch {} again: {}",
+                        &element.path,
+                        e
+                    );
+                    self.lost_elements.push(element);
+                }
+                Ok(v) => {
+                    log!(
+                        info,
+                        path = &element.path,
+                        "Watch of {} re-established",
+                        &element.path
+                    );
+                    element.watch_descriptor = v;
+                    self.manager.insert_element(element.clone());
+
+                    if element.recursive {
+                        self.manager.watch_tree(
+                            &mut self.inotify,
+                            &element.watch_descriptor,
+                            Path::new(&element.path),
+                            &element,
+                        );
+                    }
+
+                    // Files may have been put in the new directory before the watch
+                    if element.initial_scan {
+                        self.initial_scan(&element);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Watch all file sizes
+    pub fn file_watch(&mut self) {
+        let mut finished_files = Vec::new();
+        let mut truncated_files = Vec::new();
+
+        for (index, file) in &mut self.file_checks.iter_mut().enumerate() {
+            if file.lifecycle_expired() {
+                Self::lifecycle_timeout_reached(file, "completion check");
+                self.metrics
+                    .increment(Counter::Failures, &file.profile, &file.source);
+                finished_files.push(index);
+                continue;
+            }
+
+            // If file did not change, the upload/copy is considered finished
+            if file.has_changed() {
+                // The check goes on, but the truncation may need its own handling
+                if std::mem::take(&mut file.shrunk) {
+                    log!(
+                        info,
+                        path = &file.path,
+                        "File {} got smaller, truncated",
+                        &file.path
+                    );
+
+                    if !file.on_truncate.is_empty() {
+                        truncated_files.push(file.clone());
+                    }
+                }
+                continue;
+            }
+
+            // A stable file can still be corrupted, the user may want to check it
+            if !file.verify_cmd.is_empty() && !self.options.no_exec && !Self::verify_file(file) {
+                if Self::record_failure(
+                    &mut self.failure_counts,
+                    &mut self.metrics,
+                    file,
+                    "verification failed",
+                ) {
+                    finished_files.push(index);
+                    continue;
+                }
+
+                match file.verify_failure {
+                    VerifyFailure::Retry => {
+                        log!(
+                            info,
+                            path = &file.path,
+                            "File {} failed verification, checking again",
+                            &file.path
+                        );
+                        file.reset();
+                    }
+                    VerifyFailure::Drop => {
+                        log!(
+                            warning,
+                            path = &file.path,
+                            "File {} failed verification, dropped",
+                            &file.path
+                        );
+                        finished_files.push(index);
+                    }
+                }
+
+                continue;
+            }
+
+            log!(
+                info,
+                path = &file.path,
+                "File {} is now ready for execution",
+                &file.path
+            );
+            self.file_executions.push(file.clone());
+            finished_files.push(index);
+        }
+
+        // We delete finished file checks
+        finished_files.sort();
+        finished_files.reverse();
+
+        for i in finished_files {
+            self.file_checks.remove(i);
+        }
+
+        for file in truncated_files {
+            let hook = Self::convert_command(
+                &file.on_truncate,
+                &[('@', &file.dir), ('#', &file.file), ('&', &file.event)],
+                SubstitutionMode::Shell,
+            );
+            let hook = file.expand_dates(&hook);
+
+            log!(
+                info,
+                path = &file.path,
+                "TRUNCATE({}) => {}",
+                &file.path,
+                &hook
+            );
+            if !self.options.no_exec {
+                self.spawn(&hook, file.capture_output, None, Some(&file));
+            }
+        }
+    }
+
+    /// Exports event information as environment variables
+    ///
+    /// # Parameters
+    ///
+    /// * `command`: The command to spawn
+    /// * `file`: The file the command is executed for
+    fn set_event_env(command: &mut Command, file: &FileCheck) {
+        command
+            .env("RINCRON_PATH", &file.dir)
+            .env("RINCRON_FILE", &file.file)
+            .env("RINCRON_EVENT", &file.event)
+            .env(
+                "RINCRON_FULLPATH",
+                Path::new(&file.dir).join(&file.file).as_os_str(),
+            );
+
+        if let Some(cookie) = file.cookie {
+            command.env("RINCRON_COOKIE", cookie.to_string());
+        }
+
+        if !file.moved_from.is_empty() {
+            command.env("RINCRON_OLD_FILE", &file.moved_from);
+        }
+
+        if let Some(after) = &file.attributes_after {
+            after.set_env(command, "AFTER");
+
+            if let Some(before) = &file.attributes_before {
+                before.set_env(command, "BEFORE");
+                command.env("RINCRON_ATTRIB_CHANGES", before.changes(after));
+            }
+        }
+    }
+
+    /// Sets the environment variables of the element, after clearing rincron's ones if asked
+    ///
+    /// # Parameters
+    ///
+    /// * `command`: The command to prepare
+    /// * `clean_env`: Whether rincron's environment is cleared
+    /// * `env`: The variables to set
+    pub fn set_env(command: &mut Command, clean_env: bool, env: &HashMap<String, String>) {
+        if clean_env {
+            command.env_clear();
+        }
+
+        command.envs(env);
+    }
+
+    /// Prepares a command, run by bash unless the file has an argument list
+    ///
+    /// # Parameters
+    ///
+    /// * `cmd`: The command line
+    /// * `file`: The file the command is executed for, `None` for hooks
+    fn build_command(cmd: &str, file: Option<&FileCheck>, shell: &str) -> Command {
+        match file.filter(|f| !f.argv.is_empty()) {
+            Some(fc) => {
+                let argv = Self::final_argv(fc);
+                let mut command = Command::new(&argv[0]);
+                command.args(&argv[1..]);
+                command
+            }
+            None if file.is_some_and(|f| f.stdin_script) => {
+                let mut command = Command::new(Self::shell_program(shell));
+                command.arg("-s");
+                command
+            }
+            None => Self::shell_command(shell, cmd),
+        }
+    }
+
+    /// Creates the command executing a command line with a shell
+    ///
+    /// With the `none` shell, the command line is split into arguments like a shell would, but
+    /// executed directly
+    ///
+    /// # Parameters
+    ///
+    /// * `shell`: The shell, empty for the default one
+    /// * `cmd`: The command line
+    pub fn shell_command(shell: &str, cmd: &str) -> Command {
+        if shell != "none" {
+            let mut command = Command::new(Self::shell_program(shell));
+            command.arg("-c").arg(cmd);
+            return command;
+        }
+
+        // Quotes are checked with the config, replaced wildcards are escaped
+        let argv = shell_words::split(cmd).unwrap_or_default();
+        let mut command = Command::new(argv.first().map(|a| a.as_str()).unwrap_or_default());
+        command.args(argv.iter().skip(1));
+        command
+    }
+
+    /// Returns the shell program to execute, bash by default or sh if bash is missing
+    ///
+    /// # Parameters
+    ///
+    /// * `shell`: The configured shell, empty for the default one
+    fn shell_program(shell: &str) -> &str {
+        static DEFAULT: OnceLock<&str> = OnceLock::new();
+
+        if !shell.is_empty() {
+            return shell;
+        }
+
+        DEFAULT.get_or_init(|| {
+            let found = std::env::var_os("PATH")
+                .is_some_and(|p| std::env::split_paths(&p).any(|d| d.join("bash").is_file()));
+
+            if found {
+                return "bash";
+            }
+
+            log!(
+                warning,
+                "Warning: bash not found, commands are executed with sh"
+            );
+            "sh"
+        })
+    }
+
+    /// Gives the arguments of a file executed without shell, dates expanded
+    ///
+    /// Dates are expanded in the templates before the route and the wildcards so that a value
+    /// containing a placeholder is given as is
+    ///
+    /// # Parameters
+    ///
+    /// * `fc`: The file the command is executed for
+    fn final_argv(fc: &FileCheck) -> Vec<String> {
+        if fc.argv_template.is_empty() {
+            return fc.argv.iter().map(|a| fc.expand_dates(a)).collect();
+        }
+
+        let values = [
+            ('@', fc.dir.as_str()),
+            ('#', fc.file.as_str()),
+            ('&', fc.event.as_str()),
+            ('<', fc.moved_from.as_str()),
+            ('>', fc.moved_to()),
+        ];
+
+        fc.argv_template
+            .iter()
+            .map(|t| {
+                let mut t = fc.expand_dates(t);
+
+                // Templates give a literal `$` with `$$`
+                if let Some(route) = &fc.route {
+                    t = t.replace("${route}", &route.replace('$', "$$"));
+                }
+
+                Self::convert_command(&t, &values, SubstitutionMode::Argv)
+            })
+            .collect()
+    }
+
+    /// Spawns a command
+    ///
+    /// # Parameters
+    ///
+    /// * `cmd`: The command line
+    /// * `capture_output`: Whether the outputs are written to the log
+    /// * `file`: The file the command is executed for, `None` for hooks
+    /// * `hook_of`: The file whose command triggered the hook
+    fn spawn(
+        &mut self,
+        cmd: &str,
+        capture_output: bool,
+        file: Option<FileCheck>,
+        hook_of: Option<&FileCheck>,
+    ) {
+        // Outputs are read by dedicated threads to never block the child
+        let output = |piped: bool| {
+            if piped {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            }
+        };
+
+        // Chained files are read on the standard output
+        let chain_output = file.as_ref().is_some_and(|f| f.chain_output);
+
+        // Executions are checked before, hooks just lose their output
+        let capture_output = if file.is_none() && capture_output && !self.fd_headroom_allows(2) {
+            log!(
+                warning,
+                "Hook output not read, too few file descriptors left"
+            );
+            false
+        } else {
+            capture_output
+        };
+
+        // Too long commands are read by the shell on its standard input
+        let stdin_script = file.as_ref().is_some_and(|f| f.stdin_script);
+
+        // Hooks get the shell and the environment of the element
+        let origin = file.as_ref().or(hook_of);
+        let shell = origin.map(|f| f.shell.as_str()).unwrap_or_default();
+
+        let mut command = Self::build_command(cmd, file.as_ref(), shell);
+        command
+            .stdout(output(capture_output || chain_output))
+            .stderr(output(capture_output))
+            .stdin(output(stdin_script));
+
+        // Outputs go to the log file of the element instead, except chained files
+        if let Some(log_file) = origin
+            .filter(|f| !f.log_file.is_empty())
+            .and_then(Self::open_log_file)
+        {
+            if !chain_output {
+                match log_file.try_clone() {
+                    Ok(v) => {
+                        command.stdout(v);
+                    }
+                    Err(e) => log!(
+                        warning,
+                        "Warning: unable to share log file with stdout: {}",
+                        e
+                    ),
+                }
+            }
+            command.stderr(log_file);
+        }
+
+        if let Some(fc) = origin {
+            Self::set_env(&mut command, fc.clean_env, &fc.env);
+        }
+
+        // Scripts can read event information without parsing the command line
+        if let Some(fc) = &file {
+            Self::set_event_env(&mut command, fc);
+        }
+
+        let child = command.spawn();
+
+        // The child has its own descriptors of the log file, ours are closed right away
+        drop(command);
+
+        let kind = if file.is_some() { "command" } else { "hook" };
+        let (source, path) = origin.map_or((String::new(), String::new()), |f| {
+            (f.source.clone(), f.path.clone())
+        });
+
+        match child {
+            Err(e) => {
+                log!(error, "Unable to launch command: {}", e);
+                audit::spawn_failed(kind, &source, &path, cmd, &e.to_string());
+                self.metrics.increment(
+                    Counter::Failures,
+                    Self::profile_of(&file),
+                    Self::source_of(&file),
+                );
+            }
+            Ok(mut v) => {
+                log!("Child {} spawned", v.id());
+
+                // The shell reads while it executes, the script may not fit in the pipe
+                if let Some(mut stdin) = v.stdin.take() {
+                    let script = cmd.to_string();
+                    std::thread::spawn(move || {
+                        let _ = stdin.write_all(script.as_bytes());
+                    });
+                }
+
+                self.metrics.increment(
+                    Counter::Spawns,
+                    Self::profile_of(&file),
+                    Self::source_of(&file),
+                );
+
+                let id = audit::spawned(kind, &source, &path, cmd, v.id());
+                let mut process = ChildProcess::new(v, file);
+                process.audit_id = id;
+                self.child_processes.push(process);
+            }
+        };
+    }
+
+    /// Opens the log file of a file for appending, creating it if needed
+    ///
+    /// # Parameters
+    ///
+    /// * `file`: The file the command is executed for
+    fn open_log_file(file: &FileCheck) -> Option<std::fs::File> {
+        let path = file.expand_dates(&file.log_file);
+
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log!(
+                    warning,
+                    path = &path,
+                    "Warning: unable to open log file {}: {}",
+                    &path,
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the profile a command is counted in, empty for hooks
+    ///
+    /// # Parameters
+    ///
+    /// * `file`: The file the command is executed for
+    fn profile_of(file: &Option<FileCheck>) -> &str {
+        file.as_ref().map_or("", |f| f.profile.as_str())
+    }
+
+    /// Returns the config file a command is counted in, empty for hooks
+    ///
+    /// # Parameters
+    ///
+    /// * `file`: The file the command is executed for
+    fn source_of(file: &Option<FileCheck>) -> &str {
+        file.as_ref().map_or("", |f| f.source.as_str())
+    }
+
+    /// Warns about running children whose working directory was removed, then applies the
+    /// policy of the element watching it
+    ///
+    /// A directory still used as working directory is only removed by the kernel once unused,
+    /// so inotify can't tell it's gone, working directories are checked every second instead
+    fn check_removed_cwds(&mut self) {
+        if self.last_cwd_check.elapsed() < Duration::from_secs(1) {
+            return;
+        }
+        self.last_cwd_check = Instant::now();
+
+        for process in self
+            .child_processes
+            .iter_mut()
+            .filter(|p| !p.cwd_removed && !p.exited())
+        {
+            let id = process.child.id();
+
+            let cwd = match std::fs::read_link(format!("/proc/{}/cwd", id)) {
+                Ok(v) => v.to_string_lossy().into_owned(),
+                Err(_) => continue,
+            };
+
+            // The kernel marks removed directories
+            let cwd = match cwd.strip_suffix(" (deleted)") {
+                Some(v) => v,
+                None => continue,
+            };
+
+            process.cwd_removed = true;
+            log!(
+                warning,
+                "Warning: working directory {} of child {} was removed",
+                cwd,
+                id
+            );
+
+            let policy = self
+                .manager
+                .elements()
+                .find(|e| Path::new(cwd).starts_with(&e.path))
+                .map(|e| e.on_cwd_removed)
+                .unwrap_or(ShutdownPolicy::Wait);
+
+            match policy {
+                ShutdownPolicy::Wait => {}
+                ShutdownPolicy::Terminate => {
+                    log!("Child {} terminated", id);
+                    // SAFETY: the child is not reaped yet, its pid can't be reused
+                    unsafe { libc::kill(id as libc::pid_t, libc::SIGTERM) };
+                }
+                ShutdownPolicy::Kill => {
+                    log!("Child {} killed", id);
+                    if let Err(e) = process.child.kill() {
+                        log!(error, "Unable to kill child {}: {}", id, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Executes files
+    pub fn file_execute(&mut self) {
+        let mut deferred_files = Vec::new();
+        let files = std::mem::take(&mut self.file_executions);
+        let mut group_running = self.group_running();
+
+        for mut file in self.fair_order(files) {
+            if file.lifecycle_expired() {
+                Self::lifecycle_timeout_reached(&file, "execution queue");
+                self.metrics
+                    .increment(Counter::Failures, &file.profile, &file.source);
+                continue;
+            }
+
+            // A failed command waits before being executed again
+            if file.retry_at.is_some_and(|t| t > Instant::now()) {
+                deferred_files.push(file);
+                continue;
+            }
+
+            // Without enough space, the execution is kept for later
+            if !file.has_free_space() {
+                deferred_files.push(file);
+                continue;
+            }
+
+            // Commands of a group wait for a running one to exit
+            let group = file.concurrency_group.clone();
+            if !group.is_empty()
+                && group_running.get(&group).copied().unwrap_or(0) >= file.group_limit
+            {
+                deferred_files.push(file);
+                continue;
+            }
+
+            // Stuck children must not accumulate forever
+            if !self.children_cap_allows() {
+                deferred_files.push(file);
+                continue;
+            }
+
+            // Each read output holds a pipe while the command runs
+            let pipes =
+                file.capture_output as u64 * 2 + (file.chain_output && !file.capture_output) as u64;
+            if pipes > 0 && !self.options.no_exec && !self.fd_headroom_allows(pipes) {
+                deferred_files.push(file);
+                continue;
+            }
+
+            // Dates are resolved at execution time, not when the event is read
+            let mut cmd = file.expand_dates(&file.cmd);
+
+            // The whole pipeline runs, but nothing is executed
+            if self.options.no_exec {
+                log!(
+                    info,
+                    path = &file.path,
+                    "NO-EXEC({}) => {}",
+                    &file.path,
+                    &cmd
+                );
+                continue;
+            }
+
+            // The route is computed right before the execution, from the file as it is then
+            if !file.route_cmd.is_empty() {
+                let route = match Self::route_file(&file) {
+                    Some(v) => v,
+                    None => {
+                        self.metrics
+                            .increment(Counter::Failures, &file.profile, &file.source);
+                        Self::record_failure(
+                            &mut self.failure_counts,
+                            &mut self.metrics,
+                            &file,
+                            "route command failed",
+                        );
+                        continue;
+                    }
+                };
+
+                // Retries and hooks keep the same route
+                let escaped_route = Self::escape_value(&route);
+                cmd = cmd.replace("${route}", &escaped_route);
+                file.cmd = file.cmd.replace("${route}", &escaped_route);
+                file.argv = file
+                    .argv
+                    .iter()
+                    .map(|a| a.replace("${route}", &route))
+                    .collect();
+                file.route = Some(route);
+                file.route_cmd.clear();
+            }
+
+            // Callbacks run in process, the pool holds them back once its queue is full
+            if let Some(callback) = self.callbacks.get(&file.element).cloned() {
+                let pool = self
+                    .workers
+                    .get_or_insert_with(|| WorkerPool::new(self.options.workers));
+
+                let (path, profile, source) =
+                    (file.path.clone(), file.profile.clone(), file.source.clone());
+                match pool.submit(callback, file) {
+                    None => {
+                        log!(info, path = &path, "CALLBACK({})", &path);
+                        self.metrics.increment(Counter::Spawns, &profile, &source);
+                    }
+                    Some(file) => deferred_files.push(file),
+                }
+
+                continue;
+            }
+
+            // The kernel refuses too long arguments, which gives confusing spawn failures
+            if !self.command_length_allows(&cmd, &mut file) {
+                continue;
+            }
+
+            // A substitution giving nothing is a config error, a shell doing nothing hides it
+            if Self::command_is_empty(&cmd, &file) {
+                log!(
+                    warning,
+                    path = &file.path,
+                    "Warning: command of {} (element {} from {}) is empty, execution skipped",
+                    &file.path,
+                    &file.element,
+                    &file.source
+                );
+                continue;
+            }
+
+            log!(info, path = &file.path, "CMD({}) => {}", &file.path, &cmd);
+            self.last_served = Some(file.element.clone());
+
+            let capture_output = file.capture_output;
+            let running = self.child_processes.len();
+            self.spawn(&cmd, capture_output, Some(file), None);
+
+            if !group.is_empty() && self.child_processes.len() > running {
+                *group_running.entry(group).or_default() += 1;
+            }
+        }
+
+        self.file_executions = deferred_files;
+    }
+
+    /// Counts the running commands of each concurrency group
+    fn group_running(&self) -> HashMap<String, usize> {
+        let mut running = HashMap::new();
+
+        for file in self.child_processes.iter().filter_map(|p| p.file.as_ref()) {
+            if !file.concurrency_group.is_empty() {
+                *running.entry(file.concurrency_group.clone()).or_default() += 1;
+            }
+        }
+
+        running
+    }
+
+    /// Checks if a command has nothing to execute, only blanks for a shell or an empty program
+    /// for an argument list
+    ///
+    /// # Parameters
+    ///
+    /// * `cmd`: The command line, dates expanded
+    /// * `file`: The file the command is executed for
+    fn command_is_empty(cmd: &str, file: &FileCheck) -> bool {
+        match file.argv.is_empty() {
+            true => cmd.trim().is_empty(),
+            false => Self::final_argv(file)[0].trim().is_empty(),
+        }
+    }
+
+    /// Orders executions round-robin across elements, so a flooded directory can't delay the
+    /// other ones when executions are held back
+    ///
+    /// Files of a same element keep their order, the element served last comes last
+    ///
+    /// # Parameters
+    ///
+    /// * `files`: The files to execute
+    fn fair_order(&self, files: Vec<FileCheck>) -> Vec<FileCheck> {
+        let mut groups: Vec<(String, VecDeque<FileCheck>)> = Vec::new();
+        let count = files.len();
+
+        for file in files {
+            match groups
+                .iter_mut()
+                .find(|(element, _)| *element == file.element)
+            {
+                Some((_, group)) => group.push_back(file),
+                None => groups.push((file.element.clone(), VecDeque::from([file]))),
+            }
+        }
+
+        if let Some(index) = groups
+            .iter()
+            .position(|(element, _)| Some(element) == self.last_served.as_ref())
+        {
+            groups.rotate_left(index + 1);
+        }
+
+        let mut ordered = Vec::with_capacity(count);
+
+        while !groups.is_empty() {
+            groups.retain_mut(|(_, group)| {
+                ordered.extend(group.pop_front());
+                !group.is_empty()
+            });
+        }
+
+        ordered
+    }
+
+    /// Queues the periodic tasks due for execution
+    pub fn periodic_tick(&mut self) {
+        let now = Instant::now();
+
+        for task in &mut self.periodic_tasks {
+            if task.next_run > now {
+                continue;
+            }
+
+            task.schedule_next();
+
+            let mut fc = FileCheck::new("periodic", 0, &task.command);
+            fc.completion = Completion::Immediate;
+            fc.capture_output = task.capture_output;
+            fc.source = "periodic".to_string();
+            self.file_executions.push(fc);
+        }
+    }
+
+    /// Checks if a command line can be executed with respect to the maximum length, switching
+    /// the shell to its standard input if the policy allows it
+    ///
+    /// # Parameters
+    ///
+    /// * `cmd`: The command line, dates expanded
+    /// * `file`: The file the command is executed for
+    fn command_length_allows(&mut self, cmd: &str, file: &mut FileCheck) -> bool {
+        // Each argument is given to the kernel with its final NUL
+        let length: usize = match file.argv.is_empty() {
+            true if file.shell == "none" => shell_words::split(cmd)
+                .unwrap_or_default()
+                .iter()
+                .map(|a| a.len() + 1)
+                .sum(),
+            true => [Self::shell_program(&file.shell), "-c", cmd]
+                .iter()
+                .map(|a| a.len() + 1)
+                .sum(),
+            false => Self::final_argv(file).iter().map(|a| a.len() + 1).sum(),
+        };
+
+        if self.options.max_command_length == 0 || length <= self.options.max_command_length {
+            return true;
+        }
+
+        // Only a shell can read its script on the standard input
+        if self.options.long_command_policy == LongCommandPolicy::Stdin
+            && file.argv.is_empty()
+            && file.shell != "none"
+        {
+            log!(
+                warning,
+                path = &file.path,
+                "Command of {} is {} bytes long, over the maximum of {}, given on the shell standard input",
+                &file.path,
+                length,
+                self.options.max_command_length
+            );
+            file.stdin_script = true;
+            return true;
+        }
+
+        let error = format!(
+            "command is {} bytes long, over the maximum of {}",
+            length, self.options.max_command_length
+        );
+        log!(
+            error,
+            path = &file.path,
+            "Unable to launch command of {}: {}",
+            &file.path,
+            &error
+        );
+        audit::spawn_failed("command", &file.source, &file.path, cmd, &error);
+        self.metrics
+            .increment(Counter::Failures, &file.profile, &file.source);
+        false
+    }
+
+    /// Halves the maximum number of running children on SIGTTOU, doubles it on SIGTTIN, up to
+    /// the configured maximum
+    fn adjust_max_children(&mut self) {
+        let previous = self.max_children;
+
+        if self
+            .throttle_down
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            // Without limit, the running children are the starting point
+            if self.max_children == 0 {
+                self.max_children_ceiling = self.child_processes.len().max(1);
+                self.max_children = self.max_children_ceiling;
+            }
+
+            self.max_children = (self.max_children / 2).max(1);
+        }
+
+        if self
+            .throttle_up
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+            && self.max_children != 0
+        {
+            self.max_children *= 2;
+
+            if self.max_children >= self.max_children_ceiling {
+                self.max_children = self.options.max_children;
+            }
+        }
+
+        if self.max_children == previous {
+            return;
+        }
+
+        match self.max_children {
+            0 => log!("Maximum number of running children removed"),
+            v if v == self.options.max_children => {
+                log!("Maximum number of running children back to {}", v)
+            }
+            v => log!(
+                "Maximum number of running children changed from {} to {}",
+                previous,
+                v
+            ),
+        }
+    }
+
+    /// Checks the number of running children against the maximum, returns `false` if the
+    /// command must be held back
+    fn children_cap_allows(&mut self) -> bool {
+        let max = self.max_children;
+
+        if max == 0 || self.child_processes.len() < max {
+            if self.children_cap_reached {
+                log!("Running children below {}, executions resumed", max);
+                self.children_cap_reached = false;
+            }
+
+            return true;
+        }
+
+        // A limit lowered at runtime is meant to reduce the load, it always holds executions back
+        let policy = match max == self.options.max_children {
+            true => self.options.max_children_policy,
+            false => ChildrenCapPolicy::Backpressure,
+        };
+
+        // Logging once per overflow is enough to alert without flooding the log
+        if !self.children_cap_reached {
+            match policy {
+                ChildrenCapPolicy::Backpressure => log!(
+                    warning,
+                    "Warning: {} children running, executions held back until some exit",
+                    self.child_processes.len()
+                ),
+                ChildrenCapPolicy::Log => log!(
+                    critical,
+                    "CRITICAL: {} children running, maximum is {}",
+                    self.child_processes.len(),
+                    max
+                ),
+            }
+
+            self.children_cap_reached = true;
+        }
+
+        policy == ChildrenCapPolicy::Log
+    }
+
+    /// Reads the maximum number of open file descriptors
+    fn read_fd_limit() -> u64 {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+
+        // SAFETY: the struct is valid and written by the call
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+            log!(
+                warning,
+                "Warning: unable to read the file descriptor limit: {}",
+                std::io::Error::last_os_error()
+            );
+            return u64::MAX;
+        }
+
+        if limit.rlim_cur == libc::RLIM_INFINITY {
+            u64::MAX
+        } else {
+            limit.rlim_cur
+        }
+    }
+
+    /// Returns the approximate number of open file descriptors
+    fn fd_usage(&self) -> u64 {
+        // Standard streams and inotify
+        let mut usage = 4;
+
+        if self.options.statsd_addr.is_some() {
+            usage += 1;
+        }
+
+        #[cfg(feature = "nats")]
+        {
+            usage += self.publishers.len() as u64;
+        }
+
+        let pipes: usize = self.child_processes.iter().map(|p| p.open_pipes()).sum();
+        usage + pipes as u64
+    }
+
+    /// Checks if enough file descriptors are left to read outputs of a new command
+    ///
+    /// # Parameters
+    ///
+    /// * `pipes`: The number of outputs to read
+    fn fd_headroom_allows(&mut self, pipes: u64) -> bool {
+        let usage = self.fd_usage();
+
+        if usage
+            .saturating_add(pipes)
+            .saturating_add(self.options.fd_headroom)
+            <= self.fd_limit
+        {
+            if self.fd_limit_reached {
+                log!("File descriptors available again, output capturing resumed");
+                self.fd_limit_reached = false;
+            }
+
+            return true;
+        }
+
+        // Without outputs being read, no descriptor will be freed, like with a limit lower than
+        // the headroom: waiting would be forever
+        if self.child_processes.iter().all(|p| p.open_pipes() == 0) {
+            return true;
+        }
+
+        // Logging once per shortage is enough to alert without flooding the log
+        if !self.fd_limit_reached {
+            log!(
+                warning,
+                "Warning: about {} file descriptors used out of {}, commands reading outputs held back",
+                usage,
+                self.fd_limit
+            );
+            self.fd_limit_reached = true;
+        }
+
+        false
+    }
+
+    /// Returns whether a drained reload can be applied, pending work being done or the drain
+    /// timeout reached
+    fn drained(&self) -> bool {
+        let since = match self.draining_since {
+            Some(v) => v,
+            None => return false,
+        };
+
+        if self.file_checks.is_empty() && self.file_executions.is_empty() {
+            log!("Pending work drained");
+            return true;
+        }
+
+        if self.options.drain_timeout > 0
+            && since.elapsed() >= Duration::from_secs(self.options.drain_timeout)
+        {
+            log!(
+                warning,
+                "Warning: drain timeout reached with {} file checks and {} executions pending, reloading anyway",
+                self.file_checks.len(),
+                self.file_executions.len()
+            );
+            return true;
+        }
+
+        false
+    }
+
+    /// Reloads configs at the end of a drain, then processes the events deferred meanwhile
+    fn drained_reload(&mut self) {
+        log!("Reloading rincron");
+        self.draining_since = None;
+        self.clear_processed_files();
+        self.read_configs();
+
+        let deferred_events = std::mem::take(&mut self.deferred_events);
+
+        if !deferred_events.is_empty() {
+            log!("Processing {} deferred events", deferred_events.len());
+        }
+
+        // Watch descriptors of changed elements may be different after the reload
+        for (directory, mask, cookie, file) in deferred_events {
+            match self.manager.descriptor_of(&directory) {
+                Some(wd) => self.process_event(&wd, mask, cookie, &file),
+                None => log!(
+                    "{} is not watched anymore, deferred event on {} discarded",
+                    directory.display(),
+                    file.to_string_lossy()
+                ),
+            }
+        }
+    }
+
+    /// Check if rincron has been idle for too long
+    ///
+    /// Pending checks, executions, running children and callbacks are considered as activity
+    pub fn idle_expired(&mut self) -> bool {
+        if !self.file_checks.is_empty()
+            || !self.file_executions.is_empty()
+            || !self.pending_creates.is_empty()
+            || !self.pending_moves.is_empty()
+            || !self.settling_dirs.is_empty()
+            || !self.child_processes.is_empty()
+            || self.workers.as_ref().is_some_and(|w| w.in_flight() > 0)
+        {
+            self.last_activity = Instant::now();
+        }
+
+        self.options.idle_exit > 0
+            && self.last_activity.elapsed() >= Duration::from_secs(self.options.idle_exit)
+    }
+
+    /// Executes the main loop
+    pub fn execute(&mut self) {
+        self.read_configs();
+
+        // Watching nothing is most likely a configuration mistake
+        if self.manager.watch_count() == 0 {
+            if self.options.require_watches {
+                log!(error, "Error: no watch established, exiting");
+                std::process::exit(1);
+            }
+
+            log!(
+                warning,
+                "WARNING! No watch established, rincron will do nothing until a reload"
+            );
+        }
+
+        self.hook_signals();
+        self.open_journal();
+
+        if let Some(address) = self.options.statsd_addr.clone() {
+            #[cfg(feature = "statsd")]
+            if let Err(e) = self.metrics.connect_statsd(&address) {
+                log!(
+                    warning,
+                    "Warning: unable to connect to statsd {}: {}",
+                    address,
+                    e
+                );
+            }
+
+            #[cfg(not(feature = "statsd"))]
+            log!(
+                warning,
+                "Warning: statsd support is not compiled, metrics will not be sent to {}",
+                address
+            );
+        }
+
+        // Watches are established with current privileges, then we can switch user
+        if let Some(user) = self.options.run_as.clone() {
+            let watch_count = self.manager.watch_count();
+
+            if watch_count == 0 {
+                log!(
+                    warning,
+                    "Warning: no watch established before dropping privileges"
+                );
+            } else {
+                log!(
+                    "{} watches established before dropping privileges",
+                    watch_count
+                );
+            }
+
+            if let Err(e) = self.drop_privileges(&user) {
+                log!(error, "Error while dropping privileges: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        systemd::notify("READY=1");
+
+        loop {
+            // Exit requested
+            if self.sigterm.load(std::sync::atomic::Ordering::Relaxed) {
+                log!("Exiting rincron, thanks for using it");
+                break;
+            }
+
+            // Nothing happened for too long
+            if self.idle_expired() {
+                log!(
+                    "Nothing happened for {} seconds, exiting rincron",
+                    self.options.idle_exit
+                );
+                break;
+            }
+
+            // Binary upgrade requested
+            if self
+                .upgrade
+                .swap(false, std::sync::atomic::Ordering::Relaxed)
+            {
+                self.reexec();
+                continue;
+            }
+
+            // Reload requested
+            if self.reload.load(std::sync::atomic::Ordering::Relaxed) {
+                self.reload
+                    .store(false, std::sync::atomic::Ordering::Relaxed);
+
+                // Only the listed config files are read again if asked
+                if let Some(files) = self.reload_requests() {
+                    systemd::notify("RELOADING=1");
+
+                    for file in files {
+                        self.reload_config(&file);
+                    }
+
+                    systemd::notify("READY=1");
+                    continue;
+                }
+
+                log!("Reloading rincron");
+                systemd::notify("RELOADING=1");
+                self.clear_processed_files();
                 self.read_configs();
+                systemd::notify("READY=1");
+                continue;
+            }
+
+            // Concurrency change requested
+            self.adjust_max_children();
+
+            // Drained reload requested
+            if self.drain.swap(false, std::sync::atomic::Ordering::Relaxed)
+                && self.draining_since.is_none()
+            {
+                log!("Draining pending work before reloading rincron");
+                self.draining_since = Some(Instant::now());
+            }
+
+            if self.drained() {
+                systemd::notify("RELOADING=1");
+                self.drained_reload();
+                systemd::notify("READY=1");
                 continue;
             }
 
             // Main program
-            self.watch_children();
-            self.file_watch_tick();
-            self.watch_events(&mut buffer);
-            self.file_watch();
-            self.file_execute();
+            self.tick();
+            self.notify_watchdog();
+        }
+
+        systemd::notify("STOPPING=1");
+        self.stop_children();
+
+        if !self.options.no_summary {
+            self.log_summary();
+        }
+    }
+
+    /// Notifies the systemd watchdog if its interval has elapsed
+    fn notify_watchdog(&mut self) {
+        let interval = match self.watchdog_interval {
+            None => return,
+            Some(v) => v,
+        };
+
+        if self.last_watchdog.elapsed() >= interval {
+            systemd::notify("WATCHDOG=1");
+            self.last_watchdog = Instant::now();
+        }
+    }
+
+    /// Logs a summary of the activity since startup
+    fn log_summary(&self) {
+        let counters = &self.metrics.counters;
+        let uptime = self.started_at.elapsed().as_secs();
+
+        log!(
+            "Summary: {} events, {} commands, {} failed, {} quarantined, {} events shed, up {}h{:02}m{:02}s",
+            counters.events,
+            counters.spawns,
+            counters.failures,
+            counters.quarantines,
+            counters.shed,
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60
+        );
+    }
+
+    /// Stops running children according to their shutdown policy, then waits for them during
+    /// the grace period
+    ///
+    /// Children still running after the grace period are killed
+    pub fn stop_children(&mut self) {
+        if self.child_processes.is_empty() {
+            return;
+        }
+
+        log!("Stopping {} running children", self.child_processes.len());
+
+        for process in self.child_processes.iter_mut().filter(|p| !p.exited()) {
+            let policy = process
+                .file
+                .as_ref()
+                .map(|f| f.on_shutdown)
+                .unwrap_or(ShutdownPolicy::Terminate);
+
+            match policy {
+                ShutdownPolicy::Wait => {}
+                ShutdownPolicy::Terminate => {
+                    // SAFETY: the child is not reaped yet, its pid can't be reused
+                    unsafe { libc::kill(process.child.id() as libc::pid_t, libc::SIGTERM) };
+                }
+                ShutdownPolicy::Kill => {
+                    if let Err(e) = process.child.kill() {
+                        log!(error, "Unable to kill child {}: {}", process.child.id(), e);
+                    }
+                }
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(self.options.shutdown_grace);
+
+        loop {
+            self.child_processes
+                .retain_mut(|p| match p.child.try_wait() {
+                    Ok(None) => true,
+                    Ok(Some(status)) => {
+                        audit::exited(p.audit_id.as_deref(), p.child.id(), status);
+                        false
+                    }
+                    Err(_) => false,
+                });
+
+            if self.child_processes.is_empty() {
+                return;
+            }
+
+            if Instant::now() >= deadline {
+                break;
+            }
+
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        for process in &mut self.child_processes {
+            log!(
+                warning,
+                "Child {} still running after the grace period, killed",
+                process.child.id()
+            );
+
+            if process.child.kill().is_ok() {
+                if let Ok(status) = process.child.wait() {
+                    audit::exited(process.audit_id.as_deref(), process.child.id(), status);
+                }
+            }
+        }
+    }
+
+    /// Executes a callback on the worker threads instead of the command of an element
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: The watched path, like in the config
+    /// * `callback`: The callback, given the file and returning `true` on success
+    pub fn register_callback(&mut self, path: &str, callback: Callback) {
+        self.callbacks.insert(path.to_string(), callback);
+    }
+
+    /// Returns the number of callbacks queued or running
+    pub fn running_callbacks(&self) -> usize {
+        self.workers.as_ref().map(|w| w.in_flight()).unwrap_or(0)
+    }
+}
+
+/// Test harness feeding synthetic events to the pipeline, without the kernel
+///
+/// Elements are still watched for real, so their directories must exist, but events are given
+/// directly to the processing. Tests can then check the resulting file checks, executions and
+/// children. Available in tests and with the `test-utils` feature.
+#[cfg(any(test, feature = "test-utils"))]
+impl Rincron {
+    /// Replaces all elements by the ones of a JSON config
+    ///
+    /// # Parameters
+    ///
+    /// * `json`: The config, like the content of a config file
+    pub fn load_elements(&mut self, json: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let values: Vec<Value> = serde_json::from_str(json)?;
+        let context = ParseContext {
+            source: "test harness".to_string(),
+            base_dir: PathBuf::from("/"),
+            strict: true,
+            profile: String::new(),
+        };
+
+        self.manager.begin_transaction();
+
+        for value in &values {
+            let element = WatchElement::from_json_value(value, &mut self.inotify, &context)?;
+            self.manager.add_element(element);
+        }
+
+        self.manager.end_transaction(&mut self.inotify, false);
+        Ok(())
+    }
+
+    /// Processes a synthetic event like if inotify had read it, returns `false` if no element
+    /// watches the path
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: The watched path, like in the config
+    /// * `file`: The file name
+    /// * `mask`: The event mask
+    /// * `cookie`: The event cookie, 0 if none
+    pub fn inject_event(&mut self, path: &str, file: &str, mask: EventMask, cookie: u32) -> bool {
+        let wd = match self.manager.elements().find(|e| e.path == path) {
+            Some(v) => v.watch_descriptor.clone(),
+            None => return false,
+        };
+
+        self.process_event(&wd, mask, cookie, OsStr::new(file));
+        true
+    }
+
+    /// Returns the files waiting for completion
+    pub fn pending_checks(&self) -> &[FileCheck] {
+        &self.file_checks
+    }
+
+    /// Returns the files waiting for execution
+    pub fn pending_executions(&self) -> &[FileCheck] {
+        &self.file_executions
+    }
+
+    /// Returns the number of running children
+    pub fn running_children(&self) -> usize {
+        self.child_processes.len()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    /// Creates an empty directory for a test
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The test name, making the directory unique
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rincron-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Creates a rincron watching a directory with a single element
+    ///
+    /// # Parameters
+    ///
+    /// * `dir`: The watched directory
+    /// * `element`: The element config, without its path
+    pub(crate) fn rincron_with(dir: &Path, mut element: Value) -> Rincron {
+        element["path"] = json!(dir.to_string_lossy());
+        let mut rincron = Rincron::init(Options::default()).unwrap();
+        rincron
+            .load_elements(&json!([element]).to_string())
+            .unwrap();
+        rincron
+    }
+
+    /// Runs the main loop until a condition is met, fails after 5 seconds
+    ///
+    /// # Parameters
+    ///
+    /// * `rincron`: The rincron to run
+    /// * `done`: The condition
+    pub(crate) fn tick_until(rincron: &mut Rincron, done: impl Fn(&Rincron) -> bool) {
+        let start = Instant::now();
+
+        while !done(rincron) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "condition never met"
+            );
+            rincron.tick();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Returns the number of files waiting for completion or execution
+    fn queued(rincron: &Rincron) -> usize {
+        rincron.pending_checks().len() + rincron.pending_executions().len()
+    }
+
+    #[test]
+    fn exclude_matches_raw_names() {
+        let dir = test_dir("exclude");
+        let mut rincron = rincron_with(
+            &dir,
+            json!({
+                "events": ["CLOSE_WRITE"],
+                "exclude": ["my file*", "it's*", "$HOME*"],
+                "ignore_temp_files": true,
+                "command": "true"
+            }),
+        );
+        let path = dir.to_string_lossy().to_string();
+
+        for file in [
+            "my file.txt",
+            "it's.txt",
+            "$HOME.txt",
+            "a~",
+            ".#a",
+            ".~lock.a.odt#",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+            assert!(rincron.inject_event(&path, file, EventMask::CLOSE_WRITE, 0));
+            assert_eq!(queued(&rincron), 0, "{} not excluded", file);
+        }
+
+        std::fs::write(dir.join("kept file.txt"), "").unwrap();
+        rincron.inject_event(&path, "kept file.txt", EventMask::CLOSE_WRITE, 0);
+        assert_eq!(queued(&rincron), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn callbacks_replace_commands() {
+        let dir = test_dir("callbacks");
+        std::fs::write(dir.join("panic"), "").unwrap();
+        std::fs::write(dir.join("a"), "").unwrap();
+
+        // Files are written before the watch, only injected events are seen
+        let mut rincron =
+            rincron_with(&dir, json!({"events": ["CLOSE_WRITE"], "command": "false"}));
+        let path = dir.to_string_lossy().to_string();
+        let called = Arc::new(Mutex::new(Vec::new()));
+        let sink = called.clone();
+
+        rincron.register_callback(
+            &path,
+            Arc::new(move |file: &FileCheck| {
+                sink.lock().unwrap().push(file.path.clone());
+                assert!(!file.path.ends_with("panic"), "callback panicked");
+                true
+            }),
+        );
+
+        for file in ["panic", "a"] {
+            rincron.inject_event(&path, file, EventMask::CLOSE_WRITE, 0);
+            tick_until(&mut rincron, |r| {
+                queued(r) == 0 && r.running_callbacks() == 0
+            });
+        }
+
+        // The panic was reported as a failure and the worker survived it
+        assert_eq!(rincron.metrics.counters.failures, 1);
+        assert_eq!(called.lock().unwrap().len(), 2);
+        assert_eq!(rincron.running_children(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn low_fd_limit_never_holds_back_forever() {
+        let mut rincron = Rincron::init(Options::default()).unwrap();
+        rincron.fd_limit = 16;
+
+        // With the default headroom of 64, only running outputs can justify waiting
+        assert!(rincron.fd_headroom_allows(2));
+
+        let child = Command::new("sleep")
+            .arg("1")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        rincron.child_processes.push(ChildProcess::new(child, None));
+        assert!(!rincron.fd_headroom_allows(2));
+
+        rincron.stop_children();
+        assert!(rincron.fd_headroom_allows(2));
+    }
+
+    /// File names a shell would split, expand or execute if not escaped
+    const PATHOLOGICAL_NAMES: &[&str] = &[
+        "with space",
+        "it's",
+        "\"quoted\"",
+        "$HOME",
+        "${PATH}",
+        "`id`",
+        "$(id)",
+        "new\nline",
+        "tab\there",
+        "ünïcödé €",
+        "a;b|c&d>e",
+        "*",
+        "-n",
+        "back\\slash",
+    ];
+
+    /// Runs a command with a shell, returns its output
+    ///
+    /// # Parameters
+    ///
+    /// * `command`: The command
+    fn shell_output(command: &str) -> String {
+        let output = Command::new("sh").arg("-c").arg(command).output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn escaped_values_reach_the_shell_unchanged() {
+        for name in PATHOLOGICAL_NAMES {
+            let command = format!("printf %s {}", Rincron::escape_value(name));
+            assert_eq!(shell_output(&command), *name, "{}", command);
+        }
+    }
+
+    #[test]
+    fn shell_commands_get_escaped_names() {
+        for name in PATHOLOGICAL_NAMES {
+            let values = [('@', "/tmp/some dir"), ('#', *name), ('&', "CLOSE_WRITE")];
+            let command = Rincron::convert_command(
+                "printf '%s|' $@ $# $& '$$HOME' '$x'",
+                &values,
+                SubstitutionMode::Shell,
+            );
+            let expected = format!("/tmp/some dir|{}|CLOSE_WRITE|$HOME|$x|", name);
+            assert_eq!(shell_output(&command), expected, "{}", command);
+        }
+    }
+
+    #[test]
+    fn argv_templates_get_raw_names() {
+        for name in PATHOLOGICAL_NAMES {
+            let mut fc = FileCheck::new(&format!("/tmp/some dir/{}", name), 0, "");
+            fc.dir = "/tmp/some dir".to_string();
+            fc.file = name.to_string();
+            fc.event = "MOVED_TO".to_string();
+            fc.moved_from = "old name".to_string();
+            fc.argv_template = ["mv", "$@/$<", "$@/$#", "$&", "$$#"]
+                .iter()
+                .map(|a| a.to_string())
+                .collect();
+
+            let expected = [
+                "mv".to_string(),
+                "/tmp/some dir/old name".to_string(),
+                format!("/tmp/some dir/{}", name),
+                "MOVED_TO".to_string(),
+                "$#".to_string(),
+            ];
+            assert_eq!(Rincron::final_argv(&fc), expected);
+        }
+    }
+
+    #[test]
+    fn injected_events_are_queued() {
+        let dir = test_dir("inject");
+        let path = dir.to_string_lossy().to_string();
+        let mut rincron = Rincron::init(Options::default()).unwrap();
+        let config = json!([
+            {
+                "path": &path,
+                "events": ["CLOSE_WRITE"],
+                "file_match": "*.txt",
+                "check_interval": 1,
+                "command": "echo $#"
+            }
+        ]);
+        rincron.load_elements(&config.to_string()).unwrap();
+
+        assert!(!rincron.inject_event("/nowhere", "a.txt", EventMask::CLOSE_WRITE, 0));
+        assert!(rincron.inject_event(&path, "a.log", EventMask::CLOSE_WRITE, 0));
+        assert!(rincron.pending_checks().is_empty());
+
+        rincron.inject_event(&path, "a b.txt", EventMask::CLOSE_WRITE, 0);
+        let checks = rincron.pending_checks();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].cmd, "echo 'a b.txt'");
+        assert_eq!(checks[0].check_interval, 1000);
+        assert!(rincron.pending_executions().is_empty());
+
+        // Without check interval, the file is executed right away
+        let config = json!([{"path": &path, "events": ["CLOSE_WRITE"], "command": "echo $#"}]);
+        rincron.load_elements(&config.to_string()).unwrap();
+        rincron.inject_event(&path, "b.txt", EventMask::CLOSE_WRITE, 0);
+        assert_eq!(rincron.pending_executions().len(), 1);
+        assert_eq!(rincron.pending_executions()[0].cmd, "echo b.txt");
+        assert_eq!(rincron.running_children(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pending_event_policies_apply_to_checked_files() {
+        let dir = test_dir("pending-event");
+        let path = dir.to_string_lossy().to_string();
+
+        for (policy, checks) in [("reset", 1), ("keep", 1), ("queue", 2)] {
+            let mut rincron = rincron_with(
+                &dir,
+                json!({
+                    "events": ["CLOSE_WRITE"],
+                    "check_interval": 1,
+                    "pending_event": policy,
+                    "command": "true"
+                }),
+            );
+
+            rincron.inject_event(&path, "a", EventMask::CLOSE_WRITE, 0);
+            rincron.file_checks[0].tick(600);
+            rincron.inject_event(&path, "a", EventMask::CLOSE_WRITE, 0);
+            assert_eq!(rincron.pending_checks().len(), checks, "{}", policy);
+
+            // Only a reset check waits for a whole interval again
+            let restarted = rincron.pending_checks()[0].next_check == 1000;
+            assert_eq!(restarted, policy == "reset", "{}", policy);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expired_creates_restart_quiet_checks() {
+        let dir = test_dir("quiet-create");
+        let path = dir.to_string_lossy().to_string();
+        let mut rincron = rincron_with(
+            &dir,
+            json!({
+                "events": ["CREATE", "CLOSE_WRITE"],
+                "coalesce_create_close": true,
+                "coalesce_window": 50,
+                "completion": "quiet",
+                "check_interval": 10,
+                "command": "true"
+            }),
+        );
+
+        rincron.inject_event(&path, "a", EventMask::CLOSE_WRITE, 0);
+        rincron.file_checks[0].tick(5000);
+        rincron.inject_event(&path, "a", EventMask::CREATE, 0);
+
+        // The CREATE without CLOSE_WRITE goes to the running quiet check
+        tick_until(&mut rincron, |r| r.pending_creates.is_empty());
+        assert_eq!(rincron.pending_checks().len(), 1);
+        assert!(rincron.pending_checks()[0].next_check > 9000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn substitution_modes_escape_only_for_shells() {
+        let values = [('@', "/srv/my dir"), ('#', "it's \"here\".txt")];
+        let template = "cp $@/$# \"$$out\" $z$";
+
+        assert_eq!(
+            Rincron::convert_command(template, &values, SubstitutionMode::Shell),
+            "cp '/srv/my dir'/'it'\\''s \"here\".txt' \"$out\" $z$"
+        );
+        assert_eq!(
+            Rincron::convert_command(template, &values, SubstitutionMode::Argv),
+            "cp /srv/my dir/it's \"here\".txt \"$out\" $z$"
+        );
+
+        // Values are never substituted again
+        let values = [('#', "$@ $$"), ('@', "dir")];
+        for mode in [SubstitutionMode::Shell, SubstitutionMode::Argv] {
+            let converted = Rincron::convert_command("$#", &values, mode);
+            assert!(converted.contains("$@ $$"), "{}", converted);
+        }
+    }
+
+    #[test]
+    fn phases_step_through_the_pipeline() {
+        let dir = test_dir("phases");
+        std::fs::write(dir.join("a b"), "abc").unwrap();
+        let path = dir.to_string_lossy().to_string();
+        let mut rincron = rincron_with(
+            &dir,
+            json!({
+                "events": ["CLOSE_WRITE"],
+                "check_interval": 0.05,
+                "command": "cp $@/$# $@/$#.done"
+            }),
+        );
+
+        rincron.inject_event(&path, "a b", EventMask::CLOSE_WRITE, 0);
+        assert_eq!(rincron.pending_checks().len(), 1);
+
+        // Executions only take complete files
+        rincron.run_phase(Phase::Executions);
+        assert_eq!(rincron.running_children(), 0);
+
+        // The first check records the size, the next one finds it stable
+        while !rincron.pending_checks().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+            rincron.run_phase(Phase::Ticks);
+            rincron.run_phase(Phase::Checks);
+        }
+        assert_eq!(rincron.pending_executions().len(), 1);
+
+        rincron.run_phase(Phase::Executions);
+        assert!(rincron.pending_executions().is_empty());
+        assert_eq!(rincron.running_children(), 1);
+
+        tick_until(&mut rincron, |r| r.running_children() == 0);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("a b.done")).unwrap(),
+            "abc"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recreated_subdirectories_are_watched_again() {
+        let dir = test_dir("recreated");
+        let sub = dir.join("sub");
+        std::fs::create_dir(&sub).unwrap();
+        let mut rincron = rincron_with(
+            &dir,
+            json!({"events": ["CLOSE_WRITE"], "recursive": true, "command": "true"}),
+        );
+        assert!(rincron.manager.descriptor_of(&sub).is_some());
+
+        // Real events this time, the kernel removes the watch of the deleted directory
+        std::fs::remove_dir(&sub).unwrap();
+        tick_until(&mut rincron, |r| r.manager.descriptor_of(&sub).is_none());
+
+        std::fs::create_dir(&sub).unwrap();
+        tick_until(&mut rincron, |r| r.manager.descriptor_of(&sub).is_some());
+
+        std::fs::write(sub.join("file"), "").unwrap();
+        tick_until(&mut rincron, |r| r.metrics.counters.spawns == 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reloaded_elements_take_their_new_options() {
+        let dir = test_dir("reload");
+        let path = dir.to_string_lossy().to_string();
+        let mut rincron = rincron_with(&dir, json!({"events": ["CLOSE_WRITE"], "command": "old"}));
+        let descriptor = rincron.manager.descriptor_of(&dir).unwrap();
+
+        let config = json!([{
+            "path": &path,
+            "events": ["CLOSE_WRITE"],
+            "command": "new $#",
+            "file_match": "*.txt",
+            "check_interval": 2
+        }]);
+        rincron.load_elements(&config.to_string()).unwrap();
+
+        // The watch is kept, the options are the new ones
+        let elements: Vec<&WatchElement> = rincron.manager.elements().collect();
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0].watch_descriptor, descriptor);
+        assert_eq!(elements[0].command, "new $#");
+        assert_eq!(elements[0].file_match, ["*.txt"]);
+        assert_eq!(elements[0].check_interval, 2000);
+
+        rincron.inject_event(&path, "a.log", EventMask::CLOSE_WRITE, 0);
+        rincron.inject_event(&path, "a.txt", EventMask::CLOSE_WRITE, 0);
+        assert_eq!(rincron.pending_checks().len(), 1);
+        assert_eq!(rincron.pending_checks()[0].cmd, "new a.txt");
+        assert_eq!(rincron.pending_checks()[0].check_interval, 2000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repeated_writes_give_a_single_execution() {
+        let dir = test_dir("repeated");
+        let path = dir.to_string_lossy().to_string();
+        let mut rincron = rincron_with(
+            &dir,
+            json!({
+                "events": ["MODIFY", "CLOSE_WRITE"],
+                "completion": "quiet",
+                "check_interval": 0.2,
+                "command": "true"
+            }),
+        );
+
+        // Each write restarts the quiet period of the single check
+        for _ in 0..5 {
+            rincron.inject_event(&path, "a", EventMask::MODIFY, 0);
+            rincron.tick();
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(rincron.pending_checks().len(), 1);
+        }
+
+        rincron.inject_event(&path, "a", EventMask::CLOSE_WRITE, 0);
+        let last_event = Instant::now();
+        tick_until(&mut rincron, |r| r.metrics.counters.spawns == 1);
+        assert!(last_event.elapsed() >= Duration::from_millis(200));
+
+        tick_until(&mut rincron, |r| r.running_children() == 0);
+        assert!(rincron.pending_checks().is_empty());
+        assert!(rincron.pending_executions().is_empty());
+        assert_eq!(rincron.metrics.counters.spawns, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shrinking_files_run_the_truncate_hook() {
+        let dir = test_dir("truncate");
+        let file = dir.join("a.log");
+        let marker = std::env::temp_dir().join(format!("rincron-{}-truncated", std::process::id()));
+        std::fs::write(&file, "abcdef").unwrap();
+
+        let path = dir.to_string_lossy().to_string();
+        let mut rincron = rincron_with(
+            &dir,
+            json!({
+                "events": ["CLOSE_WRITE"],
+                "check_interval": 60,
+                "on_truncate": format!("echo $# > {}", marker.display()),
+                "command": "true"
+            }),
+        );
+        rincron.inject_event(&path, "a.log", EventMask::CLOSE_WRITE, 0);
+
+        // Checks are forced, the first one records the size
+        rincron.file_checks[0].next_check = 0;
+        rincron.run_phase(Phase::Checks);
+        assert_eq!(rincron.running_children(), 0);
+
+        std::fs::write(&file, "ab").unwrap();
+        rincron.file_checks[0].next_check = 0;
+        rincron.run_phase(Phase::Checks);
+
+        // The hook runs, the check goes on since the file is still being written
+        assert_eq!(rincron.running_children(), 1);
+        assert_eq!(rincron.pending_checks().len(), 1);
+        tick_until(&mut rincron, |r| r.running_children() == 0);
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "a.log\n");
+
+        std::fs::remove_file(&marker).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn completion_checks_read_names_needing_quotes() {
+        let strategies = [
+            ("size", json!("size")),
+            ("mtime", json!("mtime")),
+            ("size-mtime", json!("size_mtime")),
+            ("hash", json!("hash")),
+            ("sampled-hash", json!({"strategy": "hash", "sample": 1})),
+            ("marker", json!({"strategy": "marker", "suffix": ".ready"})),
+        ];
+
+        for (name, completion) in strategies {
+            let dir = test_dir(&format!("quoted-{}", name));
+            std::fs::write(dir.join("a b"), "abc").unwrap();
+            std::fs::write(dir.join("a b.ready"), "").unwrap();
+            let path = dir.to_string_lossy().to_string();
+            let mut rincron = rincron_with(
+                &dir,
+                json!({
+                    "events": ["CLOSE_WRITE"],
+                    "check_interval": 60,
+                    "completion": completion,
+                    "command": "true"
+                }),
+            );
+            rincron.inject_event(&path, "a b", EventMask::CLOSE_WRITE, 0);
+
+            // Checks are forced, the file is found stable by the second one at most
+            for _ in 0..2 {
+                if let Some(fc) = rincron.file_checks.first_mut() {
+                    fc.next_check = 0;
+                }
+                rincron.run_phase(Phase::Checks);
+            }
+
+            assert!(rincron.pending_checks().is_empty(), "{}", name);
+            assert_eq!(rincron.pending_executions().len(), 1, "{}", name);
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn inode_dedup_only_drops_other_hardlinks() {
+        let dir = test_dir("dedup-inode");
+        std::fs::write(dir.join("a"), "abc").unwrap();
+        std::fs::hard_link(dir.join("a"), dir.join("b")).unwrap();
+        let path = dir.to_string_lossy().to_string();
+        let mut rincron = rincron_with(
+            &dir,
+            json!({
+                "events": ["CREATE", "CLOSE_WRITE"],
+                "dedup_inode_window": 60000,
+                "command": "true"
+            }),
+        );
+
+        rincron.inject_event(&path, "a", EventMask::CREATE, 0);
+        rincron.inject_event(&path, "a", EventMask::CLOSE_WRITE, 0);
+        rincron.inject_event(&path, "b", EventMask::CLOSE_WRITE, 0);
+
+        let events: Vec<(&str, &str)> = rincron
+            .pending_executions()
+            .iter()
+            .map(|fc| (fc.file.as_str(), fc.event.as_str()))
+            .collect();
+        assert_eq!(events, [("a", "CREATE"), ("a", "CLOSE_WRITE")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Creates a failing file check quarantined at its first failure
+    ///
+    /// # Parameters
+    ///
+    /// * `dir`: The directory `$@` of the file
+    /// * `file`: The name `$#` of the file, empty for a directory target
+    /// * `quarantine`: The quarantine directory
+    fn failing_check(dir: &Path, file: &str, quarantine: &Path) -> FileCheck {
+        let mut fc = FileCheck::new(&dir.join(file).to_string_lossy(), 0, "false");
+        fc.dir = dir.to_string_lossy().to_string();
+        fc.file = file.to_string();
+        fc.quarantine = Some(quarantine.to_string_lossy().to_string());
+        fc.max_failures = 1;
+        fc
+    }
+
+    #[test]
+    fn relative_names_are_quarantined_with_their_subdirectories() {
+        let dir = test_dir("quarantine-relative");
+        let quarantine = test_dir("quarantine-relative-q");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/x"), "abc").unwrap();
+
+        let fc = failing_check(&dir, "sub/x", &quarantine);
+        let mut metrics = Metrics::default();
+        assert!(Rincron::record_failure(
+            &mut HashMap::new(),
+            &mut metrics,
+            &fc,
+            "test"
+        ));
+
+        assert!(!dir.join("sub/x").exists());
+        assert_eq!(
+            std::fs::read_to_string(quarantine.join("sub/x")).unwrap(),
+            "abc"
+        );
+        assert!(quarantine.join("sub/x.reason").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&quarantine).unwrap();
+    }
+
+    #[test]
+    fn directory_targets_are_quarantined_under_their_name() {
+        let dir = test_dir("quarantine-dir");
+        let quarantine = test_dir("quarantine-dir-q");
+        let target = dir.join("batch");
+        std::fs::create_dir(&target).unwrap();
+        std::fs::write(target.join("x"), "abc").unwrap();
+
+        let fc = failing_check(&target, "", &quarantine);
+        let mut metrics = Metrics::default();
+        assert!(Rincron::record_failure(
+            &mut HashMap::new(),
+            &mut metrics,
+            &fc,
+            "test"
+        ));
+
+        assert!(!target.exists());
+        assert_eq!(
+            std::fs::read_to_string(quarantine.join("batch/x")).unwrap(),
+            "abc"
+        );
+        assert!(quarantine.join("batch.reason").exists());
+
+        // The root has no name, it can't be moved anywhere
+        let fc = failing_check(Path::new("/"), "", &quarantine);
+        assert!(!Rincron::record_failure(
+            &mut HashMap::new(),
+            &mut metrics,
+            &fc,
+            "test"
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&quarantine).unwrap();
+    }
+
+    #[test]
+    fn once_per_file_remembers_only_successes() {
+        let dir = test_dir("once-per-file");
+        let marker = test_dir("once-per-file-ok").join("ok");
+        std::fs::write(dir.join("a"), "abc").unwrap();
+        let path = dir.to_string_lossy().to_string();
+        let mut rincron = rincron_with(
+            &dir,
+            json!({
+                "events": ["CLOSE_WRITE"],
+                "once_per_file": true,
+                "command": format!("test -e {}", marker.display())
+            }),
+        );
+        let done = |r: &Rincron| r.pending_executions().is_empty() && r.running_children() == 0;
+
+        // A failed command leaves the file unprocessed
+        rincron.inject_event(&path, "a", EventMask::CLOSE_WRITE, 0);
+        tick_until(&mut rincron, done);
+        assert!(rincron.processed_files.is_empty());
+
+        std::fs::write(&marker, "").unwrap();
+        rincron.inject_event(&path, "a", EventMask::CLOSE_WRITE, 0);
+        assert_eq!(rincron.pending_executions().len(), 1);
+        tick_until(&mut rincron, done);
+        assert!(rincron
+            .processed_files
+            .contains(dir.join("a").to_string_lossy().as_ref()));
+
+        rincron.inject_event(&path, "a", EventMask::CLOSE_WRITE, 0);
+        assert!(rincron.pending_executions().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(marker.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn verify_commands_get_the_event_environment() {
+        let dir = test_dir("verify-env");
+        std::fs::write(dir.join("a b"), "abc").unwrap();
+        let path = dir.to_string_lossy().to_string();
+        let mut rincron = rincron_with(
+            &dir,
+            json!({
+                "events": ["CLOSE_WRITE"],
+                "check_interval": 60,
+                "verify": "test \"$RINCRON_FILE\" = 'a b' -a \"$RINCRON_EVENT\" = CLOSE_WRITE",
+                "verify_failure": "drop",
+                "command": "true"
+            }),
+        );
+        rincron.inject_event(&path, "a b", EventMask::CLOSE_WRITE, 0);
+
+        // A dropped file would leave nothing to execute
+        for _ in 0..2 {
+            if let Some(fc) = rincron.file_checks.first_mut() {
+                fc.next_check = 0;
+            }
+            rincron.run_phase(Phase::Checks);
         }
+        assert!(rincron.pending_checks().is_empty());
+        assert_eq!(rincron.pending_executions().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
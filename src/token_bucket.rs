@@ -0,0 +1,62 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+/// A token bucket limiting a rate of events
+pub struct TokenBucket {
+    /// The tokens available, an event takes one
+    tokens: f64,
+
+    /// The tokens added each second
+    pub rate: f64,
+
+    /// The maximum number of tokens
+    pub burst: f64,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    ///
+    /// # Parameters
+    ///
+    /// * `rate`: The tokens added each second
+    /// * `burst`: The maximum number of tokens
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            tokens: burst,
+            rate,
+            burst,
+        }
+    }
+
+    /// Adds the tokens earned during a time
+    ///
+    /// # Parameters
+    ///
+    /// * `elapsed`: The time passed in milliseconds
+    pub fn refill(&mut self, elapsed: i64) {
+        self.tokens = (self.tokens + self.rate * elapsed as f64 / 1000.0).min(self.burst);
+    }
+
+    /// Takes a token, returns `false` if none is available
+    pub fn take(&mut self) -> bool {
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
@@ -14,60 +14,1307 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use inotify::{Inotify, WatchDescriptor, WatchMask};
-use serde_json::{Number, Value};
+use crate::content_hasher::HashAlgo;
+use crate::rincron::Rincron;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use regex::Regex;
+use serde_json::{json, Number, Value};
 use simple_error::bail;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use wildmatch::WildMatch;
 
-/// Inotify watch element
+/// What to do when a verification command fails
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum VerifyFailure {
+    /// The stability check is restarted
+    Retry,
+
+    /// The file is dropped
+    Drop,
+}
+
+/// What to do when the registration command of an element fails
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum RegisterFailure {
+    /// The directory is watched anyway
+    Watch,
+
+    /// The element is not watched until the next reload
+    Skip,
+}
+
+/// The strategy telling when a file is complete and the command can be executed
+#[derive(Clone, PartialEq)]
+pub enum Completion {
+    /// The command is executed right away
+    Immediate,
+
+    /// The file size must not change between two checks
+    Size,
+
+    /// The file modification time must not change between two checks
+    Mtime,
+
+    /// Neither the file size nor its modification time must change between two checks
+    SizeMtime,
+
+    /// The file content must not change between two checks, only the given number of bytes at
+    /// its start and at its end with its size are hashed if not 0
+    Hash(u64),
+
+    /// A marker file, named like the file with a suffix, must exist
+    Marker(String),
+
+    /// No new event must happen for the file during a check interval
+    Quiet,
+
+    /// An extended attribute, given by its name, must have the given value
+    Xattr(String, String),
+}
+
+/// Where to publish event messages
 #[derive(Clone, Eq, PartialEq)]
+pub struct PublishConfig {
+    /// The NATS server address as `host:port`
+    pub server: String,
+
+    /// The NATS subject
+    pub subject: String,
+}
+
+/// A compiled pattern of processed files
+#[derive(Clone)]
+pub enum FileMatcher {
+    /// Patterns with `*` and `?` wildcards, one of them must match the name
+    Wildcard(Vec<WildMatch>),
+
+    /// A regular expression, matched against the name as is
+    Regex(Regex),
+}
+
+impl FileMatcher {
+    /// Checks if a file name matches the pattern
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The file name, or its path relative to the watched path
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            FileMatcher::Wildcard(patterns) => patterns.iter().any(|m| m.matches(name)),
+            FileMatcher::Regex(r) => r.is_match(name),
+        }
+    }
+}
+
+impl PartialEq for FileMatcher {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FileMatcher::Wildcard(a), FileMatcher::Wildcard(b)) => a == b,
+            (FileMatcher::Regex(a), FileMatcher::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+/// Information about where an element is read
+pub struct ParseContext {
+    /// The config file path
+    pub source: String,
+
+    /// The directory relative paths are resolved against
+    pub base_dir: PathBuf,
+
+    /// Whether elements with unknown keys are rejected
+    pub strict: bool,
+
+    /// The profile the config file belongs to, empty without profiles
+    pub profile: String,
+}
+
+impl ParseContext {
+    /// Resolves a path relative to the base directory
+    ///
+    /// Absolute paths are returned as is
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: The path given by the user
+    pub fn resolve(&self, path: &str) -> String {
+        if Path::new(path).is_absolute() {
+            return path.to_string();
+        }
+
+        let resolved = self.base_dir.join(path).to_string_lossy().to_string();
+        log!(
+            info,
+            path = &path,
+            "Relative path {} in {} resolved to {}",
+            path,
+            &self.source,
+            &resolved
+        );
+        resolved
+    }
+}
+
+/// The order of files found by an initial scan
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum ScanOrder {
+    /// The order given by the filesystem
+    Filesystem,
+
+    /// Oldest modification time first
+    OldestFirst,
+
+    /// Newest modification time first
+    NewestFirst,
+}
+
+/// How events on symlinks are handled
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Symlinks are processed like other files
+    Process,
+
+    /// Events on symlinks are discarded
+    Ignore,
+
+    /// Only events on symlinks are processed
+    Only,
+}
+
+/// What `$#` gives in commands
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum FilenameMode {
+    /// The file name
+    Basename,
+
+    /// The path of the file relative to the watched path
+    Relative,
+}
+
+/// What a command is executed for
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum ActionTarget {
+    /// Each file of an event
+    File,
+
+    /// The directory of the files of events
+    Dir,
+}
+
+/// What an event does to the check of a file already waiting for completion
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum PendingEventPolicy {
+    /// The check starts again, the file is still being written
+    Reset,
+
+    /// The check goes on, the event is discarded
+    Keep,
+
+    /// Another check is added for the same file
+    Queue,
+}
+
+/// How placeholder values are inserted in a command
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum SubstitutionMode {
+    /// The command is run by a shell, values are escaped
+    Shell,
+
+    /// Each token is a program argument, values are inserted as is
+    Argv,
+}
+
+/// What happens to running commands when rincron exits
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum ShutdownPolicy {
+    /// The command may finish during the grace period
+    Wait,
+
+    /// The command receives SIGTERM
+    Terminate,
+
+    /// The command receives SIGKILL
+    Kill,
+}
+
+/// The timezone of date placeholders
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum DateTimezone {
+    /// The local timezone
+    Local,
+
+    /// Coordinated universal time
+    Utc,
+}
+
+/// The time used by date placeholders
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum DateSource {
+    /// The command execution time
+    Now,
+
+    /// The file modification time
+    Mtime,
+}
+
+/// Inotify watch element
+#[derive(Clone, PartialEq)]
 pub struct WatchElement {
     /// The inotify WatchDescriptor
     pub watch_descriptor: WatchDescriptor,
 
-    /// The path string given by the user
-    pub path: String,
+    /// The profile the element was loaded from, empty without profiles
+    pub profile: String,
+
+    /// The config file the element was loaded from
+    pub source: String,
+
+    /// The path string given by the user
+    pub path: String,
+
+    /// The command string, empty if the command is given as an argument list
+    pub command: String,
+
+    /// The program and its arguments, executed without shell, empty if the command is a string
+    pub argv: Vec<String>,
+
+    /// Where to publish event messages
+    pub publish: Option<PublishConfig>,
+
+    /// The commands to use instead of `command` for specific file extensions
+    pub command_by_ext: HashMap<String, String>,
+
+    /// The commands by event name, when `command` is given for each event
+    pub command_by_event: BTreeMap<String, String>,
+
+    /// The environment variables set for commands
+    pub env: HashMap<String, String>,
+
+    /// Whether commands start with an empty environment instead of rincron's one
+    pub clean_env: bool,
+
+    /// The interpreter of commands, `none` to execute them without shell, empty for the default
+    pub shell: String,
+
+    /// The masks
+    pub mask: WatchMask,
+
+    /// The file_match option, any of these patterns matches
+    pub file_match: Vec<String>,
+
+    /// The file_match_regex option, taking precedence over `file_match`
+    pub file_match_regex: String,
+
+    /// The compiled file_match or file_match_regex pattern, `None` if every file matches
+    pub file_matcher: Option<FileMatcher>,
+
+    /// Whether `file_match` applies to the path relative to the watched path instead of the
+    /// file name
+    pub match_relative_path: bool,
+
+    /// What `$#` gives in commands
+    pub filename_mode: FilenameMode,
+
+    /// The only file names processed, any if empty
+    pub files: Vec<String>,
+
+    /// The exclude option, the patterns of files to ignore given by the user
+    pub exclude_patterns: Vec<String>,
+
+    /// The compiled patterns of files to ignore, with temporary files ones if ignored
+    pub exclude: Vec<WildMatch>,
+
+    /// The time in milliseconds during which events on an already processed inode are ignored,
+    /// 0 if disabled
+    pub dedup_inode_window: u64,
+
+    /// Whether the command is executed only once for each file
+    pub once_per_file: bool,
+
+    /// Whether existing files are processed when the element is added
+    pub initial_scan: bool,
+
+    /// The order of files found by the initial scan
+    pub scan_order: ScanOrder,
+
+    /// Whether only events on directories are processed
+    pub dir_events_only: bool,
+
+    /// Whether subdirectories of the path are watched too, at any depth
+    pub recursive: bool,
+
+    /// Whether the command is executed once per subdirectory of the path, when no event
+    /// happened in it for `settle_window`, instead of once per file
+    pub trigger_once_per_dir: bool,
+
+    /// The time in milliseconds without event in a subdirectory before its command is executed
+    pub settle_window: u64,
+
+    /// How events on symlinks are handled
+    pub symlinks: SymlinkPolicy,
+
+    /// Whether attributes of files are cached to give their previous values on ATTRIB events
+    pub track_attributes: bool,
+
+    /// Whether a oneshot watch is added again once its event fired
+    pub rearm: bool,
+
+    /// The maximum number of events processed each second, 0 if unlimited
+    pub max_rate: f64,
+
+    /// The number of events processed at once before the rate limit applies
+    pub max_burst: f64,
+
+    /// The time interval in milliseconds betweek size checks
+    pub check_interval: i64,
+
+    /// The strategy telling when a file is complete
+    pub completion: Completion,
+
+    /// What an event on a file already checked does
+    pub pending_event: PendingEventPolicy,
+
+    /// Whether the command and the completion check are for the file or its directory
+    pub action_target: ActionTarget,
+
+    /// The algorithm hashing file contents for the hash completion strategy
+    pub hash_algo: HashAlgo,
+
+    /// Whether a file must not be open for writing by any process to be complete
+    pub require_closed: bool,
+
+    /// The command to execute once a file is stable, the main command is executed only if it
+    /// succeeds
+    pub verify: String,
+
+    /// The behavior when the verification command fails
+    pub verify_failure: VerifyFailure,
+
+    /// The command whose output replaces `${route}` in the command, empty if none
+    pub route_command: String,
+
+    /// The value of `${route}` when the route command fails or prints nothing, the execution
+    /// fails if `None`
+    pub route_default: Option<String>,
+
+    /// The command executed once when the element is added, before its watch
+    pub on_register: String,
+
+    /// The behavior when the registration command fails
+    pub on_register_failure: RegisterFailure,
+
+    /// The command to execute when the command succeeds
+    pub on_success: String,
+
+    /// The command to execute when the command fails
+    pub on_failure: String,
+
+    /// The command to execute when a checked file gets smaller
+    pub on_truncate: String,
+
+    /// The command executed for events on the watched directory itself, `None` if they are
+    /// discarded
+    pub self_command: Option<String>,
+
+    /// Whether the command outputs are written to the log
+    pub capture_output: bool,
+
+    /// The file command outputs are appended to, with the wildcards of the command, none if
+    /// empty
+    pub log_file: String,
+
+    /// The time in milliseconds a file must be handled in from its first event, 0 if disabled
+    pub lifecycle_timeout: u64,
+
+    /// The time in milliseconds a command can run before being terminated, 0 if unlimited
+    pub timeout: u64,
+
+    /// The time in milliseconds a terminated command has to exit before being killed
+    pub kill_grace: u64,
+
+    /// Whether the command output lines are processed as new files
+    pub chain_output: bool,
+
+    /// The maximum number of chained executions from an event
+    pub max_chain_depth: u32,
+
+    /// What happens to running commands when rincron exits
+    pub on_shutdown: ShutdownPolicy,
+
+    /// What happens to running commands working in the watched directory when it's removed
+    pub on_cwd_removed: ShutdownPolicy,
+
+    /// The timezone of date placeholders
+    pub date_timezone: DateTimezone,
+
+    /// The time used by date placeholders
+    pub date_source: DateSource,
+
+    /// The minimum free space in bytes needed to execute the command, 0 if disabled
+    pub min_free_space: u64,
+
+    /// The path of the filesystem where free space is checked
+    pub free_space_path: String,
+
+    /// The directory where repeatedly failing files are moved, if any
+    pub quarantine: Option<String>,
+
+    /// The number of failures before a file is quarantined
+    pub max_failures: u32,
+
+    /// The number of times a failed command is executed again
+    pub retries: u32,
+
+    /// The time in milliseconds before a failed command is executed again
+    pub retry_delay: u64,
+
+    /// The exit codes a failed command is executed again for, any if empty
+    pub retry_on_codes: Vec<i32>,
+
+    /// Whether a CREATE event is ignored when a CLOSE_WRITE follows for the same file
+    pub coalesce_create_close: bool,
+
+    /// The time in milliseconds a CREATE event waits for a CLOSE_WRITE
+    pub coalesce_window: i64,
+
+    /// Whether the MOVED_FROM and MOVED_TO events of a rename give a single command
+    pub pair_moves: bool,
+
+    /// The time in milliseconds a MOVED_FROM event waits for its MOVED_TO
+    pub pair_window: i64,
+
+    /// The group sharing a limit of running commands with other elements, empty if none
+    pub concurrency_group: String,
+
+    /// The maximum number of running commands of the group
+    pub group_limit: usize,
+}
+
+impl WatchElement {
+    /// The temporary file patterns ignored with `ignore_temp_files`
+    pub const TEMP_FILE_PATTERNS: &'static [&'static str] = &[
+        "*.tmp",
+        "*.temp",
+        "*.part",
+        "*.partial",
+        "*.crdownload",
+        "*.swp",
+        "*.swx",
+        "*~",
+        ".#*",
+        ".goutputstream-*",
+        ".~lock.*#",
+    ];
+
+    /// The longest delay before executing a failed command again, one day in milliseconds
+    const MAX_RETRY_DELAY: u64 = 86_400_000;
+
+    /// The keys allowed in an element, keys starting with `_` are always allowed
+    const KNOWN_KEYS: &'static [&'static str] = &[
+        "path",
+        "dir",
+        "events",
+        "command",
+        "command_by_ext",
+        "env",
+        "clean_env",
+        "shell",
+        "self_command",
+        "publish",
+        "file_match",
+        "file_match_regex",
+        "match_relative_path",
+        "filename_mode",
+        "files",
+        "manifest",
+        "dir_events_only",
+        "recursive",
+        "trigger_once_per_dir",
+        "settle_window",
+        "symlinks",
+        "track_attributes",
+        "rearm",
+        "max_rate",
+        "max_burst",
+        "ignore_temp_files",
+        "exclude",
+        "dedup_inode_window",
+        "once_per_file",
+        "initial_scan",
+        "scan_order",
+        "check_interval",
+        "debounce",
+        "completion",
+        "pending_event",
+        "action_target",
+        "hash_algo",
+        "require_closed",
+        "verify",
+        "verify_failure",
+        "route_command",
+        "route_default",
+        "on_register",
+        "on_register_failure",
+        "on_success",
+        "on_failure",
+        "on_truncate",
+        "capture_output",
+        "log_file",
+        "lifecycle_timeout",
+        "timeout",
+        "kill_grace",
+        "ignore_pids",
+        "chain_output",
+        "max_chain_depth",
+        "on_shutdown",
+        "on_cwd_removed",
+        "date_timezone",
+        "date_source",
+        "min_free_space",
+        "free_space_path",
+        "quarantine",
+        "max_failures",
+        "retries",
+        "retry_delay",
+        "retry_on_codes",
+        "coalesce_create_close",
+        "coalesce_window",
+        "pair_moves",
+        "pair_window",
+        "concurrency_group",
+        "group_limit",
+    ];
+
+    /// Expands an element with a manifest into one element for each directory of the listed
+    /// files, restricted to these files, an element without manifest is returned as is
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The json object
+    /// * `context`: Where the element is read
+    pub fn expand_manifest(
+        value: &Value,
+        context: &ParseContext,
+    ) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let manifest = match value.get("manifest") {
+            None => return Ok(vec![value.clone()]),
+            Some(v) => match v.as_str() {
+                Some(m) => context.resolve(m),
+                None => bail!("\"manifest\" must be a string"),
+            },
+        };
+
+        if value.get("path").is_some() || value.get("files").is_some() {
+            bail!("\"manifest\" can't be used with \"path\" or \"files\"");
+        }
+
+        let content = match std::fs::read_to_string(&manifest) {
+            Ok(v) => v,
+            Err(e) => bail!("Unable to read manifest {}: {}", &manifest, e),
+        };
+
+        // Listed paths are relative to the manifest itself
+        let manifest_context = ParseContext {
+            source: manifest.clone(),
+            base_dir: Path::new(&manifest)
+                .parent()
+                .map(|p| p.to_path_buf())
+                .unwrap_or_else(|| PathBuf::from("/")),
+            strict: context.strict,
+            profile: context.profile.clone(),
+        };
+
+        // A directory can only be watched once, its files are grouped
+        let mut directories: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        for line in content.lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let path = PathBuf::from(manifest_context.resolve(line));
+
+            match (path.parent(), path.file_name()) {
+                (Some(directory), Some(file)) => directories
+                    .entry(directory.to_string_lossy().to_string())
+                    .or_default()
+                    .push(file.to_string_lossy().to_string()),
+                _ => log!(
+                    warning,
+                    "Warning: invalid path {} in manifest {}",
+                    line,
+                    &manifest
+                ),
+            }
+        }
+
+        let mut values = Vec::new();
+
+        for (directory, files) in directories {
+            if !Path::new(&directory).is_dir() {
+                log!(
+                    warning,
+                    "Warning: directory {} of manifest {} doesn't exist, its files are not watched until a reload",
+                    &directory,
+                    &manifest
+                );
+                continue;
+            }
+
+            let mut expanded = value.clone();
+            let object = expanded.as_object_mut().unwrap();
+            object.remove("manifest");
+            object.insert("path".to_string(), Value::from(directory));
+            object.insert("files".to_string(), Value::from(files));
+            values.push(expanded);
+        }
+
+        Ok(values)
+    }
+
+    /// Checks that every key of a config block is known
+    ///
+    /// Keys starting with `_` are comments or metadata and are ignored
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The json object
+    /// * `known`: The keys allowed in the block
+    /// * `strict`: Reject the block if a key is unknown instead of printing a warning
+    pub(crate) fn check_keys(
+        value: &Value,
+        known: &[&str],
+        strict: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for key in value.as_object().unwrap().keys() {
+            if key.starts_with('_') || known.contains(&key.as_str()) {
+                continue;
+            }
+
+            if strict {
+                bail!("Unknown key \"{}\"", key);
+            }
+
+            log!(warning, "Warning: unknown key \"{}\" ignored", key);
+        }
+
+        Ok(())
+    }
+
+    /// Converts an event string to a WatchMask
+    ///
+    /// Both `EVENT` and `IN_EVENT` can be used
+    fn event_name_to_value(name: &str) -> Option<WatchMask> {
+        match name {
+            "ATTRIB" | "IN_ATTRIB" => Some(WatchMask::ATTRIB),
+            "CLOSE_WRITE" | "IN_CLOSE_WRITE" => Some(WatchMask::CLOSE_WRITE),
+            "CLOSE_NOWRITE" | "IN_CLOSE_NOWRITE" => Some(WatchMask::CLOSE_NOWRITE),
+            "CREATE" | "IN_CREATE" => Some(WatchMask::CREATE),
+            "DELETE" | "IN_DELETE" => Some(WatchMask::DELETE),
+            "DELETE_SELF" | "IN_DELETE_SELF" => Some(WatchMask::DELETE_SELF),
+            "MODIFY" | "IN_MODIFY" => Some(WatchMask::MODIFY),
+            "MOVE_SELF" | "IN_MOVE_SELF" => Some(WatchMask::MOVE_SELF),
+            "MOVED_FROM" | "IN_MOVED_FROM" => Some(WatchMask::MOVED_FROM),
+            "MOVED_TO" | "IN_MOVED_TO" => Some(WatchMask::MOVED_TO),
+            "OPEN" | "IN_OPEN" => Some(WatchMask::OPEN),
+            "ALL_EVENTS" | "IN_ALL_EVENTS" => Some(WatchMask::ALL_EVENTS),
+            "MOVE" | "IN_MOVE" => Some(WatchMask::MOVE),
+            "CLOSE" | "IN_CLOSE" => Some(WatchMask::CLOSE),
+            "DONT_FOLLOW" | "IN_DONT_FOLLOW" => Some(WatchMask::DONT_FOLLOW),
+            "EXCL_UNLINK" | "IN_EXCL_UNLINK" => Some(WatchMask::EXCL_UNLINK),
+            "MASK_ADD" | "IN_MASK_ADD" => Some(WatchMask::MASK_ADD),
+            "ONESHOT" | "IN_ONESHOT" => Some(WatchMask::ONESHOT),
+            "ONLYDIR" | "IN_ONLYDIR" => Some(WatchMask::ONLYDIR),
+            _ => None,
+        }
+    }
+
+    /// Converts a size to bytes
+    ///
+    /// The size can be a number of bytes or a string with a `K`, `M`, `G` or `T` suffix
+    /// (powers of 1024)
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The json value
+    fn parse_size(value: &Value) -> Result<u64, Box<dyn std::error::Error>> {
+        if let Some(v) = value.as_u64() {
+            return Ok(v);
+        }
+
+        if !value.is_string() {
+            bail!("A size must be a positive integer or a string");
+        }
+
+        let size = value.as_str().unwrap().trim();
+        let (number, multiplier) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&size[..size.len() - 1], 1u64 << 10),
+            Some('M') => (&size[..size.len() - 1], 1u64 << 20),
+            Some('G') => (&size[..size.len() - 1], 1u64 << 30),
+            Some('T') => (&size[..size.len() - 1], 1u64 << 40),
+            _ => (size, 1),
+        };
+
+        match number
+            .trim()
+            .parse::<u64>()
+            .map(|v| v.checked_mul(multiplier))
+        {
+            Ok(Some(v)) => Ok(v),
+            Ok(None) => bail!("Size too large: {}", size),
+            Err(_) => bail!("Invalid size: {}", size),
+        }
+    }
+
+    /// The placeholders resolved when a command is executed, never taken from the environment
+    const EXECUTION_PLACEHOLDERS: &'static [&'static str] =
+        &["year", "month", "day", "hour", "minute", "second", "route"];
+
+    /// Replaces `${VAR}` placeholders with environment variables
+    ///
+    /// `$$` is kept for the command substitution, so `$${VAR}` reaches the shell as `${VAR}`.
+    /// Placeholders resolved at execution (dates, route, `RINCRON_*` and `env` variables) and
+    /// shell expressions like `${VAR:-default}` are kept as is
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The config string
+    /// * `env`: The variables given to commands by the element
+    fn expand_env(
+        value: &str,
+        env: &HashMap<String, String>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let mut expanded = String::with_capacity(value.len());
+        let mut rest = value;
+
+        while let Some(start) = rest.find('$') {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if rest.starts_with("$$") {
+                expanded.push_str("$$");
+                rest = &rest[2..];
+                continue;
+            }
+
+            let name = match rest
+                .strip_prefix("${")
+                .and_then(|r| r.find('}').map(|e| &r[..e]))
+            {
+                Some(v) => v,
+                None => {
+                    expanded.push('$');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+
+            let is_variable = !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+            if !is_variable
+                || Self::EXECUTION_PLACEHOLDERS.contains(&name)
+                || name.starts_with("RINCRON_")
+                || env.contains_key(name)
+            {
+                expanded.push_str("${");
+                rest = &rest[2..];
+                continue;
+            }
+
+            match std::env::var(name) {
+                Ok(v) => expanded.push_str(&v),
+                Err(_) => bail!("Undefined environment variable {} in {}", name, value),
+            }
+
+            rest = &rest[name.len() + 3..];
+        }
+
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Converts a duration in seconds to milliseconds
+    ///
+    /// The duration can be an integer or a decimal number of seconds, like `0.5`
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The json value
+    /// * `name`: The option name, for errors
+    fn parse_seconds(value: &Value, name: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        match value.as_f64() {
+            Some(v) if v >= 0.0 && v.is_finite() => Ok((v * 1000.0).round() as u64),
+            _ => bail!("\"{}\" must be a positive number of seconds", name),
+        }
+    }
+
+    /// Converts a duration in milliseconds to seconds for a config, an integer when possible
+    ///
+    /// # Parameters
+    ///
+    /// * `millis`: The duration in milliseconds
+    fn seconds_value(millis: u64) -> Value {
+        match millis % 1000 {
+            0 => Value::from(millis / 1000),
+            _ => Value::from(millis as f64 / 1000.0),
+        }
+    }
+
+    /// Converts an event mask to a readable name
+    ///
+    /// If several events are in the mask, names are separated by `|`
+    ///
+    /// # Parameters
+    ///
+    /// * `mask`: The event mask
+    pub fn event_value_to_name(mask: EventMask) -> String {
+        Self::EVENT_NAMES
+            .iter()
+            .filter(|(value, _)| mask.contains(*value))
+            .map(|(_, name)| *name)
+            .collect::<Vec<&str>>()
+            .join("|")
+    }
+
+    /// Converts a name given by [`Self::event_value_to_name`] back to an event mask
+    ///
+    /// # Parameters
+    ///
+    /// * `name`: The event names, separated by `|`
+    pub fn event_name_to_mask(name: &str) -> EventMask {
+        name.split('|')
+            .filter_map(|n| Self::EVENT_NAMES.iter().find(|(_, v)| *v == n))
+            .fold(EventMask::empty(), |mask, (value, _)| mask | *value)
+    }
+
+    /// The names of events read from inotify
+    const EVENT_NAMES: &'static [(EventMask, &'static str)] = &[
+        (EventMask::ACCESS, "ACCESS"),
+        (EventMask::ATTRIB, "ATTRIB"),
+        (EventMask::CLOSE_WRITE, "CLOSE_WRITE"),
+        (EventMask::CLOSE_NOWRITE, "CLOSE_NOWRITE"),
+        (EventMask::CREATE, "CREATE"),
+        (EventMask::DELETE, "DELETE"),
+        (EventMask::DELETE_SELF, "DELETE_SELF"),
+        (EventMask::MODIFY, "MODIFY"),
+        (EventMask::MOVE_SELF, "MOVE_SELF"),
+        (EventMask::MOVED_FROM, "MOVED_FROM"),
+        (EventMask::MOVED_TO, "MOVED_TO"),
+        (EventMask::OPEN, "OPEN"),
+        (EventMask::IGNORED, "IGNORED"),
+        (EventMask::ISDIR, "ISDIR"),
+        (EventMask::Q_OVERFLOW, "Q_OVERFLOW"),
+        (EventMask::UNMOUNT, "UNMOUNT"),
+    ];
+
+    /// Returns the command given for an event, if `command` is given for each event
+    ///
+    /// The most specific event name wins, `CLOSE_WRITE` before `CLOSE` before `ALL_EVENTS`
+    ///
+    /// # Parameters
+    ///
+    /// * `mask`: The event mask, empty for files processed without event
+    pub fn event_command(&self, mask: EventMask) -> Option<&String> {
+        self.command_by_event
+            .iter()
+            .filter_map(|(name, command)| {
+                Self::event_name_to_value(name)
+                    .filter(|m| m.bits() & mask.bits() != 0)
+                    .map(|m| (m.bits().count_ones(), command))
+            })
+            .min_by_key(|(events, _)| *events)
+            .map(|(_, command)| command)
+    }
+
+    /// Reads the publish configuration
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The json value of the element
+    fn parse_publish(value: &Value) -> Result<Option<PublishConfig>, Box<dyn std::error::Error>> {
+        let publish = match value.get("publish") {
+            None => return Ok(None),
+            Some(v) => v,
+        };
+
+        if cfg!(not(feature = "nats")) {
+            bail!("\"publish\" needs rincron-mini to be built with the nats feature");
+        }
+
+        let server = publish.get("server").and_then(|v| v.as_str());
+        let subject = publish.get("subject").and_then(|v| v.as_str());
+
+        if server.is_none() || subject.is_none() {
+            bail!("\"publish\" needs a \"server\" string and a \"subject\" string");
+        }
+
+        // The subject is sent as is in the protocol line, the server would refuse every message
+        if subject.unwrap().is_empty() || subject.unwrap().contains(char::is_whitespace) {
+            bail!("Invalid NATS subject: {:?}", subject.unwrap());
+        }
+
+        Ok(Some(PublishConfig {
+            server: server.unwrap().to_string(),
+            subject: subject.unwrap().to_string(),
+        }))
+    }
+
+    /// Reads the completion strategy
+    ///
+    /// The strategy can be a string or an object with a `strategy` key and strategy options.
+    /// Without strategy, the size is checked if a check interval is given
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The json value of the element
+    /// * `check_interval`: The check interval, may be overridden by the strategy options
+    fn parse_completion(
+        value: &Value,
+        check_interval: &mut i64,
+    ) -> Result<Completion, Box<dyn std::error::Error>> {
+        let completion = match value.get("completion") {
+            None => {
+                if *check_interval == 0 {
+                    return Ok(Completion::Immediate);
+                }
+
+                return Ok(Completion::Size);
+            }
+            Some(v) => v,
+        };
+
+        let (strategy, options) = if let Some(v) = completion.as_str() {
+            (v, None)
+        } else if let Some(v) = completion.get("strategy").and_then(|v| v.as_str()) {
+            (v, Some(completion))
+        } else {
+            bail!("\"completion\" must be a string or an object with a \"strategy\" string");
+        };
+
+        if let Some(v) = options.and_then(|o| o.get("interval")) {
+            *check_interval = Self::parse_seconds(v, "interval")? as i64;
+        }
+
+        let completion = match strategy {
+            "immediate" => return Ok(Completion::Immediate),
+            "size" => Completion::Size,
+            "mtime" => Completion::Mtime,
+            "size_mtime" => Completion::SizeMtime,
+            "hash" => Completion::Hash(match options.and_then(|o| o.get("sample")) {
+                None => 0,
+                Some(v) => match v.as_u64() {
+                    Some(kib) => kib * 1024,
+                    None => bail!("\"sample\" must be a number of KiB"),
+                },
+            }),
+            "quiet" => Completion::Quiet,
+            "marker" => Completion::Marker(
+                options
+                    .and_then(|o| o.get("suffix"))
+                    .and_then(|v| v.as_str())
+                    .unwrap_or(".done")
+                    .to_string(),
+            ),
+            "wait_for_xattr" => {
+                let name = options.and_then(|o| o.get("name")).and_then(|v| v.as_str());
+                let expected = options
+                    .and_then(|o| o.get("value"))
+                    .and_then(|v| v.as_str());
+
+                match (name, expected) {
+                    (Some(n), Some(v)) => Completion::Xattr(n.to_string(), v.to_string()),
+                    _ => bail!("\"wait_for_xattr\" needs \"name\" and \"value\" strings"),
+                }
+            }
+            _ => bail!("Unknown completion strategy: {}", strategy),
+        };
+
+        // Every strategy but immediate needs to check files from time to time
+        if *check_interval <= 0 {
+            *check_interval = 1000;
+        }
+
+        Ok(completion)
+    }
+
+    /// The single event flags and their names, in config order
+    const MASK_NAMES: &'static [(WatchMask, &'static str)] = &[
+        (WatchMask::ACCESS, "ACCESS"),
+        (WatchMask::ATTRIB, "ATTRIB"),
+        (WatchMask::CLOSE_WRITE, "CLOSE_WRITE"),
+        (WatchMask::CLOSE_NOWRITE, "CLOSE_NOWRITE"),
+        (WatchMask::CREATE, "CREATE"),
+        (WatchMask::DELETE, "DELETE"),
+        (WatchMask::DELETE_SELF, "DELETE_SELF"),
+        (WatchMask::MODIFY, "MODIFY"),
+        (WatchMask::MOVE_SELF, "MOVE_SELF"),
+        (WatchMask::MOVED_FROM, "MOVED_FROM"),
+        (WatchMask::MOVED_TO, "MOVED_TO"),
+        (WatchMask::OPEN, "OPEN"),
+        (WatchMask::DONT_FOLLOW, "DONT_FOLLOW"),
+        (WatchMask::EXCL_UNLINK, "EXCL_UNLINK"),
+        (WatchMask::MASK_ADD, "MASK_ADD"),
+        (WatchMask::ONESHOT, "ONESHOT"),
+        (WatchMask::ONLYDIR, "ONLYDIR"),
+    ];
+
+    /// Returns the mask of the inotify watches of the element
+    ///
+    /// Recursive elements also need to know when subdirectories appear or leave
+    pub fn watch_mask(&self) -> WatchMask {
+        Self::watch_mask_of(self.mask, self.recursive)
+    }
+
+    /// Returns the mask of inotify watches for events
+    ///
+    /// # Parameters
+    ///
+    /// * `mask`: The events of the element
+    /// * `recursive`: Whether subdirectories are watched
+    fn watch_mask_of(mask: WatchMask, recursive: bool) -> WatchMask {
+        match recursive {
+            true => mask | WatchMask::CREATE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM,
+            false => mask,
+        }
+    }
+
+    /// Checks if an event is one of the events of the element, recursive elements receiving
+    /// more events than asked
+    ///
+    /// # Parameters
+    ///
+    /// * `mask`: The event mask
+    pub fn wants(&self, mask: EventMask) -> bool {
+        !self.recursive || mask.intersects(EventMask::from_bits_truncate(self.mask.bits()))
+    }
+
+    /// Returns the path of an event file relative to the watched path
+    ///
+    /// # Parameters
+    ///
+    /// * `directory`: The directory the event happened in
+    /// * `file`: The file name
+    pub fn relative_path(&self, directory: &Path, file: &OsStr) -> PathBuf {
+        directory
+            .strip_prefix(&self.path)
+            .unwrap_or_else(|_| Path::new(""))
+            .join(file)
+    }
+
+    /// Runs the registration command and waits for its result, `$@` being the watched path
+    pub fn run_on_register(&self) -> bool {
+        let cmd = self
+            .on_register
+            .replace("$@", &shell_escape::escape((&self.path).into()));
+        log!(
+            info,
+            path = &self.path,
+            "REGISTER({}) => {}",
+            &self.path,
+            &cmd
+        );
+
+        let mut command = Rincron::shell_command(&self.shell, &cmd);
+        command.stdin(std::process::Stdio::null());
+        Rincron::set_env(&mut command, self.clean_env, &self.env);
+
+        let status = Rincron::run_audited(&mut command, "register", &self.source, &self.path, &cmd);
+
+        match status {
+            Err(e) => {
+                log!(error, "Unable to launch registration command: {}", e);
+                false
+            }
+            Ok(v) => {
+                log!(
+                    info,
+                    path = &self.path,
+                    "Registration of {} exited with {}",
+                    &self.path,
+                    v
+                );
+                v.success()
+            }
+        }
+    }
+
+    /// Converts the element back to a config object with every option explicit
+    ///
+    /// Two elements behaving the same give the same object, and the object can be read again
+    pub fn to_json_value(&self) -> Value {
+        let events: Vec<&str> = Self::MASK_NAMES
+            .iter()
+            .filter(|(mask, _)| self.mask.contains(*mask))
+            .map(|(_, name)| *name)
+            .collect();
+
+        let command = if !self.command_by_event.is_empty() {
+            json!(&self.command_by_event)
+        } else if self.argv.is_empty() {
+            Value::from(self.command.as_str())
+        } else {
+            Value::from(self.argv.clone())
+        };
+
+        let completion = match &self.completion {
+            Completion::Immediate => json!({"strategy": "immediate"}),
+            Completion::Size => json!({"strategy": "size"}),
+            Completion::Mtime => json!({"strategy": "mtime"}),
+            Completion::SizeMtime => json!({"strategy": "size_mtime"}),
+            Completion::Hash(0) => json!({"strategy": "hash"}),
+            Completion::Hash(sample) => json!({"strategy": "hash", "sample": sample / 1024}),
+            Completion::Quiet => json!({"strategy": "quiet"}),
+            Completion::Marker(suffix) => json!({"strategy": "marker", "suffix": suffix}),
+            Completion::Xattr(name, value) => {
+                json!({"strategy": "wait_for_xattr", "name": name, "value": value})
+            }
+        };
+
+        let mut value = json!({
+            "path": &self.path,
+            "events": events,
+            "command": command,
+            "command_by_ext": &self.command_by_ext,
+            "env": &self.env,
+            "clean_env": self.clean_env,
+            "file_match": match self.file_match.len() {
+                0 => Value::from(""),
+                1 => Value::from(self.file_match[0].as_str()),
+                _ => Value::from(self.file_match.clone()),
+            },
+            "match_relative_path": self.match_relative_path,
+            "ignore_temp_files": self.exclude.len() > self.exclude_patterns.len(),
+            "dedup_inode_window": self.dedup_inode_window,
+            "once_per_file": self.once_per_file,
+            "initial_scan": self.initial_scan,
+            "scan_order": match self.scan_order {
+                ScanOrder::Filesystem => "filesystem",
+                ScanOrder::OldestFirst => "oldest_first",
+                ScanOrder::NewestFirst => "newest_first",
+            },
+            "dir_events_only": self.dir_events_only,
+            "symlinks": match self.symlinks {
+                SymlinkPolicy::Process => "process",
+                SymlinkPolicy::Ignore => "ignore",
+                SymlinkPolicy::Only => "only",
+            },
+            "rearm": self.rearm,
+            "max_rate": self.max_rate,
+            "max_burst": self.max_burst,
+            "check_interval": Self::seconds_value(self.check_interval as u64),
+            "completion": completion,
+            "hash_algo": self.hash_algo.name(),
+            "require_closed": self.require_closed,
+            "verify": &self.verify,
+            "verify_failure": match self.verify_failure {
+                VerifyFailure::Retry => "retry",
+                VerifyFailure::Drop => "drop",
+            },
+            "on_register": &self.on_register,
+            "on_register_failure": match self.on_register_failure {
+                RegisterFailure::Watch => "watch",
+                RegisterFailure::Skip => "skip",
+            },
+            "on_success": &self.on_success,
+            "on_failure": &self.on_failure,
+            "capture_output": self.capture_output,
+            "lifecycle_timeout": Self::seconds_value(self.lifecycle_timeout),
+            "chain_output": self.chain_output,
+            "max_chain_depth": self.max_chain_depth,
+            "on_shutdown": match self.on_shutdown {
+                ShutdownPolicy::Wait => "wait",
+                ShutdownPolicy::Terminate => "terminate",
+                ShutdownPolicy::Kill => "kill",
+            },
+            "on_cwd_removed": match self.on_cwd_removed {
+                ShutdownPolicy::Wait => "wait",
+                ShutdownPolicy::Terminate => "terminate",
+                ShutdownPolicy::Kill => "kill",
+            },
+            "date_timezone": match self.date_timezone {
+                DateTimezone::Local => "local",
+                DateTimezone::Utc => "utc",
+            },
+            "date_source": match self.date_source {
+                DateSource::Now => "now",
+                DateSource::Mtime => "mtime",
+            },
+            "min_free_space": self.min_free_space,
+            "free_space_path": &self.free_space_path,
+            "max_failures": self.max_failures,
+            "coalesce_create_close": self.coalesce_create_close,
+            "coalesce_window": self.coalesce_window,
+        });
 
-    /// The command string
-    pub command: String,
+        // The macro can't expand more keys at once
+        value["retries"] = Value::from(self.retries);
+        value["retry_delay"] = Self::seconds_value(self.retry_delay);
+        value["retry_on_codes"] = Value::from(self.retry_on_codes.clone());
+        value["track_attributes"] = Value::from(self.track_attributes);
+        value["route_command"] = Value::from(self.route_command.as_str());
+        value["filename_mode"] = Value::from(match self.filename_mode {
+            FilenameMode::Basename => "basename",
+            FilenameMode::Relative => "relative",
+        });
+        value["action_target"] = Value::from(match self.action_target {
+            ActionTarget::File => "file",
+            ActionTarget::Dir => "dir",
+        });
+        value["pending_event"] = Value::from(match self.pending_event {
+            PendingEventPolicy::Reset => "reset",
+            PendingEventPolicy::Keep => "keep",
+            PendingEventPolicy::Queue => "queue",
+        });
+        value["concurrency_group"] = Value::from(self.concurrency_group.as_str());
+        value["group_limit"] = Value::from(self.group_limit);
+        value["recursive"] = Value::from(self.recursive);
+        value["on_truncate"] = Value::from(self.on_truncate.as_str());
+        value["timeout"] = Self::seconds_value(self.timeout);
+        value["kill_grace"] = Self::seconds_value(self.kill_grace);
+        value["log_file"] = Value::from(self.log_file.as_str());
+        value["shell"] = Value::from(self.shell.as_str());
+        value["trigger_once_per_dir"] = Value::from(self.trigger_once_per_dir);
+        value["settle_window"] = Self::seconds_value(self.settle_window);
 
-    /// The masks
-    pub mask: WatchMask,
+        // Optional settings are left out rather than given an invalid empty value
+        if let Some(publish) = &self.publish {
+            value["publish"] = json!({"server": &publish.server, "subject": &publish.subject});
+        }
 
-    /// The file_match option
-    pub file_match: String,
+        if let Some(quarantine) = &self.quarantine {
+            value["quarantine"] = Value::from(quarantine.as_str());
+        }
 
-    /// The time interval in seconds betweek size checks
-    pub check_interval: i64,
-}
+        if !self.files.is_empty() {
+            value["files"] = Value::from(self.files.clone());
+        }
 
-impl WatchElement {
-    /// Converts an event string to a WatchMask
-    ///
-    /// Both `EVENT` and `IN_EVENT` can be used
-    fn event_name_to_value(name: &str) -> Option<WatchMask> {
-        match name {
-            "ATTRIB" | "IN_ATTRIB" => Some(WatchMask::ATTRIB),
-            "CLOSE_WRITE" | "IN_CLOSE_WRITE" => Some(WatchMask::CLOSE_WRITE),
-            "CLOSE_NOWRITE" | "IN_CLOSE_NOWRITE" => Some(WatchMask::CLOSE_NOWRITE),
-            "CREATE" | "IN_CREATE" => Some(WatchMask::CREATE),
-            "DELETE" | "IN_DELETE" => Some(WatchMask::DELETE),
-            "DELETE_SELF" | "IN_DELETE_SELF" => Some(WatchMask::DELETE_SELF),
-            "MODIFY" | "IN_MODIFY" => Some(WatchMask::MODIFY),
-            "MOVE_SELF" | "IN_MOVE_SELF" => Some(WatchMask::MOVE_SELF),
-            "MOVED_FROM" | "IN_MOVED_FROM" => Some(WatchMask::MOVED_FROM),
-            "MOVED_TO" | "IN_MOVED_TO" => Some(WatchMask::MOVED_TO),
-            "OPEN" | "IN_OPEN" => Some(WatchMask::OPEN),
-            "ALL_EVENTS" | "IN_ALL_EVENTS" => Some(WatchMask::ALL_EVENTS),
-            "MOVE" | "IN_MOVE" => Some(WatchMask::MOVE),
-            "CLOSE" | "IN_CLOSE" => Some(WatchMask::MOVE),
-            "DONT_FOLLOW" | "IN_DONT_FOLLOW" => Some(WatchMask::DONT_FOLLOW),
-            "EXCL_UNLINK" | "IN_EXCL_UNLINK" => Some(WatchMask::EXCL_UNLINK),
-            "MASK_ADD" | "IN_MASK_ADD" => Some(WatchMask::MASK_ADD),
-            "ONESHOT" | "IN_ONESHOT" => Some(WatchMask::ONESHOT),
-            "ONLYDIR" | "IN_ONLYDIR" => Some(WatchMask::ONLYDIR),
-            _ => None,
+        if !self.exclude_patterns.is_empty() {
+            value["exclude"] = Value::from(self.exclude_patterns.clone());
+        }
+
+        if let Some(route_default) = &self.route_default {
+            value["route_default"] = Value::from(route_default.as_str());
+        }
+
+        if let Some(self_command) = &self.self_command {
+            value["self_command"] = Value::from(self_command.as_str());
+        }
+
+        if !self.file_match_regex.is_empty() {
+            value["file_match_regex"] = Value::from(self.file_match_regex.as_str());
         }
+
+        value["pair_moves"] = Value::from(self.pair_moves);
+        value["pair_window"] = Value::from(self.pair_window);
+
+        value
     }
 
     /// Creates an new element from json value and adds it to inotify
@@ -76,15 +1323,19 @@ impl WatchElement {
     ///
     /// * `value`: The json value
     /// * `inotify`: The inotify object
+    /// * `context`: Where the element is read
     pub fn from_json_value(
         value: &Value,
         inotify: &mut Inotify,
+        context: &ParseContext,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         // We need an object
         if !value.is_object() {
             bail!("One item is not an object: {}", value);
         }
 
+        Self::check_keys(value, Self::KNOWN_KEYS, context.strict)?;
+
         // Data extraction
         let mut path = value.get("path");
 
@@ -93,7 +1344,7 @@ impl WatchElement {
             path = value.get("dir");
 
             if path.is_some() {
-                println!("Warning: 'dir' key used instead of 'path', this is deprecated and will be removed in a future version");
+                log!(warning, "Warning: 'dir' key used instead of 'path', this is deprecated and will be removed in a future version");
             }
         }
 
@@ -101,19 +1352,586 @@ impl WatchElement {
         let command = value.get("command");
 
         // Extact parameters with default values
-        let file_match = value
-            .get("file_match")
+        // A single pattern or a list of them, empty ones filter nothing
+        let file_match: Vec<String> = match value.get("file_match") {
+            None => Vec::new(),
+            Some(Value::String(v)) => vec![v.clone()],
+            Some(Value::Array(v)) if v.iter().all(|p| p.is_string()) => {
+                v.iter().map(|p| p.as_str().unwrap().to_string()).collect()
+            }
+            Some(_) => bail!("\"file_match\" must be a pattern or an array of patterns"),
+        }
+        .into_iter()
+        .filter(|p| !p.is_empty())
+        .collect();
+
+        let file_match_regex = value
+            .get("file_match_regex")
             .unwrap_or(&Value::String(String::new()))
             .as_str()
             .unwrap_or_default()
             .to_string();
 
-        let check_interval = value
-            .get("check_interval")
+        // Invalid expressions are rejected now rather than on each event
+        let file_matcher = if !file_match_regex.is_empty() {
+            if !file_match.is_empty() {
+                log!(warning, "Warning: both \"file_match\" and \"file_match_regex\" are set, \"file_match\" ignored");
+            }
+
+            match Regex::new(&file_match_regex) {
+                Ok(v) => Some(FileMatcher::Regex(v)),
+                Err(e) => bail!("Invalid \"file_match_regex\": {}", e),
+            }
+        } else if !file_match.is_empty() {
+            Some(FileMatcher::Wildcard(
+                file_match.iter().map(|p| WildMatch::new(p)).collect(),
+            ))
+        } else {
+            None
+        };
+
+        let match_relative_path = value
+            .get("match_relative_path")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let filename_mode = match value.get("filename_mode").and_then(|v| v.as_str()) {
+            None | Some("basename") => FilenameMode::Basename,
+            Some("relative") => FilenameMode::Relative,
+            Some(v) => bail!("Unknown filename mode: {}", v),
+        };
+
+        let mut files = Vec::new();
+
+        if let Some(v) = value.get("files") {
+            match v.as_array() {
+                Some(names) if names.iter().all(|n| n.is_string()) => {
+                    files = names
+                        .iter()
+                        .map(|n| n.as_str().unwrap().to_string())
+                        .collect()
+                }
+                _ => bail!("\"files\" must be an array of file names"),
+            }
+        }
+
+        let mut exclude_patterns = Vec::new();
+
+        if let Some(v) = value.get("exclude") {
+            match v.as_array() {
+                Some(patterns) if patterns.iter().all(|p| p.is_string()) => {
+                    exclude_patterns = patterns
+                        .iter()
+                        .map(|p| p.as_str().unwrap().to_string())
+                        .collect()
+                }
+                _ => bail!("\"exclude\" must be an array of patterns"),
+            }
+        }
+
+        let mut exclude: Vec<WildMatch> =
+            exclude_patterns.iter().map(|p| WildMatch::new(p)).collect();
+
+        // Temporary files conventions are expanded into exclude patterns
+        let ignore_temp_files = value
+            .get("ignore_temp_files")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        if ignore_temp_files {
+            for pattern in Self::TEMP_FILE_PATTERNS {
+                exclude.push(WildMatch::new(pattern));
+            }
+        }
+
+        let dedup_inode_window = value
+            .get("dedup_inode_window")
             .unwrap_or(&Value::Number(Number::from(0)))
+            .as_u64()
+            .unwrap_or_default();
+
+        let rearm = value
+            .get("rearm")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let max_rate = match value.get("max_rate") {
+            None => 0.0,
+            Some(v) => match v.as_f64() {
+                Some(r) if r >= 0.0 => r,
+                _ => bail!("Invalid max rate: {}", v),
+            },
+        };
+
+        let max_burst = match value.get("max_burst") {
+            None => max_rate.max(1.0),
+            Some(v) => match v.as_f64() {
+                Some(b) if b >= 1.0 => b,
+                _ => bail!("Invalid max burst: {}", v),
+            },
+        };
+
+        let track_attributes = value
+            .get("track_attributes")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let once_per_file = value
+            .get("once_per_file")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let initial_scan = value
+            .get("initial_scan")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let scan_order = match value.get("scan_order").and_then(|v| v.as_str()) {
+            None | Some("filesystem") => ScanOrder::Filesystem,
+            Some("oldest_first") => ScanOrder::OldestFirst,
+            Some("newest_first") => ScanOrder::NewestFirst,
+            Some(v) => bail!("Unknown scan order: {}", v),
+        };
+
+        let hash_algo = match value.get("hash_algo") {
+            None => HashAlgo::fastest(),
+            Some(v) => match v.as_str() {
+                Some(name) => HashAlgo::from_name(name)?,
+                None => bail!("\"hash_algo\" must be a string"),
+            },
+        };
+
+        let require_closed = value
+            .get("require_closed")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let lifecycle_timeout = match value.get("lifecycle_timeout") {
+            None => 0,
+            Some(v) => Self::parse_seconds(v, "lifecycle_timeout")?,
+        };
+
+        let timeout = match value.get("timeout") {
+            None => 0,
+            Some(v) => Self::parse_seconds(v, "timeout")?,
+        };
+
+        let kill_grace = match value.get("kill_grace") {
+            None => 5000,
+            Some(v) => Self::parse_seconds(v, "kill_grace")?,
+        };
+
+        // inotify events don't tell which process caused them, only fanotify does
+        if let Some(pids) = value.get("ignore_pids") {
+            if !pids
+                .as_array()
+                .is_some_and(|a| a.iter().all(|p| p.is_u64()))
+            {
+                bail!(
+                    "Invalid ignore_pids, expected an array of process ids: {}",
+                    pids
+                );
+            }
+
+            log!(
+                warning,
+                "Warning: ignore_pids needs the fanotify backend, events are not filtered"
+            );
+        }
+
+        let chain_output = value
+            .get("chain_output")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let max_chain_depth = match value.get("max_chain_depth") {
+            None => 3,
+            Some(v) => match v.as_u64() {
+                Some(d) => d as u32,
+                None => bail!("Invalid max chain depth: {}", v),
+            },
+        };
+
+        let on_shutdown = match value.get("on_shutdown").and_then(|v| v.as_str()) {
+            None | Some("terminate") => ShutdownPolicy::Terminate,
+            Some("wait") => ShutdownPolicy::Wait,
+            Some("kill") => ShutdownPolicy::Kill,
+            Some(v) => bail!("Unknown shutdown policy: {}", v),
+        };
+
+        let on_cwd_removed = match value.get("on_cwd_removed").and_then(|v| v.as_str()) {
+            None | Some("wait") => ShutdownPolicy::Wait,
+            Some("terminate") => ShutdownPolicy::Terminate,
+            Some("kill") => ShutdownPolicy::Kill,
+            Some(v) => bail!("Unknown removed working directory policy: {}", v),
+        };
+
+        let date_timezone = match value.get("date_timezone").and_then(|v| v.as_str()) {
+            None | Some("local") => DateTimezone::Local,
+            Some("utc") => DateTimezone::Utc,
+            Some(v) => bail!("Unknown date timezone: {}", v),
+        };
+
+        let date_source = match value.get("date_source").and_then(|v| v.as_str()) {
+            None | Some("now") => DateSource::Now,
+            Some("mtime") => DateSource::Mtime,
+            Some(v) => bail!("Unknown date source: {}", v),
+        };
+
+        let dir_events_only = value
+            .get("dir_events_only")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let recursive = value
+            .get("recursive")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let trigger_once_per_dir = value
+            .get("trigger_once_per_dir")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        // Files of a subdirectory created after the watch are only seen recursively
+        if trigger_once_per_dir && !recursive {
+            bail!("\"trigger_once_per_dir\" needs \"recursive\"");
+        }
+
+        let settle_window = match value.get("settle_window") {
+            None => 2000,
+            Some(v) => Self::parse_seconds(v, "settle_window")?,
+        };
+
+        let symlinks = match value.get("symlinks").and_then(|v| v.as_str()) {
+            None | Some("process") => SymlinkPolicy::Process,
+            Some("ignore") => SymlinkPolicy::Ignore,
+            Some("only") => SymlinkPolicy::Only,
+            Some(v) => bail!("Unknown symlinks policy: {}", v),
+        };
+
+        let mut check_interval = match value.get("check_interval") {
+            None => 0,
+            Some(v) => Self::parse_seconds(v, "check_interval")? as i64,
+        };
+
+        let verify = value
+            .get("verify")
+            .unwrap_or(&Value::String(String::new()))
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let route_command = value
+            .get("route_command")
+            .unwrap_or(&Value::String(String::new()))
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let route_default = match value.get("route_default") {
+            None => None,
+            Some(v) => match v.as_str() {
+                Some(route) => Some(route.to_string()),
+                None => bail!("\"route_default\" must be a string"),
+            },
+        };
+
+        let verify_failure = match value.get("verify_failure").and_then(|v| v.as_str()) {
+            None | Some("retry") => VerifyFailure::Retry,
+            Some("drop") => VerifyFailure::Drop,
+            Some(v) => bail!(
+                "\"verify_failure\" must be \"retry\" or \"drop\", got \"{}\"",
+                v
+            ),
+        };
+
+        let on_register = value
+            .get("on_register")
+            .unwrap_or(&Value::String(String::new()))
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let on_register_failure = match value.get("on_register_failure").and_then(|v| v.as_str()) {
+            None | Some("watch") => RegisterFailure::Watch,
+            Some("skip") => RegisterFailure::Skip,
+            Some(v) => bail!(
+                "\"on_register_failure\" must be \"watch\" or \"skip\", got \"{}\"",
+                v
+            ),
+        };
+
+        let mut command_by_ext = HashMap::new();
+
+        if let Some(v) = value.get("command_by_ext") {
+            if !v.is_object() {
+                bail!("\"command_by_ext\" must be an object");
+            }
+
+            for (ext, cmd) in v.as_object().unwrap() {
+                if !cmd.is_string() {
+                    bail!("\"command_by_ext\" values must be strings");
+                }
+
+                // Both "py" and ".py" are accepted
+                command_by_ext.insert(
+                    ext.trim_start_matches('.').to_string(),
+                    cmd.as_str().unwrap().to_string(),
+                );
+            }
+        }
+
+        let mut env = HashMap::new();
+
+        if let Some(v) = value.get("env") {
+            if !v.is_object() {
+                bail!("\"env\" must be an object");
+            }
+
+            for (name, var) in v.as_object().unwrap() {
+                if name.is_empty() || name.contains('=') || name.contains('\0') {
+                    bail!("Invalid environment variable name: {}", name);
+                }
+
+                match var.as_str() {
+                    Some(var) => env.insert(name.to_string(), var.to_string()),
+                    None => bail!("\"env\" values must be strings"),
+                };
+            }
+        }
+
+        let clean_env = value
+            .get("clean_env")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let shell = match value.get("shell") {
+            None => String::new(),
+            Some(v) => match v.as_str() {
+                Some(s) if !s.is_empty() => s.to_string(),
+                _ => bail!("\"shell\" must be a non empty string"),
+            },
+        };
+
+        let on_success = value
+            .get("on_success")
+            .unwrap_or(&Value::String(String::new()))
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let on_failure = value
+            .get("on_failure")
+            .unwrap_or(&Value::String(String::new()))
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let on_truncate = value
+            .get("on_truncate")
+            .unwrap_or(&Value::String(String::new()))
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let self_command = match value.get("self_command") {
+            None => None,
+            Some(v) => match v.as_str() {
+                Some(command) => Some(command.to_string()),
+                None => bail!("\"self_command\" must be a string"),
+            },
+        };
+
+        let capture_output = value
+            .get("capture_output")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let log_file = match value.get("log_file") {
+            None => String::new(),
+            Some(v) => match v.as_str() {
+                Some(f) => f.to_string(),
+                None => bail!("\"log_file\" must be a string"),
+            },
+        };
+
+        let min_free_space = match value.get("min_free_space") {
+            None => 0,
+            Some(v) => Self::parse_size(v)?,
+        };
+
+        let free_space_path = value
+            .get("free_space_path")
+            .and_then(|v| v.as_str())
+            .map(|v| context.resolve(v));
+
+        let quarantine = value
+            .get("quarantine")
+            .and_then(|v| v.as_str())
+            .map(|v| context.resolve(v));
+
+        let max_failures = match value.get("max_failures") {
+            None => 3,
+            Some(v) => match v.as_u64() {
+                Some(m) if m > 0 => m as u32,
+                _ => bail!("Invalid max failures: {}", v),
+            },
+        };
+
+        let retries = match value.get("retries") {
+            None => 0,
+            Some(v) => match v.as_u64().map(u32::try_from) {
+                Some(Ok(r)) => r,
+                _ => bail!("Invalid retries: {}", v),
+            },
+        };
+
+        let retry_delay = match value.get("retry_delay") {
+            None => 5000,
+            Some(v) => Self::parse_seconds(v, "retry_delay")?,
+        };
+
+        if retry_delay > Self::MAX_RETRY_DELAY {
+            bail!(
+                "\"retry_delay\" can't exceed {} seconds",
+                Self::MAX_RETRY_DELAY / 1000
+            );
+        }
+
+        let mut retry_on_codes = Vec::new();
+
+        if let Some(v) = value.get("retry_on_codes") {
+            let codes = match v.as_array() {
+                Some(c) => c,
+                None => bail!("\"retry_on_codes\" must be an array of exit codes"),
+            };
+
+            for code in codes {
+                match code.as_i64() {
+                    Some(c) if (1..=255).contains(&c) => retry_on_codes.push(c as i32),
+                    _ => bail!("Invalid exit code in \"retry_on_codes\": {}", code),
+                }
+            }
+        }
+
+        let coalesce_create_close = value
+            .get("coalesce_create_close")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let coalesce_window = value
+            .get("coalesce_window")
+            .unwrap_or(&Value::Number(Number::from(1000)))
             .as_i64()
             .unwrap_or_default();
 
+        let pair_moves = value
+            .get("pair_moves")
+            .unwrap_or(&Value::Bool(false))
+            .as_bool()
+            .unwrap_or_default();
+
+        let pair_window = match value.get("pair_window") {
+            None => 1000,
+            Some(v) => match v.as_i64() {
+                Some(ms) if ms > 0 => ms,
+                _ => bail!("\"pair_window\" must be a positive number of milliseconds"),
+            },
+        };
+
+        let concurrency_group = match value.get("concurrency_group") {
+            None => String::new(),
+            Some(v) => match v.as_str() {
+                Some(g) => g.to_string(),
+                None => bail!("\"concurrency_group\" must be a string"),
+            },
+        };
+
+        let group_limit = match value.get("group_limit") {
+            None => 1,
+            Some(v) => match v.as_u64() {
+                Some(l) if l > 0 => l as usize,
+                _ => bail!("Invalid group limit: {}", v),
+            },
+        };
+
+        let debounce = match value.get("debounce") {
+            None => 0,
+            Some(v) => Self::parse_seconds(v, "debounce")? as i64,
+        };
+
+        // Debouncing is a quiet completion restarted by each event
+        let completion = if debounce == 0 {
+            Self::parse_completion(value, &mut check_interval)?
+        } else if value.get("completion").is_some() || value.get("check_interval").is_some() {
+            bail!("\"debounce\" can't be used with \"completion\" nor \"check_interval\"");
+        } else {
+            check_interval = debounce;
+            Completion::Quiet
+        };
+
+        // Files executed right away are never checked, so never verified
+        if !verify.is_empty() && completion == Completion::Immediate {
+            bail!("\"verify\" needs a \"check_interval\" or a \"completion\" strategy");
+        }
+
+        let pending_event = match value.get("pending_event").and_then(|v| v.as_str()) {
+            None | Some("reset") => PendingEventPolicy::Reset,
+            Some("keep") => PendingEventPolicy::Keep,
+            Some("queue") => PendingEventPolicy::Queue,
+            Some(v) => bail!("Unknown pending event policy: {}", v),
+        };
+
+        if debounce > 0 && pending_event != PendingEventPolicy::Reset {
+            bail!("\"debounce\" needs the reset pending event policy");
+        }
+
+        let action_target = match value.get("action_target").and_then(|v| v.as_str()) {
+            None | Some("file") => ActionTarget::File,
+            Some("dir") => ActionTarget::Dir,
+            Some(v) => bail!("Unknown action target: {}", v),
+        };
+
+        // Contents, markers and writers only make sense for a file
+        if action_target == ActionTarget::Dir {
+            match completion {
+                Completion::Immediate
+                | Completion::Size
+                | Completion::Mtime
+                | Completion::SizeMtime
+                | Completion::Quiet => {}
+                _ => bail!("A directory target only supports the immediate, size, mtime, size_mtime and quiet completions"),
+            }
+
+            if require_closed {
+                bail!("\"require_closed\" can't be used with a directory target");
+            }
+        }
+
+        let publish = Self::parse_publish(value)?;
+
+        // Without command, the element can still publish messages
+        let empty_command = Value::String(String::new());
+        let command = match command {
+            None if publish.is_some() => Some(&empty_command),
+            v => v,
+        };
+
         // Integrity checks
         if path.is_none() || events.is_none() || command.is_none() {
             bail!("One parameter is missing between \"dir\", \"events\" and \"command\"");
@@ -131,13 +1949,51 @@ impl WatchElement {
             bail!("\"events\" must be an array");
         }
 
-        if !command.is_string() {
-            bail!("\"command\" must be a string");
+        let mut command_by_event = BTreeMap::new();
+
+        // An argument list is executed without shell
+        let (command, argv) = match command {
+            Value::String(v) => (Self::expand_env(v, &env)?, Vec::new()),
+            // Each event can have its own command
+            Value::Object(v) if !v.is_empty() => {
+                for (event, cmd) in v {
+                    if Self::event_name_to_value(event).is_none() {
+                        bail!("Unknown event {} in \"command\"", event);
+                    }
+
+                    let cmd = match cmd.as_str() {
+                        Some(v) => Self::expand_env(v, &env)?,
+                        None => bail!("\"command\" values must be strings"),
+                    };
+
+                    if shell == "none" && shell_words::split(&cmd).is_err() {
+                        bail!("\"command\" of {} can't be split into arguments, quotes are unbalanced", event);
+                    }
+
+                    command_by_event.insert(event.trim_start_matches("IN_").to_string(), cmd);
+                }
+
+                (String::new(), Vec::new())
+            }
+            Value::Array(v) if !v.is_empty() && v.iter().all(|t| t.is_string()) => (
+                String::new(),
+                v.iter()
+                    .map(|t| Self::expand_env(t.as_str().unwrap(), &env))
+                    .collect::<Result<Vec<String>, _>>()?,
+            ),
+            _ => bail!("\"command\" must be a string, a non empty array of strings or an object of commands by event"),
+        };
+
+        // Without shell, quotes must still give arguments once wildcards are replaced
+        if shell == "none" && shell_words::split(&command).is_err() {
+            bail!("\"command\" can't be split into arguments, quotes are unbalanced");
         }
 
-        let path = path.as_str().unwrap();
+        // The same config can watch different mount points on each machine
+        let path = Self::expand_env(path.as_str().unwrap(), &HashMap::new())?;
+        let path = context.resolve(&path);
+        let path = path.as_str();
         let events = events.as_array().unwrap();
-        let command = command.as_str().unwrap();
 
         // Path check
         let dir_path = Path::new(path);
@@ -152,7 +2008,7 @@ impl WatchElement {
         // Events extraction
         for event in events {
             if !event.is_string() {
-                println!("One event is not a string: {}", event);
+                log!(error, "One event is not a string: {}", event);
                 continue;
             }
 
@@ -173,8 +2029,20 @@ impl WatchElement {
             bail!("No events found for {}", path);
         }
 
+        // A fired oneshot watch would have to be rearmed in every subdirectory
+        if recursive && in_events.unwrap().contains(WatchMask::ONESHOT) {
+            bail!("\"recursive\" can't be used with ONESHOT");
+        }
+
+        // Both halves of a rename are needed to pair them
+        if pair_moves && !in_events.unwrap().contains(WatchMask::MOVE) {
+            bail!("\"pair_moves\" needs both MOVED_FROM and MOVED_TO in \"events\"");
+        }
+
         // Try to add watch
-        let add = inotify.watches().add(in_dir, in_events.unwrap());
+        let add = inotify
+            .watches()
+            .add(in_dir, Self::watch_mask_of(in_events.unwrap(), recursive));
 
         if let Err(e) = add {
             bail!("Unable to add watch: {}", e);
@@ -185,11 +2053,252 @@ impl WatchElement {
 
         Ok(Self {
             watch_descriptor,
+            profile: context.profile.clone(),
+            source: context.source.clone(),
             path: path.to_string(),
             command: command.to_string(),
+            argv,
+            publish,
+            command_by_ext,
+            command_by_event,
+            env,
+            clean_env,
+            shell,
+            file_matcher,
             file_match,
+            file_match_regex,
+            match_relative_path,
+            filename_mode,
+            files,
+            exclude_patterns,
+            exclude,
+            dedup_inode_window,
+            once_per_file,
+            initial_scan,
+            scan_order,
+            dir_events_only,
+            recursive,
+            trigger_once_per_dir,
+            settle_window,
+            symlinks,
+            track_attributes,
+            rearm,
+            max_rate,
+            max_burst,
             check_interval,
+            completion,
+            pending_event,
+            action_target,
+            hash_algo,
+            require_closed,
+            verify,
+            verify_failure,
+            route_command,
+            route_default,
+            on_register,
+            on_register_failure,
+            on_success,
+            on_failure,
+            on_truncate,
+            self_command,
+            capture_output,
+            log_file,
+            lifecycle_timeout,
+            timeout,
+            kill_grace,
+            chain_output,
+            max_chain_depth,
+            on_shutdown,
+            on_cwd_removed,
+            date_timezone,
+            date_source,
+            min_free_space,
+            free_space_path: free_space_path.unwrap_or_else(|| path.to_string()),
+            quarantine,
+            max_failures,
+            retries,
+            retry_delay,
+            retry_on_codes,
+            coalesce_create_close,
+            coalesce_window,
+            pair_moves,
+            pair_window,
+            concurrency_group,
+            group_limit,
             mask: in_events.unwrap(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses an element watching the temporary directory
+    ///
+    /// # Parameters
+    ///
+    /// * `value`: The element config, without its path, events and command default to
+    ///   `CLOSE_WRITE` and `true`
+    fn parse(mut value: Value) -> Result<WatchElement, Box<dyn std::error::Error>> {
+        let context = ParseContext {
+            source: "test".to_string(),
+            base_dir: PathBuf::from("/"),
+            strict: true,
+            profile: String::new(),
+        };
+        value["path"] = json!(std::env::temp_dir().to_string_lossy());
+
+        if value.get("events").is_none() {
+            value["events"] = json!(["CLOSE_WRITE"]);
+        }
+
+        if value.get("command").is_none() {
+            value["command"] = json!("true");
+        }
+
+        WatchElement::from_json_value(&value, &mut Inotify::init()?, &context)
+    }
+
+    #[test]
+    fn retries_out_of_range_are_rejected() {
+        assert_eq!(parse(json!({"retries": 3})).unwrap().retries, 3);
+        assert!(parse(json!({"retries": u64::from(u32::MAX) + 1})).is_err());
+        assert!(parse(json!({"retries": -1})).is_err());
+    }
+
+    #[test]
+    fn retry_delay_is_bounded() {
+        let element = parse(json!({"retries": 1, "retry_delay": 86400})).unwrap();
+        assert_eq!(element.retry_delay, 86_400_000);
+        assert!(parse(json!({"retries": 1, "retry_delay": 86401})).is_err());
+        assert!(parse(json!({"retries": 1, "retry_delay": 1e300})).is_err());
+    }
+
+    #[test]
+    fn specific_event_commands_win() {
+        let element = parse(json!({
+            "events": ["CLOSE_WRITE", "CLOSE_NOWRITE", "MOVED_TO"],
+            "command": {
+                "ALL_EVENTS": "all",
+                "CLOSE": "close",
+                "CLOSE_WRITE": "write"
+            }
+        }))
+        .unwrap();
+        let command = |mask| element.event_command(mask).map(|c| c.as_str());
+
+        assert_eq!(command(EventMask::CLOSE_WRITE), Some("write"));
+        assert_eq!(command(EventMask::CLOSE_NOWRITE), Some("close"));
+        assert_eq!(command(EventMask::MOVED_TO), Some("all"));
+        assert_eq!(command(EventMask::empty()), None);
+    }
+
+    #[test]
+    fn pair_moves_is_validated() {
+        let element = parse(json!({"events": ["MOVE"], "pair_moves": true})).unwrap();
+        assert_eq!(element.pair_window, 1000);
+
+        let element = parse(json!({"events": ["MOVE"], "pair_moves": true, "pair_window": 250}));
+        assert_eq!(element.unwrap().pair_window, 250);
+
+        for window in [json!(0), json!(-5), json!("1s"), json!(1.5)] {
+            let value = json!({"events": ["MOVE"], "pair_moves": true, "pair_window": window});
+            assert!(parse(value).is_err(), "{} accepted", window);
+        }
+
+        assert!(parse(json!({"events": ["MOVED_TO"], "pair_moves": true})).is_err());
+        assert!(parse(json!({"events": ["MOVED_FROM"], "pair_moves": true})).is_err());
+    }
+
+    #[test]
+    fn event_names_map_to_their_mask() {
+        let table = [
+            ("ATTRIB", WatchMask::ATTRIB),
+            ("CLOSE_WRITE", WatchMask::CLOSE_WRITE),
+            ("CLOSE_NOWRITE", WatchMask::CLOSE_NOWRITE),
+            ("CREATE", WatchMask::CREATE),
+            ("DELETE", WatchMask::DELETE),
+            ("DELETE_SELF", WatchMask::DELETE_SELF),
+            ("MODIFY", WatchMask::MODIFY),
+            ("MOVE_SELF", WatchMask::MOVE_SELF),
+            ("MOVED_FROM", WatchMask::MOVED_FROM),
+            ("MOVED_TO", WatchMask::MOVED_TO),
+            ("OPEN", WatchMask::OPEN),
+            ("ALL_EVENTS", WatchMask::ALL_EVENTS),
+            ("MOVE", WatchMask::MOVED_FROM | WatchMask::MOVED_TO),
+            ("CLOSE", WatchMask::CLOSE_WRITE | WatchMask::CLOSE_NOWRITE),
+            ("DONT_FOLLOW", WatchMask::DONT_FOLLOW),
+            ("EXCL_UNLINK", WatchMask::EXCL_UNLINK),
+            ("MASK_ADD", WatchMask::MASK_ADD),
+            ("ONESHOT", WatchMask::ONESHOT),
+            ("ONLYDIR", WatchMask::ONLYDIR),
+        ];
+
+        for (name, mask) in table {
+            assert_eq!(
+                WatchElement::event_name_to_value(name),
+                Some(mask),
+                "{}",
+                name
+            );
+            let prefixed = format!("IN_{}", name);
+            assert_eq!(
+                WatchElement::event_name_to_value(&prefixed),
+                Some(mask),
+                "{}",
+                prefixed
+            );
+        }
+
+        assert_eq!(WatchElement::event_name_to_value("close_write"), None);
+    }
+
+    #[test]
+    fn sizes_are_parsed_without_overflow() {
+        assert_eq!(WatchElement::parse_size(&json!(512)).unwrap(), 512);
+        assert_eq!(WatchElement::parse_size(&json!("2K")).unwrap(), 2048);
+        assert_eq!(WatchElement::parse_size(&json!(" 3 g ")).unwrap(), 3 << 30);
+        assert_eq!(
+            WatchElement::parse_size(&json!("16777215T")).unwrap(),
+            16_777_215 << 40
+        );
+        assert!(WatchElement::parse_size(&json!("16777216T")).is_err());
+        assert!(WatchElement::parse_size(&json!("99999999999G")).is_err());
+        assert!(WatchElement::parse_size(&json!("-1K")).is_err());
+        assert!(parse(json!({"min_free_space": "18446744073709551615K"})).is_err());
+    }
+
+    #[cfg(feature = "nats")]
+    #[test]
+    fn publish_subjects_are_validated() {
+        let publish = |subject: &str| {
+            parse(json!({"publish": {"server": "127.0.0.1:4222", "subject": subject}}))
+        };
+
+        assert!(publish("rincron.uploads").is_ok());
+        assert!(publish("").is_err());
+        assert!(publish("rincron uploads").is_err());
+        assert!(publish("rincron\r\nPUB other 0").is_err());
+    }
+
+    #[test]
+    fn debounce_is_in_seconds() {
+        let element = parse(json!({"debounce": 0.5})).unwrap();
+        assert_eq!(element.check_interval, 500);
+        assert!(element.completion == Completion::Quiet);
+
+        assert_eq!(parse(json!({"debounce": 2})).unwrap().check_interval, 2000);
+        assert!(parse(json!({"debounce": -1})).is_err());
+        assert!(parse(json!({"debounce": "1s"})).is_err());
+        assert!(parse(json!({"debounce": 1, "check_interval": 1})).is_err());
+    }
+
+    #[test]
+    fn verify_needs_a_completion_check() {
+        assert!(parse(json!({"verify": "true"})).is_err());
+        assert!(parse(json!({"verify": "true", "completion": "immediate"})).is_err());
+        assert!(parse(json!({"verify": "true", "check_interval": 1})).is_ok());
+        assert!(parse(json!({"verify": "true", "completion": "mtime"})).is_ok());
+    }
+}
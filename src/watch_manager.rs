@@ -14,9 +14,10 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::watch_element::WatchElement;
+use crate::watch_element::{RegisterFailure, WatchElement};
 use inotify::{Inotify, WatchDescriptor};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Default)]
 /// Manager of events
@@ -29,6 +30,10 @@ pub struct WatchManager {
 
     /// New elements to add after transaction end
     new_elements: Vec<WatchElement>,
+
+    /// Subdirectories watched for recursive elements, with the watch descriptor of their
+    /// element and their path
+    subdirectories: HashMap<WatchDescriptor, (WatchDescriptor, PathBuf)>,
 }
 
 impl WatchManager {
@@ -39,74 +44,400 @@ impl WatchManager {
         self.new_elements = Vec::new();
     }
 
-    /// Adds a new elements, if a similar element exists in the backup, it will be moved to avoid
-    /// losses. If not, it will be added to inotify at transaction end
+    /// Starts a new transaction on the elements of a single config file, elements of other files
+    /// stay watched as they are
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: The config file, like elements know it
+    pub fn begin_source_transaction(&mut self, source: &str) {
+        (self.previous_elements, self.current_elements) =
+            std::mem::take(&mut self.current_elements)
+                .into_iter()
+                .partition(|(_, e)| e.source == source);
+        self.new_elements = Vec::new();
+    }
+
+    /// Adds a new elements, if an element of the same path and events exists in the backup, its
+    /// watch is kept to avoid losses and its options are updated. If not, it will be added to
+    /// inotify at transaction end
     ///
     /// # Parameters
     ///
     /// * `new_element`: The new element to add
-    pub fn add_element(&mut self, new_element: WatchElement) {
-        let mut exists = false;
-        let mut previous_descriptor = None;
-        let mut previous_element = None;
-
-        // We check previous elements if it already exists
-        for (descriptor, element) in &self.previous_elements {
-            if new_element == *element {
-                println!("Already existing element: {}", &element.path);
-                exists = true;
-                previous_descriptor = Some(descriptor.clone());
-                previous_element = Some(element.clone());
+    pub fn add_element(&mut self, mut new_element: WatchElement) {
+        // We check previous elements if the watch already exists
+        let previous_descriptor = self
+            .previous_elements
+            .iter()
+            .find(|(_, e)| {
+                e.path == new_element.path
+                    && e.mask == new_element.mask
+                    && e.recursive == new_element.recursive
+            })
+            .map(|(d, _)| d.clone());
+
+        // If it already exists, the new options take its place in current elements
+        if let Some(descriptor) = previous_descriptor {
+            let previous_element = self.previous_elements.remove(&descriptor).unwrap();
+            new_element.watch_descriptor = descriptor.clone();
+
+            if new_element == previous_element {
+                log!(
+                    info,
+                    path = &new_element.path,
+                    "Already existing element: {}",
+                    &new_element.path
+                );
+            } else {
+                log!(
+                    info,
+                    path = &new_element.path,
+                    "Already existing element updated: {}",
+                    &new_element.path
+                );
             }
-        }
 
-        // If it already exists, we just move it to current elements
-        if exists {
-            self.previous_elements
-                .remove(previous_descriptor.as_ref().unwrap());
-            self.current_elements
-                .insert(previous_descriptor.unwrap(), previous_element.unwrap());
+            self.current_elements.insert(descriptor, new_element);
             return;
         }
 
         // If it does not exist, we put it in new elements
-        println!("Event added for {}", &new_element.path);
+        log!(
+            info,
+            path = &new_element.path,
+            "Event added for {}",
+            &new_element.path
+        );
         self.new_elements.push(new_element);
     }
 
     /// Ends the transaction, all non-moved elements will be removed from inotify and new ones
     /// will be added
     ///
+    /// Returns the newly added elements
+    ///
     /// # Parameters
     ///
     /// * `inotify`: The inotify object where to add events
-    pub fn end_transaction(&mut self, inotify: &mut Inotify) {
+    /// * `register`: Whether registration commands of new elements are executed
+    pub fn end_transaction(&mut self, inotify: &mut Inotify, register: bool) -> Vec<WatchElement> {
+        let mut added_elements = Vec::new();
+
+        // Changed elements were already registered, only new paths run their command
+        for element in std::mem::take(&mut self.new_elements) {
+            let new_path = !self
+                .previous_elements
+                .values()
+                .any(|e| e.path == element.path);
+
+            if register && new_path && !element.on_register.is_empty() && !element.run_on_register()
+            {
+                if element.on_register_failure == RegisterFailure::Skip {
+                    log!(
+                        warning,
+                        path = &element.path,
+                        "Registration of {} failed, not watched",
+                        &element.path
+                    );
+
+                    // Parsing already added the watch
+                    if !self
+                        .current_elements
+                        .contains_key(&element.watch_descriptor)
+                    {
+                        let _ = inotify.watches().remove(element.watch_descriptor.clone());
+                    }
+
+                    continue;
+                }
+
+                log!(
+                    warning,
+                    path = &element.path,
+                    "Registration of {} failed, watched anyway",
+                    &element.path
+                );
+            }
+
+            self.new_elements.push(element);
+        }
+
         // We remove unecessary elements
         // This needs to be done before adding new element to avoid conflicts
-        for (descriptor, element) in &self.previous_elements {
-            match inotify.watches().remove(descriptor.clone()) {
+        for (descriptor, element) in std::mem::take(&mut self.previous_elements) {
+            self.unwatch_subdirectories(inotify, |root, _| *root == descriptor);
+
+            match inotify.watches().remove(descriptor) {
                 Err(e) => {
-                    println!("Warning: error while removing inotify watch: {}", e);
+                    log!(
+                        warning,
+                        "Warning: error while removing inotify watch: {}",
+                        e
+                    );
                 }
                 Ok(_) => {
-                    println!("Event removed for {}", &element.path);
+                    log!(
+                        info,
+                        path = &element.path,
+                        "Event removed for {}",
+                        &element.path
+                    );
                 }
             };
         }
 
         // We add newly added elements
-        for element in &self.new_elements {
-            let wd = inotify.watches().add(element.path.clone(), element.mask);
+        for element in std::mem::take(&mut self.new_elements) {
+            let wd = inotify
+                .watches()
+                .add(element.path.clone(), element.watch_mask());
 
             match wd {
                 Err(e) => {
-                    println!("Warning: error while adding inotify watch: {}", e);
+                    log!(warning, "Warning: error while adding inotify watch: {}", e);
                 }
                 Ok(v) => {
+                    if element.recursive {
+                        self.watch_tree(inotify, &v, Path::new(&element.path), &element);
+                    }
+
                     self.current_elements.insert(v, element.clone());
+                    added_elements.push(element);
                 }
             };
         }
+
+        added_elements
+    }
+
+    /// Watches the subdirectories of a directory of a recursive element, at any depth
+    ///
+    /// Symlinks to directories are not followed, and directories watched by another element
+    /// are left to it
+    ///
+    /// # Parameters
+    ///
+    /// * `inotify`: The inotify object where to add watches
+    /// * `root`: The watch descriptor of the element
+    /// * `directory`: The directory whose subdirectories are watched
+    /// * `element`: The recursive element
+    pub fn watch_tree(
+        &mut self,
+        inotify: &mut Inotify,
+        root: &WatchDescriptor,
+        directory: &Path,
+        element: &WatchElement,
+    ) {
+        let entries = match std::fs::read_dir(directory) {
+            Ok(v) => v,
+            Err(e) => {
+                log!(
+                    error,
+                    "Unable to list subdirectories of {}: {}",
+                    directory.display(),
+                    e
+                );
+                return;
+            }
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                self.watch_subdirectory(inotify, root, &entry.path(), element);
+            }
+        }
+    }
+
+    /// Watches a new subdirectory of a recursive element and its own subdirectories
+    ///
+    /// # Parameters
+    ///
+    /// * `inotify`: The inotify object where to add watches
+    /// * `root`: The watch descriptor of the element
+    /// * `directory`: The subdirectory
+    /// * `element`: The recursive element
+    pub fn watch_subdirectory(
+        &mut self,
+        inotify: &mut Inotify,
+        root: &WatchDescriptor,
+        directory: &Path,
+        element: &WatchElement,
+    ) {
+        // The same directory gives the same watch, which can't serve two elements
+        if self.elements().any(|e| Path::new(&e.path) == directory) {
+            log!(
+                info,
+                path = &element.path,
+                "Subdirectory {} of {} is watched by another element, skipped",
+                directory.display(),
+                &element.path
+            );
+            return;
+        }
+
+        match inotify.watches().add(directory, element.watch_mask()) {
+            Err(e) => log!(
+                warning,
+                "Warning: unable to watch {}: {}",
+                directory.display(),
+                e
+            ),
+            Ok(v) => {
+                log!("Subdirectory {} watched", directory.display());
+                self.subdirectories
+                    .insert(v, (root.clone(), directory.to_path_buf()));
+                self.watch_tree(inotify, root, directory, element);
+            }
+        }
+    }
+
+    /// Removes the watches of subdirectories selected by a filter
+    ///
+    /// # Parameters
+    ///
+    /// * `inotify`: The inotify object where to remove watches
+    /// * `filter`: Selects subdirectories by their element watch descriptor and their path
+    pub fn unwatch_subdirectories<F: Fn(&WatchDescriptor, &Path) -> bool>(
+        &mut self,
+        inotify: &mut Inotify,
+        filter: F,
+    ) {
+        let removed: Vec<WatchDescriptor> = self
+            .subdirectories
+            .iter()
+            .filter(|(_, (root, path))| filter(root, path))
+            .map(|(wd, _)| wd.clone())
+            .collect();
+
+        for wd in removed {
+            let (_, path) = self.subdirectories.remove(&wd).unwrap();
+
+            // The kernel already removed the watch of a deleted directory
+            if inotify.watches().remove(wd).is_ok() {
+                log!(
+                    info,
+                    path = path.display(),
+                    "Subdirectory {} not watched anymore",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    /// Forgets a subdirectory whose watch was removed by the kernel, returns its path if the
+    /// watch descriptor was one of a subdirectory
+    ///
+    /// # Parameters
+    ///
+    /// * `watch_descriptor`: The watch descriptor of the subdirectory
+    pub fn forget_subdirectory(&mut self, watch_descriptor: &WatchDescriptor) -> Option<PathBuf> {
+        self.subdirectories
+            .remove(watch_descriptor)
+            .map(|(_, path)| path)
+    }
+
+    /// Checks if a watch descriptor is the one of a subdirectory of a recursive element
+    ///
+    /// # Parameters
+    ///
+    /// * `watch_descriptor`: The watch descriptor
+    pub fn is_subdirectory(&self, watch_descriptor: &WatchDescriptor) -> bool {
+        self.subdirectories.contains_key(watch_descriptor)
+    }
+
+    /// Returns the directory and the element watch descriptor of a watch, the element path for
+    /// the watch of an element
+    ///
+    /// # Parameters
+    ///
+    /// * `watch_descriptor`: The watch descriptor of an event
+    pub fn locate(&self, watch_descriptor: &WatchDescriptor) -> Option<(WatchDescriptor, PathBuf)> {
+        if let Some(v) = self.subdirectories.get(watch_descriptor) {
+            return Some(v.clone());
+        }
+
+        self.current_elements
+            .get(watch_descriptor)
+            .map(|e| (watch_descriptor.clone(), PathBuf::from(&e.path)))
+    }
+
+    /// Returns the watch descriptor of a watched directory, element path or subdirectory
+    ///
+    /// # Parameters
+    ///
+    /// * `directory`: The directory
+    pub fn descriptor_of(&self, directory: &Path) -> Option<WatchDescriptor> {
+        self.current_elements
+            .iter()
+            .find(|(_, e)| Path::new(&e.path) == directory)
+            .map(|(wd, _)| wd.clone())
+            .or_else(|| {
+                self.subdirectories
+                    .iter()
+                    .find(|(_, (_, path))| path == directory)
+                    .map(|(wd, _)| wd.clone())
+            })
+    }
+
+    /// Returns the elements of the transaction whose watch overlaps the one of an element:
+    /// same directory, one directory directly containing the other, or any directory below a
+    /// recursive element
+    ///
+    /// # Parameters
+    ///
+    /// * `element`: The element to check
+    pub fn overlapping_elements(&self, element: &WatchElement) -> Vec<&WatchElement> {
+        let canonical = |p: &str| {
+            Path::new(p)
+                .canonicalize()
+                .unwrap_or_else(|_| PathBuf::from(p))
+        };
+        let path = canonical(&element.path);
+
+        self.current_elements
+            .values()
+            .chain(self.new_elements.iter())
+            .filter(|e| {
+                let other = canonical(&e.path);
+                other == path
+                    || other.parent() == Some(&path)
+                    || path.parent() == Some(&other)
+                    || (element.recursive && other.starts_with(&path))
+                    || (e.recursive && path.starts_with(&other))
+            })
+            .collect()
+    }
+
+    /// Returns the number of elements currently watched
+    pub fn watch_count(&self) -> usize {
+        self.current_elements.len()
+    }
+
+    /// Returns all elements currently watched
+    pub fn elements(&self) -> impl Iterator<Item = &WatchElement> {
+        self.current_elements.values()
+    }
+
+    /// Removes an element whose watch no longer exists, like a fired oneshot watch
+    ///
+    /// # Parameters
+    ///
+    /// * `watch_descriptor`: The associated watch descriptor
+    pub fn remove_element(&mut self, watch_descriptor: &WatchDescriptor) -> Option<WatchElement> {
+        self.subdirectories
+            .retain(|_, (root, _)| root != watch_descriptor);
+        self.current_elements.remove(watch_descriptor)
+    }
+
+    /// Inserts an element whose watch was just added, outside of any transaction
+    ///
+    /// # Parameters
+    ///
+    /// * `element`: The element, with its new watch descriptor
+    pub fn insert_element(&mut self, element: WatchElement) {
+        self.current_elements
+            .insert(element.watch_descriptor.clone(), element);
     }
 
     /// Searches an element in the database
@@ -115,6 +446,9 @@ impl WatchManager {
     ///
     /// * `watch_descriptor`: The associated watch descriptor
     pub fn search_element(&mut self, watch_descriptor: &WatchDescriptor) -> Option<&WatchElement> {
-        self.current_elements.get(watch_descriptor)
+        match self.subdirectories.get(watch_descriptor) {
+            Some((root, _)) => self.current_elements.get(root),
+            None => self.current_elements.get(watch_descriptor),
+        }
     }
 }
@@ -0,0 +1,135 @@
+// This file is part of rincron-mini <https://github.com/nevermille/rincron-mini>
+// Copyright (C) 2022-2023 Camille Nevermind
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::file_check::FileCheck;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// A function processing a file in place of a command, returns `true` on success
+pub type Callback = Arc<dyn Fn(&FileCheck) -> bool + Send + Sync>;
+
+/// A bounded pool of threads executing callbacks
+pub struct WorkerPool {
+    /// The queue of callbacks to execute, as large as the pool
+    jobs: Option<SyncSender<(Callback, FileCheck)>>,
+
+    /// The results of executed callbacks
+    results: Receiver<(FileCheck, bool)>,
+
+    /// The worker threads
+    workers: Vec<JoinHandle<()>>,
+
+    /// The number of callbacks queued or running
+    in_flight: usize,
+}
+
+impl WorkerPool {
+    /// Starts the worker threads
+    ///
+    /// # Parameters
+    ///
+    /// * `size`: The number of threads
+    pub fn new(size: usize) -> Self {
+        let (jobs, queue) = mpsc::sync_channel::<(Callback, FileCheck)>(size);
+        let (done, results) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+
+        let workers = (0..size)
+            .map(|_| {
+                let queue = queue.clone();
+                let done = done.clone();
+
+                std::thread::spawn(move || loop {
+                    // The lock is released before the callback runs
+                    let job = queue.lock().map(|q| q.recv());
+
+                    match job {
+                        Ok(Ok((callback, file))) => {
+                            // A panicking callback is a failed one, the worker keeps going
+                            let success = panic::catch_unwind(AssertUnwindSafe(|| callback(&file)))
+                                .unwrap_or_else(|_| {
+                                    log!(
+                                        error,
+                                        path = &file.path,
+                                        "Callback of {} panicked",
+                                        &file.path
+                                    );
+                                    false
+                                });
+                            let _ = done.send((file, success));
+                        }
+                        _ => return,
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            jobs: Some(jobs),
+            results,
+            workers,
+            in_flight: 0,
+        }
+    }
+
+    /// Queues a callback, gives the file back if the queue is full, `None` if queued
+    ///
+    /// # Parameters
+    ///
+    /// * `callback`: The callback to execute
+    /// * `file`: The file given to the callback
+    pub fn submit(&mut self, callback: Callback, file: FileCheck) -> Option<FileCheck> {
+        let jobs = match &self.jobs {
+            Some(v) => v,
+            None => return Some(file),
+        };
+
+        match jobs.try_send((callback, file)) {
+            Ok(()) => {
+                self.in_flight += 1;
+                None
+            }
+            Err(TrySendError::Full((_, file))) | Err(TrySendError::Disconnected((_, file))) => {
+                Some(file)
+            }
+        }
+    }
+
+    /// Returns the files whose callback finished and whether it succeeded
+    pub fn finished(&mut self) -> Vec<(FileCheck, bool)> {
+        let results: Vec<(FileCheck, bool)> = self.results.try_iter().collect();
+        self.in_flight -= results.len();
+        results
+    }
+
+    /// Returns the number of callbacks queued or running
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the queue stops workers once their callback returns
+        self.jobs = None;
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}